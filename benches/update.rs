@@ -0,0 +1,78 @@
+//! Throughput of `ShellyMetrics::update_from_message` for a stream from 50 chatty
+//! devices; run with `cargo bench --bench update`
+
+// The exporter is a binary crate, so the modules under test are compiled in here;
+// bench builds strip their `#[test]` functions, leaving the test imports unused
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/metrics.rs"]
+mod metrics;
+#[path = "../src/parser.rs"]
+mod parser;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use prometheus_client::registry::Registry;
+
+use metrics::{MetricsOptions, ShellyMetrics};
+use parser::{parse_message, ShellyMessage};
+
+const DEVICES: usize = 50;
+
+/// A status update with power readings and a full status, per device
+fn message_stream() -> Vec<(String, ShellyMessage)> {
+    let mut stream = Vec::new();
+    for device in 0..DEVICES {
+        let src = format!("shellyplugus-d48afc78{:04x}", device);
+        let topic = format!("site-a/shelly/plug{}/events/rpc", device);
+        let status = format!(
+            r#"{{"src": "{src}", "method": "NotifyStatus", "params": {{
+                "switch:0": {{"id": 0, "apower": 125.5, "voltage": 122.3, "current": 1.025,
+                    "aenergy": {{"total": 3949.949, "minute_ts": 1763918640}},
+                    "temperature": {{"tC": 37.9, "tF": 100.1}}}},
+                "sys": {{"uptime": 3600, "ram_free": 120000}},
+                "wifi": {{"rssi": -60}}}}}}"#
+        );
+        let full_status = format!(
+            r#"{{"src": "{src}", "method": "NotifyFullStatus", "params": {{
+                "switch:0": {{"id": 0, "output": true, "apower": 130.0, "voltage": 121.9}},
+                "sys": {{"uptime": 3660, "device": {{"model": "SNPL-00116US", "gen": 2}}}},
+                "wifi": {{"rssi": -61, "ssid": "home"}}}}}}"#
+        );
+        for json in [status, full_status] {
+            stream.push((topic.clone(), parse_message(&json).unwrap()));
+        }
+    }
+    stream
+}
+
+fn bench_update(c: &mut Criterion) {
+    let stream = message_stream();
+    let mut group = c.benchmark_group("update_from_message");
+    group.throughput(Throughput::Elements(stream.len() as u64));
+
+    let cases = [
+        ("default", MetricsOptions::default()),
+        (
+            "site_labels",
+            MetricsOptions {
+                site_from_topic_segment: true,
+                ..Default::default()
+            },
+        ),
+    ];
+    for (name, options) in cases {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for (topic, msg) in &stream {
+                    metrics.update_from_message(msg, Some(topic));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_update);
+criterion_main!(benches);
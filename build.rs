@@ -0,0 +1,24 @@
+//! Captures the git commit for `mqtt2prom_build_info`
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=VERGEN_GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    // An explicit VERGEN_GIT_SHA wins, e.g. for Docker builds without .git
+    let sha = std::env::var("VERGEN_GIT_SHA").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+    });
+    if let Some(sha) = sha
+        .map(|sha| sha.trim().to_string())
+        .filter(|sha| !sha.is_empty())
+    {
+        println!("cargo:rustc-env=MQTT2PROM_GIT_SHA={}", sha);
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::time::SystemTime;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::warn;
+
+use crate::parser::ShellyMessage;
+use crate::state::StoredMessage;
+
+/// Lines waiting for the writer before new ones are dropped
+const ARCHIVE_CHANNEL_CAPACITY: usize = 1024;
+
+/// Appends every parsed message with its topic and receive time as a JSON line,
+/// in the record format of the state file, which `--replay` also reads
+///
+/// Writes happen on a separate task so a slow disk never stalls the MQTT loop;
+/// when the writer falls behind, messages are dropped with a warning.
+#[derive(Clone)]
+pub struct Archive {
+    tx: mpsc::Sender<String>,
+}
+
+impl Archive {
+    /// Open `path` for appending and spawn its writer task
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open archive file {}", path.display()))?;
+        let (tx, rx) = mpsc::channel(ARCHIVE_CHANNEL_CAPACITY);
+        tokio::spawn(run_writer(
+            BufWriter::new(tokio::fs::File::from_std(file)),
+            rx,
+        ));
+        Ok(Self { tx })
+    }
+
+    /// Queue a message for the archive without waiting
+    pub fn record(&self, message: &ShellyMessage, topic: Option<&str>) {
+        let record = StoredMessage {
+            topic: topic.map(str::to_string),
+            received_at: Some(SystemTime::now()),
+            message: message.clone(),
+        };
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to serialize message for the archive: {}", e);
+                return;
+            }
+        };
+        match self.tx.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    "Archive writer is behind, dropping a message from {}",
+                    message.src
+                )
+            }
+            Err(TrySendError::Closed(_)) => warn!("Archive writer stopped, dropping a message"),
+        }
+    }
+}
+
+/// Write queued lines, flushing whenever the queue runs empty
+async fn run_writer(mut writer: impl AsyncWrite + Unpin, mut rx: mpsc::Receiver<String>) {
+    while let Some(line) = rx.recv().await {
+        let mut batch = vec![line];
+        while let Ok(line) = rx.try_recv() {
+            batch.push(line);
+        }
+        for line in batch {
+            if let Err(e) = write_line(&mut writer, &line).await {
+                warn!("Failed to write to the archive file: {}", e);
+            }
+        }
+        if let Err(e) = writer.flush().await {
+            warn!("Failed to flush the archive file: {}", e);
+        }
+    }
+}
+
+async fn write_line(writer: &mut (impl AsyncWrite + Unpin), line: &str) -> std::io::Result<()> {
+    writer.write_all(line.as_bytes()).await?;
+    writer.write_all(b"\n").await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_message;
+
+    #[test]
+    fn test_record_drops_when_full() {
+        // Nothing drains the channel, so only the first message fits
+        let (tx, mut rx) = mpsc::channel(1);
+        let archive = Archive { tx };
+        let msg = parse_message(
+            r#"{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {}}"#,
+        )
+        .unwrap();
+
+        archive.record(&msg, None);
+        archive.record(&msg, None);
+
+        assert!(rx.try_recv().unwrap().contains("shellyplugus-d48afc781ad8"));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_record_keeps_topic_and_unknown_components() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let archive = Archive { tx };
+        let msg = parse_message(
+            r#"{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {"thermostat:0": {"id": 0}}}"#,
+        )
+        .unwrap();
+
+        archive.record(&msg, Some("mostert/shelly/plugcoffee/events/rpc"));
+
+        let record: StoredMessage = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+        assert_eq!(
+            record.topic.as_deref(),
+            Some("mostert/shelly/plugcoffee/events/rpc")
+        );
+        assert!(record.received_at.is_some());
+        assert!(record.message.params.extra.contains_key("thermostat:0"));
+    }
+}
@@ -1,59 +1,1026 @@
-use clap::Parser;
+use anyhow::{anyhow, Context, Result};
+use clap::builder::ArgPredicate;
+use clap::{ArgAction, CommandFactory, Parser};
+use serde::{Serialize, Serializer};
+use std::ffi::OsString;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 
-#[derive(Parser, Debug, Clone)]
+use crate::metrics::{
+    validate_metric_prefix, DeviceAlias, InputCalibration, MetricsOptions, NameSource,
+    ScalingRound, TemperatureUnit, DEFAULT_METRIC_PREFIX,
+};
+use crate::mqtt::qos_from_level;
+
+#[derive(Parser, Debug, Clone, Serialize)]
 #[command(author, version, about, long_about = None)]
 pub struct Config {
-    /// MQTT broker hostname
-    #[arg(env = "MQTT_HOST")]
+    /// MQTT broker hostname, or a comma-separated `host:port` list to fail over across
+    #[arg(
+        long,
+        env = "MQTT_HOST",
+        value_parser = parse_mqtt_host,
+        required = false,
+        required_unless_present_any = ["print_metrics_list", "replay", "discovery_url"],
+        default_value_if("print_metrics_list", ArgPredicate::IsPresent, ""),
+        default_value_if("replay", ArgPredicate::IsPresent, ""),
+        default_value_if("discovery_url", ArgPredicate::IsPresent, "")
+    )]
     pub mqtt_host: String,
 
     /// MQTT broker port
-    #[arg(env = "MQTT_PORT", default_value = "1883")]
+    #[arg(long, env = "MQTT_PORT", default_value = "1883")]
     pub mqtt_port: u16,
 
-    /// MQTT username
-    #[arg(env = "MQTT_USERNAME")]
-    pub mqtt_username: String,
+    /// MQTT username; set together with the password, or neither to connect anonymously
+    #[arg(long, env = "MQTT_USERNAME")]
+    pub mqtt_username: Option<String>,
 
     /// MQTT password
-    #[arg(env = "MQTT_PASSWORD")]
-    pub mqtt_password: String,
+    #[arg(long, env = "MQTT_PASSWORD")]
+    #[serde(serialize_with = "redact")]
+    pub mqtt_password: Option<String>,
+
+    /// File holding the MQTT password, read at startup; takes precedence over `MQTT_PASSWORD`
+    #[arg(long, env = "MQTT_PASSWORD_FILE")]
+    pub mqtt_password_file: Option<PathBuf>,
+
+    /// MQTT topics to subscribe to, comma-separated
+    #[arg(
+        long,
+        env = "MQTT_TOPIC",
+        value_delimiter = ',',
+        default_value = "mostert/shelly/#"
+    )]
+    pub mqtt_topic: Vec<String>,
 
-    /// MQTT topic to subscribe to
-    #[arg(env = "MQTT_TOPIC", default_value = "mostert/shelly/#")]
-    pub mqtt_topic: String,
+    /// Fetch broker and topic settings (JSON) from this URL at startup, overriding the local values
+    #[arg(long, env = "DISCOVERY_URL")]
+    #[serde(serialize_with = "redact_userinfo")]
+    pub discovery_url: Option<String>,
 
     /// MQTT client ID
-    #[arg(env = "MQTT_CLIENT_ID", default_value = "mqtt2prom")]
+    #[arg(long, env = "MQTT_CLIENT_ID", default_value = "mqtt2prom")]
     pub mqtt_client_id: String,
 
+    /// QoS level of the subscriptions: 0 (at most once), 1 (at least once) or 2 (exactly once)
+    #[arg(
+        long,
+        env = "MQTT_QOS",
+        default_value = "0",
+        value_parser = clap::value_parser!(u8).range(0..=2)
+    )]
+    pub mqtt_qos: u8,
+
+    /// Start each connection with a clean session; with `false` the broker keeps the
+    /// subscriptions and queues QoS 1/2 messages for this client ID while it is away
+    #[arg(
+        long,
+        env = "MQTT_CLEAN_SESSION",
+        default_value_t = true,
+        action = ArgAction::Set
+    )]
+    pub mqtt_clean_session: bool,
+
+    /// Keep-alive interval for the broker connection (0 disables)
+    #[arg(long, env = "MQTT_KEEP_ALIVE_SECONDS", default_value = "30")]
+    pub mqtt_keep_alive_seconds: u64,
+
+    /// How to reach the broker: plain TCP, or MQTT over WebSocket (`ws`) or TLS WebSocket (`wss`)
+    #[arg(long, env = "MQTT_TRANSPORT", value_enum, default_value = "tcp")]
+    pub mqtt_transport: MqttTransport,
+
+    /// Path of the WebSocket endpoint with `ws` or `wss`
+    #[arg(long, env = "MQTT_WS_PATH", default_value = "/mqtt")]
+    pub mqtt_ws_path: String,
+
     /// Prometheus metrics HTTP port
-    #[arg(env = "METRICS_PORT", default_value = "8080")]
+    #[arg(long, env = "METRICS_PORT", default_value = "8080")]
     pub metrics_port: u16,
+
+    /// Address the metrics HTTP server binds to
+    #[arg(long, env = "METRICS_BIND_ADDR", default_value = "0.0.0.0")]
+    pub metrics_bind_addr: IpAddr,
+
+    /// Keep collecting MQTT data when the metrics server fails instead of exiting
+    #[arg(long, env = "TOLERATE_SERVER_FAILURE")]
+    pub tolerate_server_failure: bool,
+
+    /// Require `Authorization: Bearer <token>` on `/metrics` (`/health` stays open)
+    #[arg(long, env = "METRICS_AUTH_TOKEN", hide_env_values = true)]
+    #[serde(serialize_with = "redact")]
+    pub metrics_auth_token: Option<String>,
+
+    /// Serve scrapes from a snapshot of the registry refreshed every N seconds
+    #[arg(long, env = "METRICS_SNAPSHOT_INTERVAL_SECONDS")]
+    pub metrics_snapshot_interval_seconds: Option<u64>,
+
+    /// Process at most this many `<prefix>:<N>` components per message (0 disables the cap)
+    #[arg(long, env = "MAX_COMPONENTS_PER_MESSAGE", default_value = "64")]
+    pub max_components_per_message: usize,
+
+    /// Process at most this many `switch:N` channels per device message (0 disables the cap)
+    #[arg(long, env = "MAX_CHANNELS_PER_DEVICE", default_value = "16")]
+    pub max_channels_per_device: usize,
+
+    /// Drop messages from new devices once this many devices have series (0 disables the cap)
+    #[arg(long, env = "MAX_DEVICES", default_value = "1000")]
+    pub max_devices: usize,
+
+    /// Updates under load without energy progress before a switch is flagged as stalled (0 disables)
+    #[arg(long, env = "ENERGY_STALL_UPDATES", default_value = "10")]
+    pub energy_stall_updates: u32,
+
+    /// Initial delay before reconnecting to the broker, doubled after each failed attempt
+    #[arg(long, env = "MQTT_RECONNECT_BASE_SECONDS", default_value = "1")]
+    pub mqtt_reconnect_base_seconds: u64,
+
+    /// Upper bound for the reconnect delay
+    #[arg(long, env = "MQTT_RECONNECT_MAX_SECONDS", default_value = "60")]
+    pub mqtt_reconnect_max_seconds: u64,
+
+    /// Exit after this many consecutive authentication refusals (0 keeps retrying)
+    #[arg(long, env = "MQTT_MAX_AUTH_FAILURES", default_value = "0")]
+    pub mqtt_max_auth_failures: u32,
+
+    /// Force a reconnect when no message arrives for this many seconds (0 disables)
+    #[arg(long, env = "MESSAGE_WATCHDOG_SECS", default_value = "0")]
+    pub message_watchdog_secs: u64,
+
+    /// Export `shelly_switch_computed_power_watts` derived from consecutive energy totals
+    #[arg(long, env = "COMPUTED_POWER")]
+    pub computed_power: bool,
+
+    /// Export `shelly_component_last_seen_timestamp_seconds` per device and component
+    #[arg(long, env = "PER_COMPONENT_FRESHNESS")]
+    pub per_component_freshness: bool,
+
+    /// Export `mqtt2prom_last_payload_info` with a hash of each device's last raw payload
+    #[arg(long, env = "DEBUG_PAYLOAD_LABEL")]
+    pub debug_payload_label: bool,
+
+    /// Set `shelly_battery_low` for batteries below this charge percentage (0 disables)
+    #[arg(long, env = "BATTERY_LOW_PERCENT", default_value = "20")]
+    pub battery_low_percent: f64,
+
+    /// Set `shelly_battery_voltage_percent_mismatch` when the reported battery percent is
+    /// off the voltage discharge curve by more than this many points (0 disables)
+    #[arg(long, env = "BATTERY_MISMATCH_PERCENT", default_value = "25")]
+    pub battery_mismatch_percent: f64,
+
+    /// Remove the series of devices not heard from for this many seconds (0 keeps them
+    /// forever)
+    #[arg(long, env = "METRIC_TTL_SECONDS", default_value = "3600")]
+    pub metric_ttl_seconds: u64,
+
+    /// Number of registries devices are sharded across, to reduce lock contention
+    #[arg(long, env = "REGISTRY_SHARDS", default_value = "1", value_parser = clap::value_parser!(u16).range(1..))]
+    pub registry_shards: u16,
+
+    /// Map analog input percent to engineering units, as `id=scale,offset` (repeatable)
+    #[arg(long, env = "INPUT_CALIBRATION", value_delimiter = ';')]
+    pub input_calibration: Vec<InputCalibration>,
+
+    /// Label every device series with the broker it came from (`broker="host:port"`)
+    #[arg(long, env = "BROKER_LABEL")]
+    pub broker_label: bool,
+
+    /// Prefix of the device metric names, in place of `shelly`
+    #[arg(
+        long,
+        env = "METRIC_PREFIX",
+        default_value = DEFAULT_METRIC_PREFIX,
+        value_parser = parse_metric_prefix
+    )]
+    pub metric_prefix: String,
+
+    /// Label every device series with the first topic segment as `site`, for brokers
+    /// shared by several sites
+    #[arg(long, env = "SITE_FROM_TOPIC_SEGMENT")]
+    pub site_from_topic_segment: bool,
+
+    /// Count NotifyEvent events (button pushes, over-temperature, ...) instead of ignoring them
+    #[arg(long, env = "PROCESS_EVENTS")]
+    pub process_events: bool,
+
+    /// Omit gauge samples whose value did not change since the same scraper's previous
+    /// scrape; Prometheus treats omitted series as stale, so unchanged gauges read as missing
+    #[arg(long, env = "EMIT_ONLY_CHANGED")]
+    pub emit_only_changed: bool,
+
+    /// Persist the latest device values to this file and reload them on startup
+    #[arg(long, env = "STATE_FILE")]
+    pub state_file: Option<PathBuf>,
+
+    /// How often to write the state file, in seconds
+    #[arg(long, env = "STATE_SAVE_INTERVAL_SECONDS", default_value = "60")]
+    pub state_save_interval_seconds: u64,
+
+    /// Append every parsed message with its topic to this file as a JSON line, which
+    /// `--replay` reads
+    #[arg(long, env = "ARCHIVE_PATH")]
+    pub archive_path: Option<PathBuf>,
+
+    /// Also write the metrics to `<dir>/mqtt2prom.prom` for the node_exporter textfile collector
+    #[arg(long, env = "TEXTFILE_DIR")]
+    pub textfile_dir: Option<PathBuf>,
+
+    /// How often to write the textfile, in seconds
+    #[arg(long, env = "TEXTFILE_INTERVAL_SECONDS", default_value = "15")]
+    pub textfile_interval_seconds: u64,
+
+    /// Also push the metrics to this Pushgateway, under `/metrics/job/mqtt2prom`
+    #[arg(long, env = "PUSHGATEWAY_URL")]
+    #[serde(serialize_with = "redact_userinfo")]
+    pub pushgateway_url: Option<String>,
+
+    /// How often to push to the Pushgateway, in seconds
+    #[arg(long, env = "PUSH_INTERVAL_SECONDS", default_value = "15")]
+    pub push_interval_seconds: u64,
+
+    /// Topic segment holding the device name, counted from 0 (default 2, as in
+    /// `<prefix>/shelly/<device-name>/events/rpc`)
+    #[arg(long, env = "DEVICE_NAME_TOPIC_INDEX")]
+    pub device_name_topic_index: Option<usize>,
+
+    /// Friendly device names as `mac=name`, where `mac` is the `src` or its MAC suffix (repeatable)
+    #[arg(long, env = "DEVICE_ALIASES", value_delimiter = ';')]
+    pub device_alias: Vec<DeviceAlias>,
+
+    /// File of `mac=name` lines, read at startup; `--device-alias` entries win on conflicts
+    #[arg(long, env = "DEVICE_ALIAS_FILE")]
+    pub device_alias_file: Option<PathBuf>,
+
+    /// Order of the sources tried for the device label; the MAC is the last resort
+    #[arg(
+        long,
+        env = "NAME_PRECEDENCE",
+        value_enum,
+        value_delimiter = ',',
+        default_value = "alias,topic,mac"
+    )]
+    pub name_precedence: Vec<NameSource>,
+
+    /// Decompress gzip payloads before parsing, for bridges that compress JSON
+    #[arg(long, env = "ACCEPT_COMPRESSED_PAYLOADS")]
+    pub accept_compressed_payloads: bool,
+
+    /// Lowercase topics before the `/events/rpc` check and device name extraction
+    #[arg(long, env = "CASE_INSENSITIVE_TOPICS")]
+    pub case_insensitive_topics: bool,
+
+    /// Also export the deprecated pre-scaled integer metrics as `<name>_scaled`
+    #[arg(long, env = "EMIT_LEGACY_SCALED")]
+    pub emit_legacy_scaled: bool,
+
+    /// How the legacy `_scaled` metrics convert to integers
+    #[arg(long, env = "SCALING_ROUND", value_enum, default_value_t = ScalingRound::Truncate)]
+    pub scaling_round: ScalingRound,
+
+    /// Device metric families to leave out, comma-separated, by full name or short name
+    /// such as `voltage` or `wifi_rssi`
+    #[arg(long, env = "DISABLED_METRICS", value_delimiter = ',')]
+    pub disabled_metrics: Vec<String>,
+
+    /// Temperature families to export: `celsius`, `fahrenheit` (from the reported `tF`) or `both`
+    #[arg(long, env = "TEMPERATURE_UNIT", value_enum, default_value_t = TemperatureUnit::Celsius)]
+    pub temperature_unit: TemperatureUnit,
+
+    /// Skip components the device type inferred from `src` cannot report
+    #[arg(long, env = "INFER_DEVICE_TYPE")]
+    pub infer_device_type: bool,
+
+    /// Log every request to the metrics HTTP server
+    #[arg(long, env = "HTTP_ACCESS_LOG")]
+    pub http_access_log: bool,
+
+    /// Log level for HTTP access records
+    #[arg(long, env = "HTTP_ACCESS_LOG_LEVEL", default_value = "info")]
+    #[serde(serialize_with = "serialize_level")]
+    pub http_access_log_level: tracing::Level,
+
+    /// Print every metric the exporter can emit and exit
+    #[arg(long)]
+    pub print_metrics_list: bool,
+
+    /// TOML file of settings keyed by option name (`mqtt_host = "..."`); they override
+    /// environment variables and are overridden by command-line arguments
+    #[arg(long, env = "CONFIG_FILE", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Apply newline-delimited JSON messages from this file, print the metrics and exit
+    #[arg(long, value_name = "FILE")]
+    pub replay: Option<PathBuf>,
+}
+
+/// Serialize a secret as `"<redacted>"`, keeping `null` so an unset one stays visible
+fn redact<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => serializer.serialize_str("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Serialize a URL with any `user:password@` in its authority replaced by `<redacted>@`
+fn redact_userinfo<S: Serializer>(url: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    let Some(url) = url else {
+        return serializer.serialize_none();
+    };
+    let (scheme, rest) = url.split_once("://").unwrap_or(("", url));
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    match rest[..authority_end].rfind('@') {
+        Some(at) => {
+            let separator = if scheme.is_empty() { "" } else { "://" };
+            serializer.serialize_str(&format!("{}{}<redacted>{}", scheme, separator, &rest[at..]))
+        }
+        None => serializer.serialize_str(url),
+    }
+}
+
+fn serialize_level<S: Serializer>(
+    level: &tracing::Level,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(level.as_str())
+}
+
+/// Transport to the MQTT broker
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttTransport {
+    /// Plain TCP
+    Tcp,
+    /// MQTT over WebSocket
+    Ws,
+    /// MQTT over WebSocket with TLS, verified against the system roots
+    Wss,
+}
+
+/// One MQTT broker to connect to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokerEndpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+impl fmt::Display for BrokerEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Split a `host`, `host:port` or `[ipv6]:port` entry, leaving the port out when absent
+fn split_host_port(entry: &str) -> Result<(&str, Option<u16>), String> {
+    let invalid_port = |port: &str| format!("invalid port {:?} in {:?}", port, entry);
+    if let Some(rest) = entry.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or_else(|| format!("unclosed '[' in {:?}", entry))?;
+        return match rest.strip_prefix(':') {
+            Some(port) => Ok((host, Some(port.parse().map_err(|_| invalid_port(port))?))),
+            None if rest.is_empty() => Ok((host, None)),
+            None => Err(format!("unexpected {:?} after ']' in {:?}", rest, entry)),
+        };
+    }
+    match entry.rsplit_once(':') {
+        // A bare IPv6 address has no port
+        Some((host, _)) if host.contains(':') => Ok((entry, None)),
+        Some((host, port)) => Ok((host, Some(port.parse().map_err(|_| invalid_port(port))?))),
+        None => Ok((entry, None)),
+    }
+}
+
+/// Validate every broker entry of `MQTT_HOST` at parse time
+fn parse_mqtt_host(value: &str) -> Result<String, String> {
+    for entry in value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        split_host_port(entry)?;
+    }
+    Ok(value.to_string())
+}
+
+/// Reject prefixes that would produce invalid metric names
+fn parse_metric_prefix(value: &str) -> Result<String, String> {
+    validate_metric_prefix(value)?;
+    Ok(value.to_string())
+}
+
+/// Path given with `--config`, or `CONFIG_FILE`, before the full parse
+fn config_file_arg(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1).map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(|path| PathBuf::from(path.as_ref()));
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os("CONFIG_FILE").map(PathBuf::from)
+}
+
+/// Turn the config file into `--option value` arguments, skipping options given on
+/// the command line so those take precedence
+fn config_file_args(path: &Path, cli_args: &[OsString]) -> Result<Vec<OsString>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let table: toml::Table = toml::from_str(&contents)
+        .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+
+    let command = Config::command();
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == key.as_str() && arg.get_long().is_some())
+            .with_context(|| format!("Unknown option {:?} in {}", key, path.display()))?;
+        let flag = format!("--{}", arg.get_long().unwrap_or_default());
+        let on_cli = cli_args.iter().any(|cli_arg| {
+            let cli_arg = cli_arg.to_string_lossy();
+            cli_arg == flag || cli_arg.starts_with(&format!("{}=", flag))
+        });
+        if on_cli {
+            continue;
+        }
+
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                // Switches take no value, so `false` leaves them unset
+                toml::Value::Boolean(value) if !arg.get_action().takes_values() => {
+                    if value {
+                        args.push(flag.clone().into());
+                    }
+                    continue;
+                }
+                toml::Value::Boolean(value) => value.to_string(),
+                other => anyhow::bail!(
+                    "Unsupported value for {:?} in {}: {}",
+                    key,
+                    path.display(),
+                    other
+                ),
+            };
+            args.push(flag.clone().into());
+            args.push(value.into());
+        }
+    }
+    Ok(args)
 }
 
 impl Config {
+    /// Parse the command line and environment, with values from the config file in
+    /// between; exits with usage on invalid arguments like `Config::parse`
+    pub fn load() -> Result<Self> {
+        match Self::try_load_from(std::env::args_os().collect()) {
+            Ok(config) => Ok(config),
+            Err(e) => match e.downcast::<clap::Error>() {
+                Ok(e) => e.exit(),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    /// Precedence is command line, then config file, then environment, then defaults
+    pub fn try_load_from(args: Vec<OsString>) -> Result<Self> {
+        let mut config = match config_file_arg(&args) {
+            Some(path) => {
+                let file_args = config_file_args(&path, &args)?;
+                // File values go before the real arguments, after the program name
+                let merged = args
+                    .iter()
+                    .take(1)
+                    .cloned()
+                    .chain(file_args)
+                    .chain(args.iter().skip(1).cloned());
+                Self::try_parse_from(merged)?
+            }
+            None => Self::try_parse_from(args)?,
+        };
+        config.resolve_password_file()?;
+        config.resolve_alias_file()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks across options that clap cannot express, so bad combinations fail at
+    /// startup rather than on every reconnect
+    fn validate(&self) -> Result<()> {
+        qos_from_level(self.mqtt_qos)?;
+        // The broker restores a persistent session by client ID, so it must be set
+        anyhow::ensure!(
+            self.mqtt_clean_session || !self.mqtt_client_id.is_empty(),
+            "MQTT_CLEAN_SESSION=false requires a non-empty MQTT_CLIENT_ID"
+        );
+        anyhow::ensure!(
+            self.mqtt_username.is_some() == self.mqtt_password.is_some(),
+            "MQTT_USERNAME and MQTT_PASSWORD must be set together, or neither to connect anonymously"
+        );
+        Ok(())
+    }
+
+    /// Replace `mqtt_password` with the contents of `mqtt_password_file`, if set
+    fn resolve_password_file(&mut self) -> Result<()> {
+        if let Some(path) = &self.mqtt_password_file {
+            let password = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read MQTT password file {}", path.display()))?;
+            self.mqtt_password = Some(password.trim_end_matches(['\r', '\n']).to_string());
+        }
+        Ok(())
+    }
+
+    /// Prepend the aliases in `device_alias_file`, if set, skipping blank and `#` lines
+    fn resolve_alias_file(&mut self) -> Result<()> {
+        let Some(path) = &self.device_alias_file else {
+            return Ok(());
+        };
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read device alias file {}", path.display()))?;
+        let mut aliases = contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|(index, line)| {
+                line.parse::<DeviceAlias>()
+                    .map_err(|e| anyhow!("{}:{}: {}", path.display(), index + 1, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Later entries win, so explicit aliases override the file
+        aliases.append(&mut self.device_alias);
+        self.device_alias = aliases;
+        Ok(())
+    }
+
     pub fn mqtt_server(&self) -> String {
-        format!("{}:{}", self.mqtt_host, self.mqtt_port)
+        self.mqtt_brokers()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Brokers in failover order; entries without a port use `MQTT_PORT`
+    pub fn mqtt_brokers(&self) -> Vec<BrokerEndpoint> {
+        self.mqtt_host
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| split_host_port(entry).ok())
+            .map(|(host, port)| BrokerEndpoint {
+                host: host.to_string(),
+                port: port.unwrap_or(self.mqtt_port),
+            })
+            .collect()
+    }
+
+    pub fn metrics_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.metrics_bind_addr, self.metrics_port)
+    }
+
+    pub fn metrics_options(&self) -> MetricsOptions {
+        MetricsOptions {
+            emit_legacy_scaled: self.emit_legacy_scaled,
+            infer_device_type: self.infer_device_type,
+            max_components_per_message: (self.max_components_per_message > 0)
+                .then_some(self.max_components_per_message),
+            max_channels_per_device: (self.max_channels_per_device > 0)
+                .then_some(self.max_channels_per_device),
+            max_devices: (self.max_devices > 0).then_some(self.max_devices),
+            energy_stall_updates: (self.energy_stall_updates > 0)
+                .then_some(self.energy_stall_updates),
+            computed_power: self.computed_power,
+            debug_payload_label: self.debug_payload_label,
+            device_name_topic_index: self.device_name_topic_index,
+            device_aliases: self.device_alias.clone(),
+            name_precedence: self.name_precedence.clone(),
+            per_component_freshness: self.per_component_freshness,
+            battery_low_percent: (self.battery_low_percent > 0.0)
+                .then_some(self.battery_low_percent),
+            battery_mismatch_percent: (self.battery_mismatch_percent > 0.0)
+                .then_some(self.battery_mismatch_percent),
+            input_calibration: self.input_calibration.clone(),
+            broker_label: self.broker_label.then(|| self.mqtt_server()),
+            site_from_topic_segment: self.site_from_topic_segment,
+            metric_prefix: Some(self.metric_prefix.clone()),
+            scaling_round: self.scaling_round,
+            disabled_metrics: self.disabled_metrics.clone(),
+            temperature_unit: self.temperature_unit,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use clap::CommandFactory;
 
     #[test]
     fn test_mqtt_server() {
         let config = Config {
             mqtt_host: "localhost".to_string(),
             mqtt_port: 1883,
-            mqtt_username: "user".to_string(),
-            mqtt_password: "pass".to_string(),
-            mqtt_topic: "test/#".to_string(),
+            mqtt_username: Some("user".to_string()),
+            mqtt_password: Some("pass".to_string()),
+            mqtt_password_file: None,
+            mqtt_topic: vec!["test/#".to_string()],
+            discovery_url: None,
             mqtt_client_id: "test".to_string(),
+            mqtt_qos: 0,
+            mqtt_clean_session: true,
+            mqtt_keep_alive_seconds: 30,
+            mqtt_transport: MqttTransport::Tcp,
+            mqtt_ws_path: "/mqtt".to_string(),
             metrics_port: 8080,
+            metrics_bind_addr: IpAddr::from([127, 0, 0, 1]),
+            tolerate_server_failure: false,
+            metrics_auth_token: None,
+            metrics_snapshot_interval_seconds: None,
+            max_components_per_message: 64,
+            max_channels_per_device: 16,
+            max_devices: 1000,
+            energy_stall_updates: 10,
+            mqtt_reconnect_base_seconds: 1,
+            mqtt_reconnect_max_seconds: 60,
+            mqtt_max_auth_failures: 0,
+            message_watchdog_secs: 0,
+            computed_power: false,
+            debug_payload_label: false,
+            per_component_freshness: false,
+            battery_low_percent: 20.0,
+            battery_mismatch_percent: 25.0,
+            metric_ttl_seconds: 3600,
+            registry_shards: 1,
+            input_calibration: Vec::new(),
+            broker_label: false,
+            site_from_topic_segment: false,
+            metric_prefix: DEFAULT_METRIC_PREFIX.to_string(),
+            process_events: false,
+            emit_only_changed: false,
+            state_file: None,
+            replay: None,
+            config: None,
+            archive_path: None,
+            textfile_dir: None,
+            textfile_interval_seconds: 15,
+            pushgateway_url: None,
+            push_interval_seconds: 15,
+            state_save_interval_seconds: 60,
+            device_name_topic_index: None,
+            device_alias: Vec::new(),
+            device_alias_file: None,
+            name_precedence: vec![NameSource::Alias, NameSource::Topic, NameSource::Mac],
+            accept_compressed_payloads: false,
+            case_insensitive_topics: false,
+            emit_legacy_scaled: false,
+            scaling_round: ScalingRound::Truncate,
+            disabled_metrics: Vec::new(),
+            temperature_unit: TemperatureUnit::Celsius,
+            infer_device_type: false,
+            http_access_log: false,
+            http_access_log_level: tracing::Level::INFO,
+            print_metrics_list: false,
+        };
+
+        assert_eq!(config.mqtt_server(), "localhost:1883");
+    }
+
+    #[test]
+    fn test_command_definition() {
+        Config::command().debug_assert();
+    }
+
+    #[test]
+    fn test_input_calibration_parsing() {
+        let config = Config::try_parse_from([
+            "mqtt2prom",
+            "--print-metrics-list",
+            "--input-calibration",
+            "100=0.5,-10",
+            "--input-calibration",
+            "101=2,0",
+        ])
+        .unwrap();
+        assert_eq!(config.input_calibration.len(), 2);
+        assert_eq!(config.input_calibration[0].apply(64.0), 22.0);
+
+        let invalid = Config::try_parse_from([
+            "mqtt2prom",
+            "--print-metrics-list",
+            "--input-calibration",
+            "100=0.5",
+        ]);
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_metrics_bind_addr() {
+        let args = ["mqtt2prom", "--print-metrics-list"];
+        let config = Config::try_parse_from(args).unwrap();
+        assert_eq!(config.metrics_addr().to_string(), "0.0.0.0:8080");
+
+        let config =
+            Config::try_parse_from(args.iter().chain(&["--metrics-bind-addr", "::1"])).unwrap();
+        assert_eq!(config.metrics_addr().to_string(), "[::1]:8080");
+
+        let error =
+            Config::try_parse_from(args.iter().chain(&["--metrics-bind-addr", "localhost"]))
+                .unwrap_err();
+        assert!(error.to_string().contains("invalid IP address syntax"));
+    }
+
+    #[test]
+    fn test_mqtt_broker_list() {
+        let parse = |host: &str| {
+            Config::try_parse_from([
+                "mqtt2prom",
+                "--mqtt-host",
+                host,
+                "--mqtt-username",
+                "user",
+                "--mqtt-password",
+                "pass",
+            ])
         };
 
+        let config = parse("a:1883,b:1883").unwrap();
+        let brokers = config.mqtt_brokers();
+        assert_eq!(brokers.len(), 2);
+        assert_eq!(brokers[0].to_string(), "a:1883");
+        assert_eq!(brokers[1].to_string(), "b:1883");
+
+        // A single host keeps using MQTT_PORT
+        let config = parse("localhost").unwrap();
+        assert_eq!(
+            config.mqtt_brokers(),
+            vec![BrokerEndpoint {
+                host: "localhost".to_string(),
+                port: 1883
+            }]
+        );
         assert_eq!(config.mqtt_server(), "localhost:1883");
+
+        assert_eq!(parse("[::1]:8883").unwrap().mqtt_server(), "[::1]:8883");
+        assert!(parse("a:1883,b:port").is_err());
+    }
+
+    #[test]
+    fn test_mqtt_transport() {
+        let parse = |transport: &str| {
+            Config::try_parse_from([
+                "mqtt2prom",
+                "--mqtt-host",
+                "localhost",
+                "--mqtt-transport",
+                transport,
+            ])
+        };
+        assert_eq!(parse("ws").unwrap().mqtt_transport, MqttTransport::Ws);
+        assert_eq!(parse("wss").unwrap().mqtt_transport, MqttTransport::Wss);
+        assert!(parse("quic").is_err());
+    }
+
+    #[test]
+    fn test_mqtt_qos_range() {
+        let parse = |qos: &str| {
+            Config::try_parse_from(["mqtt2prom", "--mqtt-host", "localhost", "--mqtt-qos", qos])
+        };
+        assert_eq!(parse("1").unwrap().mqtt_qos, 1);
+        assert!(parse("3").is_err());
+
+        let config = Config::try_parse_from(["mqtt2prom", "--mqtt-host", "localhost"]).unwrap();
+        assert_eq!(config.mqtt_qos, 0);
+        assert_eq!(config.mqtt_keep_alive_seconds, 30);
+    }
+
+    #[test]
+    fn test_mqtt_clean_session() {
+        let parse = |extra: &[&str]| {
+            let args = ["mqtt2prom", "--mqtt-host", "localhost"];
+            Config::try_parse_from(args.iter().chain(extra)).unwrap()
+        };
+        assert!(parse(&[]).mqtt_clean_session);
+        assert!(!parse(&["--mqtt-clean-session", "false"]).mqtt_clean_session);
+        assert!(parse(&["--mqtt-clean-session", "true"]).mqtt_clean_session);
+
+        let load = |extra: &[&str]| {
+            let args = ["mqtt2prom", "--mqtt-host", "localhost"];
+            Config::try_load_from(args.iter().chain(extra).map(OsString::from).collect())
+        };
+        let err = load(&["--mqtt-clean-session", "false", "--mqtt-client-id", ""]).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("requires a non-empty MQTT_CLIENT_ID"));
+        assert!(load(&[
+            "--mqtt-clean-session",
+            "false",
+            "--mqtt-client-id",
+            "exporter"
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn test_mqtt_credentials_set_together() {
+        let load = |extra: &[&str]| {
+            let args = ["mqtt2prom", "--mqtt-host", "localhost"];
+            Config::try_load_from(args.iter().chain(extra).map(OsString::from).collect())
+        };
+        for half in [&["--mqtt-username", "u"][..], &["--mqtt-password", "p"]] {
+            let err = load(half).unwrap_err();
+            assert!(err.to_string().contains("must be set together"), "{}", err);
+        }
+        assert!(load(&[]).is_ok());
+        assert!(load(&["--mqtt-username", "u", "--mqtt-password", "p"]).is_ok());
+    }
+
+    #[test]
+    fn test_multiple_topics() {
+        let parse = |topics: &str| {
+            Config::try_parse_from([
+                "mqtt2prom",
+                "--mqtt-host",
+                "localhost",
+                "--mqtt-topic",
+                topics,
+            ])
+            .unwrap()
+            .mqtt_topic
+        };
+        assert_eq!(
+            parse("mostert/shelly/#,lab/devices/#"),
+            ["mostert/shelly/#", "lab/devices/#"]
+        );
+        assert_eq!(parse("mostert/shelly/#"), ["mostert/shelly/#"]);
+    }
+
+    #[test]
+    fn test_metric_prefix() {
+        let parse = |prefix: &str| {
+            Config::try_parse_from([
+                "mqtt2prom",
+                "--mqtt-host",
+                "localhost",
+                "--metric-prefix",
+                prefix,
+            ])
+        };
+        assert_eq!(parse("home").unwrap().metric_prefix, "home");
+        assert!(parse("home-shelly").is_err());
+    }
+
+    #[test]
+    fn test_anonymous_credentials() {
+        let config = Config::try_parse_from(["mqtt2prom", "--mqtt-host", "localhost"]).unwrap();
+        assert_eq!(config.mqtt_username, None);
+        assert_eq!(config.mqtt_password, None);
+    }
+
+    #[test]
+    fn test_device_alias_file() {
+        let path =
+            std::env::temp_dir().join(format!("mqtt2prom-alias-test-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# kitchen\nd48afc781ad8=espresso\n\n3030f9e7d294 = temp-main\n",
+        )
+        .unwrap();
+
+        let file = path.to_str().unwrap();
+        let load = |extra: &[&str]| {
+            let args = [
+                "mqtt2prom",
+                "--mqtt-host",
+                "localhost",
+                "--device-alias-file",
+                file,
+            ];
+            Config::try_load_from(args.iter().chain(extra).map(OsString::from).collect())
+        };
+        let config = load(&["--device-alias", "d48afc781ad8=grinder"]).unwrap();
+        std::fs::write(&path, "d48afc781ad8\n").unwrap();
+        let invalid = load(&[]);
+        std::fs::remove_file(&path).unwrap();
+
+        let aliases: Vec<_> = config
+            .device_alias
+            .iter()
+            .map(|alias| (alias.mac.as_str(), alias.name.as_str()))
+            .collect();
+        assert_eq!(
+            aliases,
+            [
+                ("d48afc781ad8", "espresso"),
+                ("3030f9e7d294", "temp-main"),
+                ("d48afc781ad8", "grinder"),
+            ]
+        );
+        assert!(invalid
+            .unwrap_err()
+            .to_string()
+            .ends_with(":1: expected mac=name, got \"d48afc781ad8\""));
+    }
+
+    #[test]
+    fn test_mqtt_password_file() {
+        let path =
+            std::env::temp_dir().join(format!("mqtt2prom-password-test-{}", std::process::id()));
+        std::fs::write(&path, "s3cret\n").unwrap();
+
+        let load = |extra: &[&str]| {
+            let args = [
+                "mqtt2prom",
+                "--mqtt-host",
+                "localhost",
+                "--mqtt-username",
+                "u",
+            ];
+            Config::try_load_from(args.iter().chain(extra).map(OsString::from).collect())
+        };
+        let file = path.to_str().unwrap();
+        let config = load(&["--mqtt-password-file", file]);
+        // The file wins over a password given directly
+        let both = load(&["--mqtt-password", "plain", "--mqtt-password-file", file]);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.unwrap().mqtt_password.as_deref(), Some("s3cret"));
+        assert_eq!(both.unwrap().mqtt_password.as_deref(), Some("s3cret"));
+
+        let err = load(&["--mqtt-password-file", file]).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Failed to read MQTT password file"));
+    }
+
+    #[test]
+    fn test_config_file() {
+        let path =
+            std::env::temp_dir().join(format!("mqtt2prom-config-test-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+mqtt_host = "broker.example:8883"
+mqtt_username = "exporter"
+mqtt_password = "s3cret"
+metrics_port = 9100
+emit_legacy_scaled = true
+input_calibration = ["100=1.5,0", "101=2,-10"]
+"#,
+        )
+        .unwrap();
+
+        let args = [
+            "mqtt2prom",
+            "--config",
+            path.to_str().unwrap(),
+            "--metrics-port",
+            "9200",
+        ];
+        let config = Config::try_load_from(args.iter().map(OsString::from).collect());
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!(config.mqtt_server(), "broker.example:8883");
+        assert_eq!(config.mqtt_username.as_deref(), Some("exporter"));
+        assert_eq!(config.mqtt_password.as_deref(), Some("s3cret"));
+        assert!(config.emit_legacy_scaled);
+        assert_eq!(config.input_calibration.len(), 2);
+        // The command line wins over the file, untouched options keep their defaults
+        assert_eq!(config.metrics_port, 9200);
+        assert_eq!(config.mqtt_topic, ["mostert/shelly/#"]);
+    }
+
+    #[test]
+    fn test_config_file_unknown_option() {
+        let path = std::env::temp_dir().join(format!(
+            "mqtt2prom-config-unknown-test-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(&path, "mqtt_hostname = \"typo\"\n").unwrap();
+
+        let args = ["mqtt2prom", "--config", path.to_str().unwrap()];
+        let result = Config::try_load_from(args.iter().map(OsString::from).collect());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.unwrap_err().to_string().contains("mqtt_hostname"));
+    }
+
+    #[test]
+    fn test_print_metrics_list_without_mqtt_settings() {
+        let config = Config::try_parse_from(["mqtt2prom", "--print-metrics-list"]).unwrap();
+        assert!(config.print_metrics_list);
     }
 }
@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::config::Config;
+
+/// Broker and topic settings served by a discovery endpoint; absent fields keep the local value
+#[derive(Debug, Default, Deserialize, PartialEq)]
+pub struct Discovery {
+    pub mqtt_host: Option<String>,
+    pub mqtt_port: Option<u16>,
+    pub mqtt_topic: Option<String>,
+}
+
+impl Discovery {
+    /// Override the matching `config` fields
+    pub fn apply(self, config: &mut Config) {
+        if let Some(host) = self.mqtt_host {
+            config.mqtt_host = host;
+        }
+        if let Some(port) = self.mqtt_port {
+            config.mqtt_port = port;
+        }
+        if let Some(topic) = self.mqtt_topic {
+            config.mqtt_topic = topic.split(',').map(|t| t.trim().to_string()).collect();
+        }
+    }
+}
+
+/// Fetch the discovery document from `url`
+pub async fn fetch(url: &str, timeout: Duration) -> Result<Discovery> {
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .context("Failed to build discovery HTTP client")?;
+
+    client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Failed to query discovery endpoint {}", url))?
+        .json()
+        .await
+        .with_context(|| format!("Invalid discovery document from {}", url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_discovery_overrides_broker_and_topic() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/mqtt"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "mqtt_host": "broker.internal",
+                "mqtt_port": 8883,
+                "mqtt_topic": "site/shelly/#"
+            })))
+            .mount(&server)
+            .await;
+
+        let mut config = Config::try_parse_from([
+            "mqtt2prom",
+            "--discovery-url",
+            "http://unused",
+            "--mqtt-username",
+            "user",
+            "--mqtt-password",
+            "pass",
+            "--mqtt-client-id",
+            "local",
+        ])
+        .unwrap();
+
+        let url = format!("{}/mqtt", server.uri());
+        fetch(&url, Duration::from_secs(5))
+            .await
+            .unwrap()
+            .apply(&mut config);
+
+        assert_eq!(config.mqtt_server(), "broker.internal:8883");
+        assert_eq!(config.mqtt_topic, ["site/shelly/#"]);
+        // Fields the endpoint does not serve keep their local value
+        assert_eq!(config.mqtt_client_id, "local");
+    }
+
+    #[tokio::test]
+    async fn test_discovery_error_status() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&server)
+            .await;
+
+        let result = fetch(&server.uri(), Duration::from_secs(5)).await;
+        assert!(result.is_err());
+    }
+}
@@ -1,17 +1,77 @@
+mod archive;
 mod config;
+mod discovery;
 mod metrics;
 mod mqtt;
 mod parser;
+mod push;
+mod replay;
 mod server;
+mod state;
+mod textfile;
 
 use anyhow::Result;
-use clap::Parser;
 use prometheus_client::registry::Registry;
-use std::sync::{Arc, Mutex};
-use tracing::info;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Load configuration
+    let mut config = config::Config::load()?;
+
+    if config.print_metrics_list {
+        print!("{}", metrics::metrics_list());
+        return Ok(());
+    }
+
+    // Replay logs to stderr so stdout carries only the exposition
+    if let Some(path) = &config.replay {
+        tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .with_env_filter(
+                tracing_subscriber::EnvFilter::try_from_default_env()
+                    .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+            )
+            .init();
+        let exposition = replay::replay(path, &config.metrics_options(), config.process_events)?;
+        print!("{}", exposition);
+        return Ok(());
+    }
+
     // Initialize logging
     tracing_subscriber::fmt()
         .with_env_filter(
@@ -22,35 +82,170 @@ async fn main() -> Result<()> {
 
     info!("Starting mqtt2prom - MQTT to Prometheus exporter for Shelly devices");
 
-    // Load configuration
-    let config = config::Config::parse();
+    if let Some(url) = config.discovery_url.clone() {
+        discovery::fetch(&url, Duration::from_secs(10))
+            .await?
+            .apply(&mut config);
+        info!("Applied discovery settings from {}", url);
+        anyhow::ensure!(
+            !config.mqtt_host.is_empty(),
+            "No MQTT host from MQTT_HOST or the discovery endpoint"
+        );
+    }
+
     info!("Configuration loaded");
     info!("MQTT broker: {}", config.mqtt_server());
-    info!("MQTT topic: {}", config.mqtt_topic);
-    info!("Metrics port: {}", config.metrics_port);
+    info!("MQTT topics: {}", config.mqtt_topic.join(", "));
+    info!("Metrics address: {}", config.metrics_addr());
 
     // Initialize metrics registry
-    let registry = Arc::new(Mutex::new(Registry::default()));
-    let metrics = {
-        let mut reg = registry.lock().unwrap();
-        Arc::new(metrics::ShellyMetrics::new(&mut reg))
+    let registries: Vec<_> = (0..config.registry_shards)
+        .map(|_| Arc::new(RwLock::new(Registry::default())))
+        .collect();
+    let (metrics, exporter_metrics) = {
+        let mut guards: Vec<_> = registries
+            .iter()
+            .map(|r| server::write_registry(r))
+            .collect();
+        let mut regs: Vec<&mut Registry> = guards.iter_mut().map(|g| &mut **g).collect();
+        metrics::register_build_info(regs[0]);
+        let metrics = metrics::ShellyMetrics::sharded(&mut regs, &config.metrics_options());
+        let exporter_metrics = metrics::ExporterMetrics::new(regs[0]);
+        (Arc::new(metrics), Arc::new(exporter_metrics))
     };
 
     info!("Metrics registry initialized");
 
-    // Spawn HTTP server
-    let server_registry = registry.clone();
-    let server_port = config.metrics_port;
-    tokio::spawn(async move {
-        if let Err(e) = server::run(server_port, server_registry).await {
-            tracing::error!("HTTP server error: {}", e);
+    tokio::spawn(metrics::observe_process(
+        metrics.clone(),
+        exporter_metrics.clone(),
+        Duration::from_secs(15),
+    ));
+
+    if config.metric_ttl_seconds > 0 {
+        let ttl = Duration::from_secs(config.metric_ttl_seconds);
+        let interval = ttl.min(Duration::from_secs(60));
+        tokio::spawn(metrics::prune_stale(metrics.clone(), ttl, interval));
+        info!(
+            "Pruning series of devices not heard from for {}s",
+            config.metric_ttl_seconds
+        );
+    }
+
+    let state = match &config.state_file {
+        Some(path) => {
+            let store = Arc::new(state::StateStore::new(path));
+            match store.restore(&metrics) {
+                Ok(count) => info!("Restored {} messages from {}", count, path.display()),
+                Err(e) => warn!("Failed to restore device state: {:#}", e),
+            }
+            tokio::spawn(state::run_saver(
+                store.clone(),
+                Duration::from_secs(config.state_save_interval_seconds),
+            ));
+            Some(store)
+        }
+        None => None,
+    };
+
+    let archive = match &config.archive_path {
+        Some(path) => {
+            info!("Archiving parsed messages to {}", path.display());
+            Some(archive::Archive::open(path)?)
         }
+        None => None,
+    };
+
+    if let Some(dir) = &config.textfile_dir {
+        tokio::spawn(textfile::run_writer(
+            textfile::TextfileWriter::new(dir),
+            registries.clone(),
+            Duration::from_secs(config.textfile_interval_seconds),
+        ));
+        info!(
+            "Writing metrics to the textfile directory {}",
+            dir.display()
+        );
+    }
+
+    if let Some(url) = &config.pushgateway_url {
+        let interval = Duration::from_secs(config.push_interval_seconds);
+        tokio::spawn(push::run_pusher(
+            push::Pusher::new(url, interval)?,
+            registries.clone(),
+            exporter_metrics.clone(),
+            interval,
+        ));
+        info!("Pushing metrics to the Pushgateway at {}", url);
+    }
+
+    let mut server_state = server::AppState::with_registries(registries.clone());
+    let mqtt_connected = Arc::new(AtomicBool::new(false));
+    server_state.mqtt_connected = Some(mqtt_connected.clone());
+    server_state.data_received = Some(metrics.data_received());
+    server_state.config = Some(Arc::new(config.clone()));
+    if config.http_access_log {
+        server_state.access_log = Some(config.http_access_log_level);
+    }
+    if let Some(token) = &config.metrics_auth_token {
+        server_state.auth_token = Some(token.as_str().into());
+        info!("Requiring a bearer token on /metrics");
+    }
+    if config.emit_only_changed {
+        server_state.only_changed = Some(Arc::new(server::ChangeFilter::default()));
+        warn!(
+            "EMIT_ONLY_CHANGED is set: Prometheus marks omitted series stale, so unchanged gauges read as missing until they change"
+        );
+    }
+    if let Some(secs) = config.metrics_snapshot_interval_seconds {
+        let snapshot = Arc::new(server::MetricsSnapshot::default());
+        snapshot.refresh(&registries)?;
+        tokio::spawn(server::refresh_snapshot(
+            snapshot.clone(),
+            registries.clone(),
+            Duration::from_secs(secs),
+        ));
+        server_state.snapshot = Some(snapshot);
+        info!("Serving metrics from a snapshot refreshed every {}s", secs);
+    }
+
+    // Broadcast shutdown to the HTTP server and MQTT loop
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutdown signal received");
+        let _ = shutdown_tx.send(true);
+    });
+
+    // Spawn HTTP server
+    let server_addr = config.metrics_addr();
+    let tolerate_server_failure = config.tolerate_server_failure;
+    let mut server_shutdown = shutdown_rx.clone();
+    let server = tokio::spawn(async move {
+        let shutdown = async move {
+            let _ = server_shutdown.wait_for(|&stop| stop).await;
+        };
+        server::run_tolerant(server_addr, server_state, shutdown, tolerate_server_failure).await
     });
 
-    info!("HTTP server started on port {}", config.metrics_port);
+    info!("HTTP server started on {}", server_addr);
 
-    // Run MQTT client (blocks until error or shutdown)
-    mqtt::run(config, metrics).await?;
+    // Run MQTT client until shutdown; a server failure stops it unless tolerated,
+    // and on shutdown in-flight scrapes finish first
+    let server = async { server.await? };
+    tokio::try_join!(
+        mqtt::run(
+            config,
+            metrics,
+            exporter_metrics,
+            state,
+            archive,
+            mqtt_connected,
+            shutdown_rx
+        ),
+        server
+    )?;
+    info!("Shutdown complete");
 
     Ok(())
 }
@@ -1,9 +1,39 @@
-use prometheus_client::encoding::EncodeLabelSet;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use prometheus_client::encoding::{
+    EncodeLabelKey, EncodeLabelSet, EncodeLabelValue, LabelSetEncoder,
+};
+use prometheus_client::metrics::counter::{self, Counter};
 use prometheus_client::metrics::family::Family;
-use prometheus_client::metrics::gauge::Gauge;
-use prometheus_client::registry::Registry;
+use prometheus_client::metrics::gauge::{self, Gauge};
+use prometheus_client::metrics::{MetricType, TypedMetric};
+use prometheus_client::registry::{Metric, Registry};
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::parser::{
+    device_type_from_src, extract_device_from_topic_at, extract_device_id, DeviceInfoData,
+    DeviceType, EmData, EventData, MessageMethod, MessageParams, MotionData, Pm1Data,
+    ShellyMessage, SwitchData, DEFAULT_DEVICE_NAME_TOPIC_INDEX,
+};
 
-use crate::parser::{extract_device_from_topic, extract_device_id, ShellyMessage};
+/// Label names of a label set, so metrics can be documented without creating series
+pub trait LabelNames {
+    const NAMES: &'static [&'static str];
+
+    /// Device the series belongs to, used to look up its `site`
+    fn device(&self) -> Option<&str> {
+        None
+    }
+}
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub struct DeviceLabels {
@@ -11,383 +41,4687 @@ pub struct DeviceLabels {
     pub switch: String,
 }
 
+impl LabelNames for DeviceLabels {
+    const NAMES: &'static [&'static str] = &["device", "switch"];
+
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
+}
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub struct DeviceOnlyLabels {
     pub device: String,
 }
 
-pub struct ShellyMetrics {
-    power: Family<DeviceLabels, Gauge>,
-    voltage: Family<DeviceLabels, Gauge>,
-    current: Family<DeviceLabels, Gauge>,
-    energy_total: Family<DeviceLabels, Gauge>,
-    switch_state: Family<DeviceLabels, Gauge>,
-    temperature: Family<DeviceOnlyLabels, Gauge>,
-    humidity: Family<DeviceOnlyLabels, Gauge>,
-    battery_percent: Family<DeviceOnlyLabels, Gauge>,
-    battery_voltage: Family<DeviceOnlyLabels, Gauge>,
-    wifi_rssi: Family<DeviceOnlyLabels, Gauge>,
+impl LabelNames for DeviceOnlyLabels {
+    const NAMES: &'static [&'static str] = &["device"];
+
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
 }
 
-impl ShellyMetrics {
-    pub fn new(registry: &mut Registry) -> Self {
-        let power = Family::<DeviceLabels, Gauge>::default();
-        let voltage = Family::<DeviceLabels, Gauge>::default();
-        let current = Family::<DeviceLabels, Gauge>::default();
-        let energy_total = Family::<DeviceLabels, Gauge>::default();
-        let switch_state = Family::<DeviceLabels, Gauge>::default();
-        let temperature = Family::<DeviceOnlyLabels, Gauge>::default();
-        let humidity = Family::<DeviceOnlyLabels, Gauge>::default();
-        let battery_percent = Family::<DeviceOnlyLabels, Gauge>::default();
-        let battery_voltage = Family::<DeviceOnlyLabels, Gauge>::default();
-        let wifi_rssi = Family::<DeviceOnlyLabels, Gauge>::default();
-
-        registry.register(
-            "shelly_switch_power_watts",
-            "Current power consumption in watts",
-            power.clone(),
-        );
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ChannelLabels {
+    pub device: String,
+    pub channel: String,
+}
 
-        registry.register(
-            "shelly_switch_voltage_volts",
-            "Line voltage in volts",
-            voltage.clone(),
-        );
+impl LabelNames for ChannelLabels {
+    const NAMES: &'static [&'static str] = &["device", "channel"];
 
-        registry.register(
-            "shelly_switch_current_amps",
-            "Current draw in amps",
-            current.clone(),
-        );
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct SensorLabels {
+    pub device: String,
+    pub id: String,
+}
+
+impl LabelNames for SensorLabels {
+    const NAMES: &'static [&'static str] = &["device", "id"];
+
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct InputLabels {
+    pub device: String,
+    pub input: String,
+}
+
+impl LabelNames for InputLabels {
+    const NAMES: &'static [&'static str] = &["device", "input"];
+
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct LightLabels {
+    pub device: String,
+    pub light: String,
+}
+
+impl LabelNames for LightLabels {
+    const NAMES: &'static [&'static str] = &["device", "light"];
+
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct CoverStateLabels {
+    pub device: String,
+    pub channel: String,
+    pub state: String,
+}
+
+impl LabelNames for CoverStateLabels {
+    const NAMES: &'static [&'static str] = &["device", "channel", "state"];
+
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct LightColorLabels {
+    pub device: String,
+    pub channel: String,
+    pub color: String,
+}
+
+impl LabelNames for LightColorLabels {
+    const NAMES: &'static [&'static str] = &["device", "channel", "color"];
+
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ComponentLabels {
+    pub device: String,
+    pub component: String,
+}
+
+impl LabelNames for ComponentLabels {
+    const NAMES: &'static [&'static str] = &["device", "component"];
+
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct PayloadLabels {
+    pub device: String,
+    pub hash: String,
+}
+
+impl LabelNames for PayloadLabels {
+    const NAMES: &'static [&'static str] = &["device", "hash"];
+
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct EventLabels {
+    pub device: String,
+    pub event: String,
+}
+
+impl LabelNames for EventLabels {
+    const NAMES: &'static [&'static str] = &["device", "event"];
+
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct PhaseLabels {
+    pub device: String,
+    pub phase: String,
+}
+
+impl LabelNames for PhaseLabels {
+    const NAMES: &'static [&'static str] = &["device", "phase"];
+
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct VirtualLabels {
+    pub device: String,
+    pub id: String,
+    pub name: String,
+}
+
+impl LabelNames for VirtualLabels {
+    const NAMES: &'static [&'static str] = &["device", "id", "name"];
+
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeviceInfoLabels {
+    pub device: String,
+    pub model: String,
+    pub gen: String,
+    pub fw_id: String,
+}
+
+impl LabelNames for DeviceInfoLabels {
+    const NAMES: &'static [&'static str] = &["device", "model", "gen", "fw_id"];
+
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct WifiInfoLabels {
+    pub device: String,
+    pub ssid: String,
+}
+
+impl LabelNames for WifiInfoLabels {
+    const NAMES: &'static [&'static str] = &["device", "ssid"];
+
+    fn device(&self) -> Option<&str> {
+        Some(&self.device)
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct BuildInfoLabels {
+    pub version: String,
+    pub commit: String,
+}
+
+impl LabelNames for BuildInfoLabels {
+    const NAMES: &'static [&'static str] = &["version", "commit"];
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ReasonLabels {
+    pub reason: String,
+}
+
+impl LabelNames for ReasonLabels {
+    const NAMES: &'static [&'static str] = &["reason"];
+}
+
+/// Description of an exported metric, recorded when the metric is registered
+#[derive(Clone, Debug)]
+pub struct MetricDescriptor {
+    pub name: String,
+    pub help: String,
+    pub metric_type: MetricType,
+    pub unit: &'static str,
+    pub labels: &'static [&'static str],
+}
+
+impl fmt::Display for MetricDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            self.name,
+            self.metric_type.as_str(),
+            self.unit,
+            self.labels.join(","),
+            self.help
+        )
+    }
+}
+
+/// Options controlling which metric families are registered
+#[derive(Clone, Debug, Default)]
+pub struct MetricsOptions {
+    /// Also emit the deprecated integer families pre-multiplied by their scale factor
+    pub emit_legacy_scaled: bool,
+    /// Only check the components the device type inferred from `src` can report
+    pub infer_device_type: bool,
+    /// Count messages with more `<prefix>:<N>` components and only process this many
+    pub max_components_per_message: Option<usize>,
+    /// Count `switch:N` channels past this many per message and skip them
+    pub max_channels_per_device: Option<usize>,
+    /// Drop messages from new devices once this many devices have series
+    pub max_devices: Option<usize>,
+    /// Flag a switch's energy as stalled after this many updates without progress under load
+    pub energy_stall_updates: Option<u32>,
+    /// Derive power from consecutive energy totals and their `minute_ts`
+    pub computed_power: bool,
+    /// Export a hash of each device's last raw payload for debugging
+    pub debug_payload_label: bool,
+    /// Topic segment holding the device name, `DEFAULT_DEVICE_NAME_TOPIC_INDEX` if unset
+    pub device_name_topic_index: Option<usize>,
+    /// Friendly names for devices, keyed by `src` or its MAC
+    pub device_aliases: Vec<DeviceAlias>,
+    /// Order of the device label sources; empty means `DEFAULT_NAME_PRECEDENCE`
+    pub name_precedence: Vec<NameSource>,
+    /// Track when each component of a device was last reported
+    pub per_component_freshness: bool,
+    /// Flag batteries below this charge percentage as low
+    pub battery_low_percent: Option<f64>,
+    /// Flag batteries whose percent is off the voltage discharge curve by more than this
+    pub battery_mismatch_percent: Option<f64>,
+    /// Linear calibrations mapping analog input percent to engineering units
+    pub input_calibration: Vec<InputCalibration>,
+    /// Attach `broker="<host:port>"` to every device series
+    pub broker_label: Option<String>,
+    /// Attach `site="<first topic segment>"` to every device series
+    pub site_from_topic_segment: bool,
+    /// Prefix replacing `shelly` in device metric names, `DEFAULT_METRIC_PREFIX` if unset
+    pub metric_prefix: Option<String>,
+    /// Integer conversion of the legacy `_scaled` families
+    pub scaling_round: ScalingRound,
+    /// Device families left unregistered, as matched by [`disables`]
+    pub disabled_metrics: Vec<String>,
+    /// Which temperature families to export
+    pub temperature_unit: TemperatureUnit,
+}
+
+/// Linear mapping `value = percent * scale + offset` for analog input `id`,
+/// parsed from `id=scale,offset`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct InputCalibration {
+    pub id: u16,
+    pub scale: f64,
+    pub offset: f64,
+}
+
+impl InputCalibration {
+    pub fn apply(&self, percent: f64) -> f64 {
+        percent * self.scale + self.offset
+    }
+}
+
+impl std::str::FromStr for InputCalibration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected id=scale,offset, got {:?}", s);
+        let (id, coefficients) = s.split_once('=').ok_or_else(invalid)?;
+        let (scale, offset) = coefficients.split_once(',').ok_or_else(invalid)?;
+        Ok(Self {
+            id: id.trim().parse().map_err(|_| invalid())?,
+            scale: scale.trim().parse().map_err(|_| invalid())?,
+            offset: offset.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Whether a `--disabled-metrics` entry names the device family `name`, given as its full
+/// name, its name without `shelly_`, or the trailing words of that without the unit, so
+/// `voltage` disables `shelly_switch_voltage_volts` and `shelly_pm1_voltage_volts`
+pub fn disables(entry: &str, name: &str, unit: &str) -> bool {
+    let Some(rest) = name.strip_prefix("shelly_") else {
+        return false;
+    };
+    let short = rest
+        .strip_suffix(unit)
+        .and_then(|short| short.strip_suffix('_'))
+        .unwrap_or(rest);
+    entry == name
+        || entry == rest
+        || entry == short
+        || short
+            .strip_suffix(entry)
+            .is_some_and(|head| head.ends_with('_'))
+}
+
+/// Friendly device name for a `src` or MAC, parsed from `mac=name`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DeviceAlias {
+    pub mac: String,
+    pub name: String,
+}
+
+impl std::str::FromStr for DeviceAlias {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((mac, name)) if !mac.trim().is_empty() && !name.trim().is_empty() => Ok(Self {
+                mac: mac.trim().to_string(),
+                name: name.trim().to_string(),
+            }),
+            _ => Err(format!("expected mac=name, got {:?}", s)),
+        }
+    }
+}
+
+/// Where a device label can come from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NameSource {
+    /// A `--device-alias` for the device's `src` or MAC
+    Alias,
+    /// The topic segment at `--device-name-topic-index`
+    Topic,
+    /// The MAC suffix of `src`, always available
+    Mac,
+}
+
+/// Aliases first, then the topic, then the MAC
+pub const DEFAULT_NAME_PRECEDENCE: [NameSource; 3] =
+    [NameSource::Alias, NameSource::Topic, NameSource::Mac];
+
+/// Float gauge with unscaled values
+pub type FloatGauge = Gauge<f64, AtomicU64>;
+
+/// Float counter for lifetime totals the device reports as absolute readings
+pub type FloatCounter = Counter<f64, AtomicU64>;
+
+/// A metric that can be set to an absolute float reading
+pub trait SetFloat {
+    fn set_float(&self, value: f64);
+}
+
+impl SetFloat for FloatGauge {
+    fn set_float(&self, value: f64) {
+        self.set(value);
+    }
+}
+
+/// Counters cannot be set, so the reading is stored in the inner atomic. A total
+/// that drops (a device reset or replacement) is stored as is, which Prometheus
+/// treats as a counter reset in `rate()` and `increase()`.
+impl SetFloat for FloatCounter {
+    fn set_float(&self, value: f64) {
+        self.inner().store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Site of each device, the first segment of the topic it was last seen on
+type SiteMap = Arc<RwLock<HashMap<String, String>>>;
+
+/// A label set with the `site` of its device, which leads the labels when known
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct Sited<L> {
+    site: Option<String>,
+    labels: L,
+}
+
+impl<L> Sited<L> {
+    /// Labels of a family that is not per device, which never has a site
+    fn unsited(labels: L) -> Self {
+        Self { site: None, labels }
+    }
+}
+
+impl<L> Deref for Sited<L> {
+    type Target = L;
+
+    fn deref(&self) -> &L {
+        &self.labels
+    }
+}
+
+/// Site of `labels`' device in `sites`, built once per component and reused by
+/// every family it updates
+fn sited<L: LabelNames>(sites: Option<&SiteMap>, labels: L) -> Sited<L> {
+    let site = sites.and_then(|sites| {
+        let device = labels.device()?;
+        sites
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(device)
+            .cloned()
+    });
+    Sited { site, labels }
+}
+
+impl<L: EncodeLabelSet> EncodeLabelSet for Sited<L> {
+    fn encode(&self, mut encoder: LabelSetEncoder) -> Result<(), fmt::Error> {
+        if let Some(site) = &self.site {
+            let mut label = encoder.encode_label();
+            let mut key = label.encode_label_key()?;
+            EncodeLabelKey::encode(&"site", &mut key)?;
+            let mut value = key.encode_label_value()?;
+            EncodeLabelValue::encode(site, &mut value)?;
+            value.finish()?;
+        }
+        self.labels.encode(encoder)
+    }
+}
+
+/// A metric family that remembers the label sets of its series, so the series
+/// of devices that stopped reporting can be removed
+pub struct Tracked<L, M> {
+    /// `None` for a `--disabled-metrics` family, which ignores every update
+    family: Option<Family<Sited<L>, M>>,
+    /// Label sets of the series held; existing series are found under the read
+    /// lock, so concurrent updates do not wait on each other
+    held: RwLock<HashSet<Sited<L>>>,
+}
+
+impl<L: LabelNames + Clone + Hash + Eq, M: Default> Tracked<L, M> {
+    fn new(family: Family<Sited<L>, M>) -> Self {
+        Self {
+            family: Some(family),
+            held: RwLock::default(),
+        }
+    }
+
+    /// A family that is not registered and never creates series
+    fn disabled() -> Self {
+        Self {
+            family: None,
+            ..Self::new(Family::default())
+        }
+    }
+
+    /// Get or create the series for `labels`; `None` for a disabled family, before
+    /// any locking
+    fn series(&self, labels: &Sited<L>) -> Option<impl Deref<Target = M> + '_> {
+        let family = self.family.as_ref()?;
+        let known = self
+            .held
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(labels);
+        // Only a new series takes the write lock and clones its labels
+        if !known {
+            self.held
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(labels.clone());
+        }
+        Some(family.get_or_create(labels))
+    }
+
+    /// Remove a single series, under whichever site it was created
+    fn remove(&self, labels: &L) {
+        let Some(family) = &self.family else {
+            return;
+        };
+        self.held
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|sited| {
+                let matches = sited.labels == *labels;
+                if matches {
+                    family.remove(sited);
+                }
+                !matches
+            });
+    }
+}
+
+impl<L: LabelNames + Clone + Hash + Eq, N, A: gauge::Atomic<N> + Default> Tracked<L, Gauge<N, A>> {
+    fn set(&self, labels: &Sited<L>, value: N) {
+        if let Some(series) = self.series(labels) {
+            series.set(value);
+        }
+    }
+}
+
+impl<L: LabelNames + Clone + Hash + Eq, N, A: counter::Atomic<N> + Default>
+    Tracked<L, Counter<N, A>>
+{
+    fn inc(&self, labels: &Sited<L>) {
+        if let Some(series) = self.series(labels) {
+            series.inc();
+        }
+    }
+
+    fn inc_by(&self, labels: &Sited<L>, value: N) {
+        if let Some(series) = self.series(labels) {
+            series.inc_by(value);
+        }
+    }
+}
+
+/// Families whose series can be counted and removed by device
+trait TrackedSeries {
+    /// Number of series currently held
+    fn len(&self) -> usize;
+
+    /// Remove every series of the given devices, returning how many were removed
+    fn remove_devices(&self, devices: &HashSet<&str>) -> usize;
+}
+
+impl<L: LabelNames + Clone + Hash + Eq, M: Default> TrackedSeries for Tracked<L, M> {
+    fn len(&self) -> usize {
+        self.held
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    fn remove_devices(&self, devices: &HashSet<&str>) -> usize {
+        let Some(family) = &self.family else {
+            return 0;
+        };
+        let mut held = self.held.write().unwrap_or_else(PoisonError::into_inner);
+        let before = held.len();
+        held.retain(|labels| {
+            let stale = labels
+                .device()
+                .is_some_and(|device| devices.contains(device));
+            if stale {
+                family.remove(labels);
+            }
+            !stale
+        });
+        before - held.len()
+    }
+}
+
+/// A value exported as an unscaled float gauge (or counter) and, during the deprecation
+/// window, as the legacy integer gauge `<name>_scaled` multiplied by `factor`
+pub struct ScaledGauge<L, M = FloatGauge> {
+    value: Tracked<L, M>,
+    legacy: Option<Tracked<L, Gauge>>,
+    factor: i64,
+    round: ScalingRound,
+}
+
+impl<L: LabelNames + Clone + Hash + Eq, M: Default> TrackedSeries for ScaledGauge<L, M> {
+    fn len(&self) -> usize {
+        self.value.len() + self.legacy.as_ref().map_or(0, |legacy| legacy.len())
+    }
+
+    fn remove_devices(&self, devices: &HashSet<&str>) -> usize {
+        let legacy = self.legacy.as_ref();
+        self.value.remove_devices(devices) + legacy.map_or(0, |l| l.remove_devices(devices))
+    }
+}
+
+impl<L: LabelNames + Clone + Hash + Eq, M: Default + SetFloat> ScaledGauge<L, M> {
+    /// A gauge that is not registered and never creates series
+    fn disabled() -> Self {
+        Self {
+            value: Tracked::disabled(),
+            legacy: None,
+            factor: 1,
+            round: ScalingRound::default(),
+        }
+    }
+
+    fn set(&self, labels: &Sited<L>, value: f64) {
+        if let Some(series) = self.value.series(labels) {
+            series.set_float(value);
+        }
+        if let Some(legacy) = &self.legacy {
+            legacy.set(labels, self.round.scale(value, self.factor));
+        }
+    }
+}
+
+/// How the legacy `_scaled` families convert the scaled value to an integer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScalingRound {
+    /// Drop the fraction, as releases before the float gauges did
+    #[default]
+    Truncate,
+    /// Round to the nearest integer, half away from zero
+    Round,
+}
+
+/// Unit of the exported temperature families
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TemperatureUnit {
+    /// `shelly_temperature_celsius` only
+    #[default]
+    Celsius,
+    /// `shelly_temperature_fahrenheit` only, from the reported `tF`
+    Fahrenheit,
+    /// Both families
+    Both,
+}
+
+impl TemperatureUnit {
+    fn celsius(self) -> bool {
+        self != TemperatureUnit::Fahrenheit
+    }
+
+    fn fahrenheit(self) -> bool {
+        self != TemperatureUnit::Celsius
+    }
+}
+
+impl ScalingRound {
+    /// Multiply `value` by `factor` and convert it to an integer
+    pub fn scale(self, value: f64, factor: i64) -> i64 {
+        match self {
+            ScalingRound::Truncate => (value * factor as f64) as i64,
+            ScalingRound::Round => scale(value, factor),
+        }
+    }
+}
+
+/// Multiply `value` by `factor`, rounding to the nearest integer (half away from zero),
+/// so 1.0259 A at `CURRENT_SCALE` is 1026 rather than 1025
+pub fn scale(value: f64, factor: i64) -> i64 {
+    (value * factor as f64).round() as i64
+}
+
+/// Factors of the legacy `_scaled` families, the integer each reading used to be
+/// multiplied by before the float gauges
+pub const VOLTAGE_SCALE: i64 = 10;
+pub const CURRENT_SCALE: i64 = 1000;
+pub const FREQUENCY_SCALE: i64 = 10;
+pub const ENERGY_SCALE: i64 = 10;
+pub const TEMPERATURE_SCALE: i64 = 10;
+pub const HUMIDITY_SCALE: i64 = 10;
+pub const BATTERY_VOLTAGE_SCALE: i64 = 100;
+
+/// Prefix of the device metric names
+pub const DEFAULT_METRIC_PREFIX: &str = "shelly";
+
+/// Check a metric prefix against the Prometheus name rules, `[a-zA-Z_][a-zA-Z0-9_]*`
+pub fn validate_metric_prefix(prefix: &str) -> Result<(), String> {
+    let mut chars = prefix.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "metric prefix {:?} must match [a-zA-Z_][a-zA-Z0-9_]*",
+            prefix
+        ))
+    }
+}
+
+/// Registers metric families and records a descriptor for each of them
+struct Registrar<'a> {
+    registry: &'a mut Registry,
+    options: &'a MetricsOptions,
+    sites: Option<SiteMap>,
+    descriptors: Vec<MetricDescriptor>,
+    /// `disabled_metrics` entries that matched a family
+    disabled_matched: BTreeSet<String>,
+}
+
+impl<'a> Registrar<'a> {
+    fn new(registry: &'a mut Registry, options: &'a MetricsOptions) -> Self {
+        Self {
+            registry,
+            options,
+            sites: None,
+            descriptors: Vec::new(),
+            disabled_matched: BTreeSet::new(),
+        }
+    }
+
+    /// Site map for the shards registered through this registrar to label their series with
+    fn with_sites(mut self, sites: Option<SiteMap>) -> Self {
+        self.sites = sites;
+        self
+    }
+
+    fn scaled<L, M>(
+        &mut self,
+        name: &str,
+        help: &str,
+        unit: &'static str,
+        factor: i64,
+    ) -> ScaledGauge<L, M>
+    where
+        L: LabelNames + Clone + Hash + Eq,
+        M: Default + TypedMetric,
+        Family<Sited<L>, M>: Metric,
+        Family<Sited<L>, Gauge>: Metric,
+    {
+        let value = self.family(name, help, unit);
+        let legacy = self.options.emit_legacy_scaled.then(|| {
+            self.family(
+                &format!("{}_scaled", name),
+                &format!("{} (deprecated, scaled x{})", help, factor),
+                unit,
+            )
+        });
+
+        ScaledGauge {
+            value,
+            legacy,
+            factor,
+            round: self.options.scaling_round,
+        }
+    }
+
+    fn family<L, M>(&mut self, name: &str, help: &str, unit: &'static str) -> Tracked<L, M>
+    where
+        L: LabelNames + Clone + Hash + Eq,
+        M: Default + TypedMetric,
+        Family<Sited<L>, M>: Metric,
+    {
+        let matched: Vec<_> = self
+            .options
+            .disabled_metrics
+            .iter()
+            .filter(|entry| disables(entry, name, unit))
+            .cloned()
+            .collect();
+        if !matched.is_empty() {
+            self.disabled_matched.extend(matched);
+            return Tracked::disabled();
+        }
+        let family = self.register(name, help, unit, L::NAMES, Family::default());
+        Tracked::new(family)
+    }
+
+    /// Register a single metric without labels
+    fn metric<M: Metric + TypedMetric + Clone>(
+        &mut self,
+        name: &str,
+        help: &str,
+        unit: &'static str,
+        metric: M,
+    ) -> M {
+        self.register(name, help, unit, &[], metric)
+    }
+
+    fn register<M: Metric + TypedMetric + Clone>(
+        &mut self,
+        name: &str,
+        help: &str,
+        unit: &'static str,
+        labels: &'static [&'static str],
+        metric: M,
+    ) -> M {
+        // Device metrics are written with the default prefix, swapped for the configured one
+        let name = match (&self.options.metric_prefix, name.strip_prefix("shelly_")) {
+            (Some(prefix), Some(rest)) => Cow::Owned(format!("{}_{}", prefix, rest)),
+            _ => Cow::Borrowed(name),
+        };
+        let name = name.as_ref();
+        self.registry.register(name, help, metric.clone());
+
+        // Counters are exposed with a `_total` suffix appended by the encoder
+        let exposed_name = match M::TYPE {
+            MetricType::Counter => format!("{}_total", name),
+            _ => name.to_string(),
+        };
+        self.descriptors.push(MetricDescriptor {
+            name: exposed_name,
+            help: help.to_string(),
+            metric_type: M::TYPE,
+            unit,
+            labels,
+        });
+        metric
+    }
+}
+
+/// Self-monitoring metrics about the exporter's own MQTT client
+pub struct ExporterMetrics {
+    messages_received: Counter,
+    messages_parsed: Counter,
+    messages_ignored: Tracked<ReasonLabels, Counter>,
+    parse_errors: Counter,
+    auth_failures: Counter,
+    watchdog_reconnects: Counter,
+    mqtt_reconnects: Counter,
+    push_failures: Counter,
+    duplicate_messages: Counter,
+    series: Gauge,
+    resident_bytes: Gauge,
+    descriptors: Vec<MetricDescriptor>,
+}
+
+impl ExporterMetrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let options = MetricsOptions::default();
+        let mut registrar = Registrar::new(registry, &options);
+
+        let messages_received = registrar.metric(
+            "mqtt2prom_messages_received",
+            "MQTT messages received",
+            "messages",
+            Counter::default(),
+        );
+
+        let messages_parsed = registrar.metric(
+            "mqtt2prom_messages_parsed",
+            "MQTT messages parsed and applied to the metrics",
+            "messages",
+            Counter::default(),
+        );
+
+        let messages_ignored = registrar.family(
+            "mqtt2prom_messages_ignored",
+            "MQTT messages ignored before parsing, by reason",
+            "messages",
+        );
+
+        let parse_errors = registrar.metric(
+            "mqtt2prom_parse_errors",
+            "MQTT messages that failed to parse",
+            "messages",
+            Counter::default(),
+        );
+
+        let auth_failures = registrar.metric(
+            "mqtt2prom_auth_failures",
+            "MQTT connections the broker refused for bad credentials",
+            "connections",
+            Counter::default(),
+        );
+
+        let watchdog_reconnects = registrar.metric(
+            "mqtt2prom_watchdog_reconnects",
+            "Reconnects forced after --message-watchdog-secs without a message",
+            "reconnects",
+            Counter::default(),
+        );
+
+        let mqtt_reconnects = registrar.metric(
+            "mqtt2prom_mqtt_reconnects",
+            "Reconnects to the MQTT broker after a disconnect or connection error",
+            "reconnects",
+            Counter::default(),
+        );
+
+        let push_failures = registrar.metric(
+            "mqtt2prom_push_failures",
+            "Pushes to --pushgateway-url that failed or were rejected",
+            "pushes",
+            Counter::default(),
+        );
+
+        let duplicate_messages = registrar.metric(
+            "mqtt2prom_duplicate_messages",
+            "MQTT messages repeating one of the last few payloads on their topic, as on broker redelivery",
+            "messages",
+            Counter::default(),
+        );
+
+        let series = registrar.metric(
+            "mqtt2prom_series_total",
+            "Distinct device label sets currently held",
+            "series",
+            Gauge::default(),
+        );
+
+        let resident_bytes = registrar.metric(
+            "mqtt2prom_process_resident_bytes",
+            "Resident memory of the exporter process, where the platform reports it",
+            "bytes",
+            Gauge::default(),
+        );
+
+        Self {
+            messages_received,
+            messages_parsed,
+            messages_ignored,
+            parse_errors,
+            auth_failures,
+            watchdog_reconnects,
+            mqtt_reconnects,
+            push_failures,
+            duplicate_messages,
+            series,
+            resident_bytes,
+            descriptors: registrar.descriptors,
+        }
+    }
+
+    pub fn message_received(&self) {
+        self.messages_received.inc();
+    }
+
+    pub fn message_parsed(&self) {
+        self.messages_parsed.inc();
+    }
+
+    /// Count an ignored message; reasons are `topic_filter`, `notify_event` and `utf8_error`
+    pub fn message_ignored(&self, reason: &str) {
+        let labels = Sited::unsited(ReasonLabels {
+            reason: reason.to_string(),
+        });
+        self.messages_ignored.inc(&labels);
+    }
+
+    pub fn parse_error(&self) {
+        self.parse_errors.inc();
+    }
+
+    pub fn auth_failure(&self) {
+        self.auth_failures.inc();
+    }
+
+    pub fn watchdog_reconnect(&self) {
+        self.watchdog_reconnects.inc();
+    }
+
+    pub fn mqtt_reconnect(&self) {
+        self.mqtt_reconnects.inc();
+    }
+
+    pub fn push_failure(&self) {
+        self.push_failures.inc();
+    }
+
+    pub fn duplicate_message(&self) {
+        self.duplicate_messages.inc();
+    }
+
+    /// Refresh the series count and process memory gauges
+    pub fn observe_process(&self, metrics: &ShellyMetrics) {
+        self.series.set(metrics.series_count() as i64);
+        if let Some(bytes) = resident_bytes() {
+            self.resident_bytes.set(bytes as i64);
+        }
+    }
+
+    pub fn descriptors(&self) -> &[MetricDescriptor] {
+        &self.descriptors
+    }
+}
+
+/// FNV-1a hash of a payload, stable across builds unlike the std hashers
+fn payload_hash(payload: &[u8]) -> String {
+    let hash = payload
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("{:016x}", hash)
+}
+
+/// Pass finite readings through; NaN and infinities are dropped with a warning
+/// so they never reach a gauge
+fn finite(device_id: &str, field: &str, value: Option<f64>) -> Option<f64> {
+    value.filter(|value| {
+        if !value.is_finite() {
+            warn!(
+                "Skipping non-finite {} from {}: {}",
+                field, device_id, value
+            );
+        }
+        value.is_finite()
+    })
+}
+
+/// Discharge curve of the 4xAA pack in H&T devices as (volts, percent), ascending
+const BATTERY_DISCHARGE_CURVE: &[(f64, f64)] = &[
+    (4.0, 0.0),
+    (4.4, 10.0),
+    (4.8, 30.0),
+    (5.2, 60.0),
+    (5.6, 85.0),
+    (6.0, 100.0),
+];
+
+/// Charge percentage expected at `volts`, interpolated along the discharge curve
+fn battery_percent_from_voltage(volts: f64) -> f64 {
+    let curve = BATTERY_DISCHARGE_CURVE;
+    let (first, last) = (curve[0], curve[curve.len() - 1]);
+    if volts <= first.0 {
+        return first.1;
+    }
+    if volts >= last.0 {
+        return last.1;
+    }
+    curve
+        .windows(2)
+        .find(|pair| volts <= pair[1].0)
+        .map(|pair| {
+            let ((v0, p0), (v1, p1)) = (pair[0], pair[1]);
+            p0 + (volts - v0) / (v1 - v0) * (p1 - p0)
+        })
+        .unwrap_or(last.1)
+}
+
+/// Metric families for the devices hashed to one registry
+struct Shard {
+    power: Tracked<DeviceLabels, FloatGauge>,
+    voltage: ScaledGauge<DeviceLabels>,
+    current: ScaledGauge<DeviceLabels>,
+    power_factor: Tracked<DeviceLabels, FloatGauge>,
+    frequency: Tracked<DeviceLabels, FloatGauge>,
+    energy_total: ScaledGauge<DeviceLabels, FloatCounter>,
+    energy_returned: Tracked<DeviceLabels, FloatGauge>,
+    switch_state: Tracked<DeviceLabels, FloatGauge>,
+    temperature: ScaledGauge<SensorLabels>,
+    temperature_fahrenheit: Tracked<SensorLabels, FloatGauge>,
+    humidity: ScaledGauge<SensorLabels>,
+    battery_percent: Tracked<DeviceOnlyLabels, FloatGauge>,
+    battery_voltage: ScaledGauge<DeviceOnlyLabels>,
+    battery_low: Tracked<DeviceOnlyLabels, FloatGauge>,
+    battery_powered: Tracked<DeviceOnlyLabels, FloatGauge>,
+    external_power_present: Tracked<DeviceOnlyLabels, FloatGauge>,
+    smoke_alarm: Tracked<DeviceOnlyLabels, FloatGauge>,
+    flood_alarm: Tracked<DeviceOnlyLabels, FloatGauge>,
+    motion_detected: Tracked<DeviceOnlyLabels, FloatGauge>,
+    illuminance: Tracked<DeviceOnlyLabels, FloatGauge>,
+    battery_low_percent: Option<f64>,
+    battery_mismatch: Tracked<DeviceOnlyLabels, FloatGauge>,
+    battery_mismatch_percent: Option<f64>,
+    wifi_rssi: Tracked<DeviceOnlyLabels, FloatGauge>,
+    wifi_info: Tracked<WifiInfoLabels, FloatGauge>,
+    /// Current SSID labels per device, so roaming replaces the old series
+    wifi_info_labels: Mutex<HashMap<String, WifiInfoLabels>>,
+    uptime: Tracked<DeviceOnlyLabels, FloatGauge>,
+    update_available: Tracked<DeviceOnlyLabels, FloatGauge>,
+    ram_free: Tracked<DeviceOnlyLabels, FloatGauge>,
+    ram_size: Tracked<DeviceOnlyLabels, FloatGauge>,
+    fs_free: Tracked<DeviceOnlyLabels, FloatGauge>,
+    fs_size: Tracked<DeviceOnlyLabels, FloatGauge>,
+    last_seen: Tracked<DeviceOnlyLabels, FloatGauge>,
+    online: Tracked<DeviceOnlyLabels, FloatGauge>,
+    component_last_seen: Tracked<ComponentLabels, FloatGauge>,
+    device_info: Tracked<DeviceInfoLabels, FloatGauge>,
+    /// Current info labels per device, so a firmware change replaces the old series
+    device_info_labels: Mutex<HashMap<String, DeviceInfoLabels>>,
+    payload_info: Tracked<PayloadLabels, FloatGauge>,
+    /// Current payload hash per device, so each device keeps a single series
+    payload_info_labels: Mutex<HashMap<String, PayloadLabels>>,
+    /// Unsupported component keys seen per device, logged once each
+    unknown_components: Mutex<HashMap<String, BTreeSet<String>>>,
+    pm1_power: Tracked<ChannelLabels, FloatGauge>,
+    pm1_voltage: ScaledGauge<ChannelLabels>,
+    pm1_current: ScaledGauge<ChannelLabels>,
+    pm1_frequency: ScaledGauge<ChannelLabels>,
+    pm1_energy_total: ScaledGauge<ChannelLabels>,
+    em_power: Tracked<PhaseLabels, FloatGauge>,
+    em_voltage: Tracked<PhaseLabels, FloatGauge>,
+    em_current: Tracked<PhaseLabels, FloatGauge>,
+    em_total_power: Tracked<DeviceOnlyLabels, FloatGauge>,
+    virtual_number: Tracked<VirtualLabels, FloatGauge>,
+    virtual_boolean: Tracked<VirtualLabels, FloatGauge>,
+    input_percent: Tracked<InputLabels, FloatGauge>,
+    input_value: Tracked<InputLabels, FloatGauge>,
+    light_color_temp: Tracked<ChannelLabels, FloatGauge>,
+    light_color_intensity: Tracked<LightColorLabels, FloatGauge>,
+    light_on: Tracked<LightLabels, FloatGauge>,
+    light_brightness: Tracked<LightLabels, FloatGauge>,
+    cover_position: Tracked<ChannelLabels, FloatGauge>,
+    cover_power: Tracked<ChannelLabels, FloatGauge>,
+    cover_state: Tracked<CoverStateLabels, FloatGauge>,
+    /// Current state labels per cover, so a state change replaces the old series
+    cover_state_labels: Mutex<HashMap<ChannelLabels, CoverStateLabels>>,
+    input_calibration: HashMap<u16, InputCalibration>,
+    energy_out_of_order: Tracked<DeviceLabels, Counter>,
+    events: Tracked<EventLabels, Counter>,
+    oversized_messages: Tracked<DeviceOnlyLabels, Counter>,
+    dropped_channels: Tracked<DeviceOnlyLabels, Counter>,
+    energy_stalled: Tracked<DeviceLabels, FloatGauge>,
+    overtemp: Tracked<DeviceLabels, FloatGauge>,
+    /// Last `aenergy.minute_ts` seen per switch, for out-of-order detection
+    last_minute_ts: Mutex<HashMap<DeviceLabels, i64>>,
+    computed_power: Tracked<DeviceLabels, FloatGauge>,
+    /// Latest `(minute_ts, total)` per switch, for the computed power
+    energy_samples: Mutex<HashMap<DeviceLabels, (i64, f64)>>,
+    emit_computed_power: bool,
+    /// Energy total progress per switch, for stall detection
+    energy_progress: Mutex<HashMap<DeviceLabels, EnergyProgress>>,
+    energy_stall_updates: Option<u32>,
+    /// Devices admitted to this shard and when each last reported, kept apart from the
+    /// families so `--disabled-metrics` cannot hide devices from `--max-devices`
+    devices: Mutex<HashMap<String, Instant>>,
+    /// Set with `--site-from-topic-segment`
+    sites: Option<SiteMap>,
+}
+
+/// Latest energy total and power of a switch, and how many energy updates
+/// under load did not advance the total
+#[derive(Debug, Default)]
+struct EnergyProgress {
+    total: f64,
+    power: f64,
+    unchanged: u32,
+}
+
+pub struct ShellyMetrics {
+    /// Devices are spread across shards by a hash of their id, one registry each
+    shards: Vec<Shard>,
+    options: MetricsOptions,
+    /// `device_aliases` keyed by `src` or MAC
+    aliases: HashMap<String, String>,
+    /// Site per device, with `site_from_topic_segment`
+    sites: Option<SiteMap>,
+    /// Messages from new devices dropped past `max_devices`
+    devices_dropped: Counter,
+    /// When the device cap was last warned about
+    devices_dropped_warned: Mutex<Option<Instant>>,
+    /// Set once the first message has been applied, for readiness
+    data_received: Arc<AtomicBool>,
+    descriptors: Vec<MetricDescriptor>,
+}
+
+/// Minimum time between warnings about devices dropped past `max_devices`
+const DEVICES_DROPPED_WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+impl ShellyMetrics {
+    #[allow(dead_code)]
+    pub fn new(registry: &mut Registry) -> Self {
+        Self::with_options(registry, &MetricsOptions::default())
+    }
+
+    pub fn with_options(registry: &mut Registry, options: &MetricsOptions) -> Self {
+        Self::sharded(&mut [registry], options)
+    }
+
+    /// Register the same metric families in every registry and spread devices across them
+    pub fn sharded(registries: &mut [&mut Registry], options: &MetricsOptions) -> Self {
+        assert!(!registries.is_empty(), "at least one registry is required");
+
+        let sites = options.site_from_topic_segment.then(SiteMap::default);
+        let mut descriptors = Vec::new();
+        let mut disabled_matched = BTreeSet::new();
+        let shards = registries
+            .iter_mut()
+            .map(|registry| {
+                let registry = match &options.broker_label {
+                    Some(broker) => registry.sub_registry_with_label((
+                        Cow::Borrowed("broker"),
+                        Cow::Owned(broker.clone()),
+                    )),
+                    None => registry,
+                };
+                let mut registrar = Registrar::new(registry, options).with_sites(sites.clone());
+                let shard = Shard::register(&mut registrar);
+                descriptors = registrar.descriptors;
+                disabled_matched = registrar.disabled_matched;
+                shard
+            })
+            .collect();
+        for entry in &options.disabled_metrics {
+            if !disabled_matched.contains(entry) {
+                warn!("No metric family matches {:?} in DISABLED_METRICS", entry);
+            }
+        }
+
+        // Not per device, so only registered once next to the exporter metrics
+        let devices_dropped = Counter::default();
+        if options.max_devices.is_some() {
+            let mut registrar = Registrar::new(registries[0], options);
+            registrar.metric(
+                "mqtt2prom_devices_dropped",
+                "Messages from new devices dropped past --max-devices",
+                "messages",
+                devices_dropped.clone(),
+            );
+            descriptors.extend(registrar.descriptors);
+        }
+
+        Self {
+            shards,
+            options: options.clone(),
+            aliases: options
+                .device_aliases
+                .iter()
+                .map(|alias| (alias.mac.clone(), alias.name.clone()))
+                .collect(),
+            sites,
+            devices_dropped,
+            devices_dropped_warned: Mutex::new(None),
+            data_received: Arc::default(),
+            descriptors,
+        }
+    }
+
+    /// Descriptors of every metric family this exporter can emit
+    pub fn descriptors(&self) -> &[MetricDescriptor] {
+        &self.descriptors
+    }
+
+    /// Flag set once the first message has been applied
+    pub fn data_received(&self) -> Arc<AtomicBool> {
+        self.data_received.clone()
+    }
+
+    /// The shard holding all series of a device
+    fn shard(&self, device_id: &str) -> &Shard {
+        let mut hasher = DefaultHasher::new();
+        device_id.hash(&mut hasher);
+        &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+    }
+
+    /// Device name from the configured topic segment
+    fn device_from_topic(&self, topic: &str) -> Option<String> {
+        let index = self
+            .options
+            .device_name_topic_index
+            .unwrap_or(DEFAULT_DEVICE_NAME_TOPIC_INDEX);
+        extract_device_from_topic_at(topic, index)
+    }
+
+    /// Device label of a message from the first `name_precedence` source that has one,
+    /// falling back to the MAC
+    fn device_id(&self, msg: &ShellyMessage, topic: Option<&str>) -> String {
+        let precedence = match self.options.name_precedence.as_slice() {
+            [] => &DEFAULT_NAME_PRECEDENCE[..],
+            precedence => precedence,
+        };
+        // Only extracted when a source needs it, the topic usually decides first
+        let mac = std::cell::OnceCell::new();
+        let mac = || mac.get_or_init(|| extract_device_id(&msg.src)).clone();
+        precedence
+            .iter()
+            .find_map(|source| match source {
+                NameSource::Alias if self.aliases.is_empty() => None,
+                NameSource::Alias => self
+                    .aliases
+                    .get(&msg.src)
+                    .or_else(|| self.aliases.get(&mac()))
+                    .cloned(),
+                NameSource::Topic => topic.and_then(|topic| self.device_from_topic(topic)),
+                NameSource::Mac => Some(mac()),
+            })
+            .unwrap_or_else(mac)
+    }
+
+    /// Record a hash of the raw payload behind `msg`, with `debug_payload_label`
+    pub fn record_payload(&self, msg: &ShellyMessage, topic: Option<&str>, payload: &[u8]) {
+        if !self.options.debug_payload_label {
+            return;
+        }
+        let device_id = self.device_id(msg, topic);
+        let shard = self.shard(&device_id);
+        // Devices dropped by `max_devices` hold no series to prune
+        if shard.touch_device(&device_id) {
+            shard.update_payload_info(&device_id, payload_hash(payload));
+        }
+    }
+
+    /// Apply a live message, received now; the first one admitted makes `/readyz` ready
+    pub fn update_from_message(&self, msg: &ShellyMessage, topic: Option<&str>) {
+        let admitted = self.update_from_message_at(msg, topic, SystemTime::now());
+        if admitted && !self.data_received.load(Ordering::Relaxed) {
+            self.data_received.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Remember the first topic segment as the device's site, with `site_from_topic_segment`
+    fn record_site(&self, device_id: &str, topic: Option<&str>) {
+        let Some(sites) = &self.sites else { return };
+        let Some(site) = topic.and_then(|topic| extract_device_from_topic_at(topic, 0)) else {
+            return;
+        };
+        let mut sites = sites.write().unwrap_or_else(PoisonError::into_inner);
+        if sites.get(device_id) != Some(&site) {
+            sites.insert(device_id.to_string(), site);
+        }
+    }
+
+    /// Whether a device may create series: known devices always can, new ones
+    /// only while fewer than `max_devices` have series
+    fn admit(&self, device_id: &str) -> bool {
+        let shard = self.shard(device_id);
+        if shard.touch_device(device_id) {
+            return true;
+        }
+        let devices: usize = self.shards.iter().map(Shard::device_count).sum();
+        if self.options.max_devices.is_none_or(|max| devices < max) {
+            shard.add_device(device_id);
+            return true;
+        }
+
+        self.devices_dropped.inc();
+        let mut warned = self
+            .devices_dropped_warned
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if warned.is_none_or(|at| at.elapsed() >= DEVICES_DROPPED_WARN_INTERVAL) {
+            *warned = Some(Instant::now());
+            warn!(
+                "Dropping messages from {}: {} devices reached --max-devices",
+                device_id, devices
+            );
+        }
+        false
+    }
+
+    /// Record the retained `<topic>/online` state for the topic's device
+    pub fn update_online(&self, topic: &str, online: bool) {
+        let Some(device_id) = self.device_from_topic(topic) else {
+            return;
+        };
+        if !self.admit(&device_id) {
+            return;
+        }
+        self.record_site(&device_id, Some(topic));
+        let shard = self.shard(&device_id);
+        let labels = shard.sited(DeviceOnlyLabels { device: device_id });
+        shard.online.set(&labels, if online { 1.0 } else { 0.0 });
+    }
+
+    /// Apply a message received at `now`, the wall-clock time recorded as last seen,
+    /// returning whether its device was admitted; restored state is replayed through
+    /// here, so it does not count as live data
+    pub fn update_from_message_at(
+        &self,
+        msg: &ShellyMessage,
+        topic: Option<&str>,
+        now: SystemTime,
+    ) -> bool {
+        let device_id = self.device_id(msg, topic);
+        if !self.admit(&device_id) {
+            return false;
+        }
+        self.record_site(&device_id, topic);
+
+        let device_type = if self.options.infer_device_type {
+            device_type_from_src(&msg.src)
+        } else {
+            DeviceType::Unknown
+        };
+        let shard = self.shard(&device_id);
+        // Shared by every per-device family below
+        let device_labels = shard.sited(DeviceOnlyLabels {
+            device: device_id.clone(),
+        });
+
+        let seen = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        shard.last_seen.set(&device_labels, seen.as_secs_f64());
+
+        shard.log_unknown_components(&device_id, &msg.params);
+
+        // Events carry no readings, only counts
+        if msg.method == MessageMethod::NotifyEvent {
+            shard.count_events(&device_id, &msg.params.events);
+            return true;
+        }
+
+        // Cap oversized messages so one malformed payload cannot create unbounded series
+        let capped;
+        let params = match self.options.max_components_per_message {
+            Some(max) if msg.params.component_count() > max => {
+                shard.oversized_messages.inc(&device_labels);
+                let mut truncated = msg.params.clone();
+                truncated.truncate_components(max);
+                capped = truncated;
+                &capped
+            }
+            _ => &msg.params,
+        };
+
+        if self.options.per_component_freshness {
+            for component in params.component_keys() {
+                let labels = shard.sited(ComponentLabels {
+                    device: device_id.clone(),
+                    component,
+                });
+                shard.component_last_seen.set(&labels, seen.as_secs_f64());
+            }
+        }
+
+        if device_type.has_switches() {
+            let max_channels = self.options.max_channels_per_device.unwrap_or(usize::MAX);
+            let dropped = params.switch.len().saturating_sub(max_channels);
+            if dropped > 0 {
+                shard
+                    .dropped_channels
+                    .inc_by(&device_labels, dropped as u64);
+            }
+            let full_status = msg.method == MessageMethod::NotifyFullStatus;
+            for (channel, switch) in params.switch.iter().take(max_channels) {
+                shard.update_switch(&device_id, channel, switch, full_status);
+            }
+        }
+
+        if device_type.has_power_meters() {
+            for (channel, pm1) in params.pm1.iter() {
+                shard.update_pm1(&device_id, channel, pm1);
+            }
+            if let Some(em) = &params.em {
+                shard.update_em(&device_labels, em);
+            }
+        }
+
+        shard.update_sensors(&device_labels, params);
+
+        shard.update_virtual(&device_id, params);
+        shard.update_alarms(&device_labels, params);
+
+        // Update illuminance from light sensors (illuminance:0)
+        let lux = params
+            .illuminance
+            .as_ref()
+            .and_then(|illuminance| finite(&device_id, "illuminance.lux", illuminance.lux));
+        if let Some(lux) = lux {
+            shard.illuminance.set(&device_labels, lux);
+        }
+        shard.update_inputs(&device_id, params);
+        shard.update_lights(&device_id, params);
+        shard.update_dimmers(&device_id, params);
+        shard.update_covers(&device_id, params);
+
+        // Update WiFi RSSI if present
+        if let Some(rssi) = params.wifi.as_ref().and_then(|wifi| wifi.rssi) {
+            shard.wifi_rssi.set(&device_labels, rssi as f64);
+        }
+        if let Some(ssid) = params.wifi.as_ref().and_then(|wifi| wifi.ssid.as_ref()) {
+            shard.update_wifi_info(&device_id, ssid);
+        }
+
+        if msg.method == MessageMethod::NotifyFullStatus {
+            let device = params.sys.as_ref().and_then(|sys| sys.device.as_ref());
+            shard.update_device_info(&device_id, device);
+        }
+
+        // Partial updates without devicepower say nothing about the power source
+        let battery_powered = match &params.devicepower {
+            Some(devicepower) => Some(
+                devicepower.battery.is_some()
+                    && !devicepower
+                        .external
+                        .as_ref()
+                        .is_some_and(|external| external.present),
+            ),
+            None if msg.method == MessageMethod::NotifyFullStatus => Some(false),
+            None => None,
+        };
+        if let Some(battery_powered) = battery_powered {
+            shard
+                .battery_powered
+                .set(&device_labels, if battery_powered { 1.0 } else { 0.0 });
+        }
+
+        // Partial sys updates only carry the fields that changed
+        let update_available = match &params.sys {
+            Some(sys) => sys
+                .update_available()
+                .or((msg.method == MessageMethod::NotifyFullStatus).then_some(false)),
+            None => None,
+        };
+        if let Some(update_available) = update_available {
+            shard
+                .update_available
+                .set(&device_labels, if update_available { 1.0 } else { 0.0 });
+        }
+
+        if let Some(sys) = &params.sys {
+            let memory = [
+                (&shard.ram_free, sys.ram_free),
+                (&shard.ram_size, sys.ram_size),
+                (&shard.fs_free, sys.fs_free),
+                (&shard.fs_size, sys.fs_size),
+            ];
+            for (gauge, bytes) in memory {
+                if let Some(bytes) = bytes {
+                    gauge.set(&device_labels, bytes as f64);
+                }
+            }
+        }
+
+        // Update uptime if present, it resets to zero on reboot
+        if let Some(uptime) = params.sys.as_ref().and_then(|sys| sys.uptime) {
+            shard.uptime.set(&device_labels, uptime as f64);
+        }
+        true
+    }
+
+    /// Number of device series currently held across all shards
+    pub fn series_count(&self) -> usize {
+        self.shards.iter().map(Shard::series_count).sum()
+    }
+
+    /// Remove the series of devices that have not reported within `max_age`
+    pub fn prune(&self, max_age: Duration) -> usize {
+        self.prune_at(Instant::now(), max_age)
+    }
+
+    fn prune_at(&self, now: Instant, max_age: Duration) -> usize {
+        let mut removed = 0;
+        for shard in &self.shards {
+            let (count, forgotten) = shard.prune_at(now, max_age);
+            removed += count;
+            if let (Some(sites), false) = (&self.sites, forgotten.is_empty()) {
+                let mut sites = sites.write().unwrap_or_else(PoisonError::into_inner);
+                for device in &forgotten {
+                    sites.remove(device);
+                }
+            }
+        }
+        removed
+    }
+
+    #[allow(dead_code)]
+    pub fn update_power(&self, device: &str, switch: &str, watts: f64) {
+        let shard = self.shard(device);
+        let labels = shard.sited(DeviceLabels {
+            device: device.to_string(),
+            switch: switch.to_string(),
+        });
+        shard.power.set(&labels, watts);
+    }
+
+    #[allow(dead_code)]
+    pub fn update_voltage(&self, device: &str, switch: &str, volts: f64) {
+        let shard = self.shard(device);
+        let labels = shard.sited(DeviceLabels {
+            device: device.to_string(),
+            switch: switch.to_string(),
+        });
+        shard.voltage.set(&labels, volts);
+    }
+
+    #[allow(dead_code)]
+    pub fn update_current(&self, device: &str, switch: &str, amps: f64) {
+        let shard = self.shard(device);
+        let labels = shard.sited(DeviceLabels {
+            device: device.to_string(),
+            switch: switch.to_string(),
+        });
+        shard.current.set(&labels, amps);
+    }
+
+    #[allow(dead_code)]
+    pub fn update_energy(&self, device: &str, switch: &str, wh: f64) {
+        let shard = self.shard(device);
+        let labels = shard.sited(DeviceLabels {
+            device: device.to_string(),
+            switch: switch.to_string(),
+        });
+        shard.energy_total.set(&labels, wh);
+    }
+}
+
+impl Shard {
+    fn register(registrar: &mut Registrar<'_>) -> Self {
+        let power = registrar.family(
+            "shelly_switch_power_watts",
+            "Current power consumption in watts",
+            "watts",
+        );
+
+        let voltage = registrar.scaled(
+            "shelly_switch_voltage_volts",
+            "Line voltage in volts",
+            "volts",
+            VOLTAGE_SCALE,
+        );
+
+        let current = registrar.scaled(
+            "shelly_switch_current_amps",
+            "Current draw in amps",
+            "amps",
+            CURRENT_SCALE,
+        );
+
+        let energy_total = registrar.scaled(
+            "shelly_switch_energy_total_wh",
+            "Total energy consumed in watt-hours",
+            "wh",
+            ENERGY_SCALE,
+        );
+
+        let power_factor = registrar.family(
+            "shelly_switch_power_factor",
+            "Power factor reported by PM switches",
+            "ratio",
+        );
+
+        let frequency = registrar.family(
+            "shelly_switch_frequency_hz",
+            "Line frequency reported by PM switches in hertz",
+            "hertz",
+        );
+
+        let energy_returned = registrar.family(
+            "shelly_switch_energy_returned_wh",
+            "Total energy returned to the grid in watt-hours",
+            "wh",
+        );
+
+        let computed_power = registrar.family(
+            "shelly_switch_computed_power_watts",
+            "Average power derived from consecutive energy totals, to cross-check apower",
+            "watts",
+        );
+
+        let switch_state = registrar.family(
+            "shelly_switch_state",
+            "Switch output state (0=off, 1=on)",
+            "bool",
+        );
+
+        let temperature_unit = registrar.options.temperature_unit;
+        let temperature = if temperature_unit.celsius() {
+            registrar.scaled(
+                "shelly_temperature_celsius",
+                "Device temperature in celsius",
+                "celsius",
+                TEMPERATURE_SCALE,
+            )
+        } else {
+            ScaledGauge::disabled()
+        };
+
+        let temperature_fahrenheit = if temperature_unit.fahrenheit() {
+            registrar.family(
+                "shelly_temperature_fahrenheit",
+                "Device temperature in fahrenheit",
+                "fahrenheit",
+            )
+        } else {
+            Tracked::disabled()
+        };
+
+        let humidity = registrar.scaled(
+            "shelly_humidity_percent",
+            "Relative humidity percentage",
+            "percent",
+            HUMIDITY_SCALE,
+        );
+
+        let battery_percent = registrar.family(
+            "shelly_battery_percent",
+            "Battery charge percentage",
+            "percent",
+        );
+
+        let battery_voltage = registrar.scaled(
+            "shelly_battery_voltage",
+            "Battery voltage in volts",
+            "volts",
+            BATTERY_VOLTAGE_SCALE,
+        );
+
+        let battery_low = registrar.family(
+            "shelly_battery_low",
+            "Battery below --battery-low-percent (0=ok, 1=low)",
+            "bool",
+        );
+
+        let battery_powered = registrar.family(
+            "shelly_device_battery_powered",
+            "Device running on its battery without external power (0=mains, 1=battery)",
+            "bool",
+        );
+
+        let external_power_present = registrar.family(
+            "shelly_external_power_present",
+            "External power reported by devicepower (0=absent, 1=present)",
+            "bool",
+        );
+
+        let smoke_alarm = registrar.family(
+            "shelly_smoke_alarm",
+            "Smoke sensor alarm (0=clear, 1=alarm)",
+            "bool",
+        );
+
+        let flood_alarm = registrar.family(
+            "shelly_flood_alarm",
+            "Flood sensor alarm (0=dry, 1=alarm)",
+            "bool",
+        );
+
+        let motion_detected = registrar.family(
+            "shelly_motion_detected",
+            "Motion or vibration detected (0=idle, 1=detected)",
+            "bool",
+        );
+
+        let illuminance = registrar.family("shelly_illuminance_lux", "Illuminance in lux", "lux");
+
+        let battery_mismatch = registrar.family(
+            "shelly_battery_voltage_percent_mismatch",
+            "Battery percent disagrees with the percent expected from its voltage (0=ok, 1=mismatch)",
+            "bool",
+        );
+
+        let wifi_rssi =
+            registrar.family("shelly_wifi_rssi_dbm", "WiFi signal strength in dBm", "dbm");
+
+        let wifi_info = registrar.family(
+            "shelly_wifi_info",
+            "WiFi network the device is attached to (always 1)",
+            "info",
+        );
+
+        let update_available = registrar.family(
+            "shelly_update_available",
+            "Firmware update offered on any channel (0=up to date, 1=available)",
+            "bool",
+        );
+
+        let ram_free = registrar.family("shelly_ram_free_bytes", "Free RAM in bytes", "bytes");
+        let ram_size = registrar.family("shelly_ram_size_bytes", "Total RAM in bytes", "bytes");
+        let fs_free = registrar.family(
+            "shelly_fs_free_bytes",
+            "Free filesystem space in bytes",
+            "bytes",
+        );
+        let fs_size = registrar.family(
+            "shelly_fs_size_bytes",
+            "Total filesystem space in bytes",
+            "bytes",
+        );
+
+        let uptime = registrar.family(
+            "shelly_uptime_seconds",
+            "Seconds since the device booted; a drop indicates a reboot",
+            "seconds",
+        );
+
+        let last_seen = registrar.family(
+            "shelly_last_seen_timestamp_seconds",
+            "Unix time of the last message processed for the device",
+            "seconds",
+        );
+
+        let component_last_seen = registrar.family(
+            "shelly_component_last_seen_timestamp_seconds",
+            "Unix time the component was last reported, with --per-component-freshness",
+            "seconds",
+        );
+
+        let online = registrar.family(
+            "shelly_online",
+            "Broker connection from the retained online topic (1=online, 0=offline)",
+            "bool",
+        );
+
+        let device_info = registrar.family(
+            "shelly_device_info",
+            "Device model, generation and firmware (always 1)",
+            "info",
+        );
+
+        let pm1_power = registrar.family(
+            "shelly_pm1_power_watts",
+            "Power meter active power in watts",
+            "watts",
+        );
+
+        let pm1_voltage = registrar.scaled(
+            "shelly_pm1_voltage_volts",
+            "Power meter voltage in volts",
+            "volts",
+            VOLTAGE_SCALE,
+        );
+
+        let pm1_current = registrar.scaled(
+            "shelly_pm1_current_amps",
+            "Power meter current in amps",
+            "amps",
+            CURRENT_SCALE,
+        );
+
+        let pm1_frequency = registrar.scaled(
+            "shelly_pm1_frequency_hz",
+            "Power meter line frequency in hertz",
+            "hertz",
+            FREQUENCY_SCALE,
+        );
+
+        let pm1_energy_total = registrar.scaled(
+            "shelly_pm1_energy_total_wh",
+            "Power meter total energy in watt-hours",
+            "wh",
+            ENERGY_SCALE,
+        );
+
+        let em_power = registrar.family(
+            "shelly_em_power_watts",
+            "Energy meter active power per phase in watts",
+            "watts",
+        );
+
+        let em_voltage = registrar.family(
+            "shelly_em_voltage_volts",
+            "Energy meter voltage per phase in volts",
+            "volts",
+        );
+
+        let em_current = registrar.family(
+            "shelly_em_current_amps",
+            "Energy meter current per phase in amps",
+            "amps",
+        );
+
+        let em_total_power = registrar.family(
+            "shelly_em_total_power_watts",
+            "Energy meter active power across all phases in watts",
+            "watts",
+        );
+
+        let virtual_number = registrar.family(
+            "shelly_virtual_number",
+            "Value of a script-controlled virtual number component",
+            "value",
+        );
+
+        let virtual_boolean = registrar.family(
+            "shelly_virtual_boolean",
+            "Value of a script-controlled virtual boolean component (0=false, 1=true)",
+            "bool",
+        );
+
+        let input_percent = registrar.family(
+            "shelly_input_percent",
+            "Analog input reading in percent of its range",
+            "percent",
+        );
+
+        let input_value = registrar.family(
+            "shelly_input_value",
+            "Analog input reading mapped by --input-calibration",
+            "calibrated",
+        );
+
+        let light_color_temp = registrar.family(
+            "shelly_light_color_temp_kelvin",
+            "Color temperature of a tunable white light (cct:N)",
+            "kelvin",
+        );
+
+        let light_color_intensity = registrar.family(
+            "shelly_light_color_intensity",
+            "Color channel intensity (0-255) of an rgb:N or rgbw:N light",
+            "intensity",
+        );
+
+        let light_on = registrar.family(
+            "shelly_light_on",
+            "Light output state (0=off, 1=on)",
+            "bool",
+        );
+
+        let light_brightness = registrar.family(
+            "shelly_light_brightness_percent",
+            "Light brightness from 0 to 100",
+            "percent",
+        );
+
+        let cover_position = registrar.family(
+            "shelly_cover_position_percent",
+            "Cover position from 0 (closed) to 100 (open)",
+            "percent",
+        );
+
+        let cover_power = registrar.family(
+            "shelly_cover_power_watts",
+            "Active power of the cover motor in watts",
+            "watts",
+        );
+
+        let cover_state = registrar.family(
+            "shelly_cover_state",
+            "Current cover state such as open, closed, opening, closing or stopped (always 1)",
+            "info",
+        );
+
+        let payload_info = registrar.family(
+            "mqtt2prom_last_payload_info",
+            "FNV-1a hash of the last raw payload applied for the device (always 1)",
+            "info",
+        );
+
+        let energy_out_of_order = registrar.family(
+            "mqtt2prom_energy_out_of_order",
+            "Energy updates whose minute_ts repeated or went backward",
+            "messages",
+        );
+
+        let events = registrar.family(
+            "shelly_events",
+            "NotifyEvent events by name, counted with --process-events",
+            "events",
+        );
+
+        let oversized_messages = registrar.family(
+            "mqtt2prom_oversized_component_messages",
+            "Messages with more components than --max-components-per-message",
+            "messages",
+        );
+
+        let dropped_channels = registrar.family(
+            "mqtt2prom_switch_channels_dropped",
+            "Switch channels skipped past --max-channels-per-device",
+            "channels",
+        );
+
+        let energy_stalled = registrar.family(
+            "shelly_switch_energy_stalled",
+            "Energy total not advancing despite nonzero power (0=ok, 1=stalled)",
+            "bool",
+        );
+
+        let overtemp = registrar.family(
+            "shelly_switch_overtemp",
+            "Switch reporting the overtemp error, throttled or shut off (0=ok, 1=overheated)",
+            "bool",
+        );
+
+        Self {
+            power,
+            voltage,
+            current,
+            power_factor,
+            frequency,
+            energy_total,
+            energy_returned,
+            switch_state,
+            temperature,
+            temperature_fahrenheit,
+            humidity,
+            battery_percent,
+            battery_voltage,
+            battery_low,
+            battery_powered,
+            external_power_present,
+            smoke_alarm,
+            flood_alarm,
+            motion_detected,
+            illuminance,
+            battery_low_percent: registrar.options.battery_low_percent,
+            battery_mismatch,
+            battery_mismatch_percent: registrar.options.battery_mismatch_percent,
+            wifi_rssi,
+            wifi_info,
+            wifi_info_labels: Mutex::new(HashMap::new()),
+            uptime,
+            update_available,
+            ram_free,
+            ram_size,
+            fs_free,
+            fs_size,
+            last_seen,
+            component_last_seen,
+            online,
+            device_info,
+            device_info_labels: Mutex::new(HashMap::new()),
+            payload_info,
+            payload_info_labels: Mutex::new(HashMap::new()),
+            unknown_components: Mutex::new(HashMap::new()),
+            pm1_power,
+            pm1_voltage,
+            pm1_current,
+            pm1_frequency,
+            pm1_energy_total,
+            em_power,
+            em_voltage,
+            em_current,
+            em_total_power,
+            virtual_number,
+            virtual_boolean,
+            input_percent,
+            input_value,
+            light_color_temp,
+            light_color_intensity,
+            light_on,
+            light_brightness,
+            cover_position,
+            cover_power,
+            cover_state,
+            cover_state_labels: Mutex::new(HashMap::new()),
+            input_calibration: registrar
+                .options
+                .input_calibration
+                .iter()
+                .map(|calibration| (calibration.id, *calibration))
+                .collect(),
+            energy_out_of_order,
+            events,
+            oversized_messages,
+            dropped_channels,
+            energy_stalled,
+            overtemp,
+            last_minute_ts: Mutex::new(HashMap::new()),
+            computed_power,
+            energy_samples: Mutex::new(HashMap::new()),
+            emit_computed_power: registrar.options.computed_power,
+            energy_progress: Mutex::new(HashMap::new()),
+            energy_stall_updates: registrar.options.energy_stall_updates,
+            devices: Mutex::new(HashMap::new()),
+            sites: registrar.sites.clone(),
+        }
+    }
+
+    /// Labels with their device's site, to be shared by every family they update
+    fn sited<L: LabelNames>(&self, labels: L) -> Sited<L> {
+        sited(self.sites.as_ref(), labels)
+    }
+
+    /// Mark a known device as reporting now, returning false for a new one
+    fn touch_device(&self, device_id: &str) -> bool {
+        let mut devices = self.devices.lock().unwrap_or_else(PoisonError::into_inner);
+        match devices.get_mut(device_id) {
+            Some(last) => {
+                *last = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn add_device(&self, device_id: &str) {
+        self.devices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(device_id.to_string(), Instant::now());
+    }
+
+    fn device_count(&self) -> usize {
+        self.devices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    /// Every family holding per-device series
+    fn tracked(&self) -> Vec<&dyn TrackedSeries> {
+        vec![
+            &self.power,
+            &self.voltage,
+            &self.current,
+            &self.power_factor,
+            &self.frequency,
+            &self.energy_total,
+            &self.energy_returned,
+            &self.computed_power,
+            &self.switch_state,
+            &self.temperature,
+            &self.temperature_fahrenheit,
+            &self.humidity,
+            &self.battery_percent,
+            &self.battery_voltage,
+            &self.battery_low,
+            &self.battery_powered,
+            &self.external_power_present,
+            &self.smoke_alarm,
+            &self.flood_alarm,
+            &self.motion_detected,
+            &self.illuminance,
+            &self.battery_mismatch,
+            &self.wifi_rssi,
+            &self.wifi_info,
+            &self.uptime,
+            &self.update_available,
+            &self.ram_free,
+            &self.ram_size,
+            &self.fs_free,
+            &self.fs_size,
+            &self.last_seen,
+            &self.component_last_seen,
+            &self.online,
+            &self.device_info,
+            &self.payload_info,
+            &self.pm1_power,
+            &self.pm1_voltage,
+            &self.pm1_current,
+            &self.pm1_frequency,
+            &self.pm1_energy_total,
+            &self.em_power,
+            &self.em_voltage,
+            &self.em_current,
+            &self.em_total_power,
+            &self.virtual_number,
+            &self.virtual_boolean,
+            &self.input_percent,
+            &self.input_value,
+            &self.light_color_temp,
+            &self.light_color_intensity,
+            &self.light_on,
+            &self.light_brightness,
+            &self.cover_position,
+            &self.cover_power,
+            &self.cover_state,
+            &self.energy_out_of_order,
+            &self.events,
+            &self.oversized_messages,
+            &self.dropped_channels,
+            &self.energy_stalled,
+            &self.overtemp,
+        ]
+    }
+
+    fn series_count(&self) -> usize {
+        self.tracked().iter().map(|family| family.len()).sum()
+    }
+
+    /// Forget devices that have not reported within `max_age` of `now`, removing all
+    /// of their series together so they no longer count against `--max-devices`;
+    /// returns the number of series removed and the forgotten devices
+    fn prune_at(&self, now: Instant, max_age: Duration) -> (usize, Vec<String>) {
+        let mut forgotten = Vec::new();
+        self.devices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|device, last| {
+                let fresh = now.saturating_duration_since(*last) <= max_age;
+                if !fresh {
+                    forgotten.push(device.clone());
+                }
+                fresh
+            });
+        if forgotten.is_empty() {
+            return (0, forgotten);
+        }
+        for device in &forgotten {
+            self.forget_device(device);
+        }
+        let devices: HashSet<&str> = forgotten.iter().map(String::as_str).collect();
+        let removed = self
+            .tracked()
+            .iter()
+            .map(|family| family.remove_devices(&devices))
+            .sum();
+        (removed, forgotten)
+    }
+
+    /// Drop the per-device state kept next to the families
+    fn forget_device(&self, device: &str) {
+        fn lock<T>(map: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+            map.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+        lock(&self.wifi_info_labels).remove(device);
+        lock(&self.device_info_labels).remove(device);
+        lock(&self.payload_info_labels).remove(device);
+        lock(&self.unknown_components).remove(device);
+        lock(&self.cover_state_labels).retain(|labels, _| labels.device != device);
+        lock(&self.last_minute_ts).retain(|labels, _| labels.device != device);
+        lock(&self.energy_samples).retain(|labels, _| labels.device != device);
+        lock(&self.energy_progress).retain(|labels, _| labels.device != device);
+    }
+
+    /// Update switch channel (switch:N), labelled with the channel from the key
+    fn update_switch(&self, device_id: &str, channel: u16, switch: &SwitchData, full_status: bool) {
+        let labels = self.sited(DeviceLabels {
+            device: device_id.to_string(),
+            switch: channel.to_string(),
+        });
+
+        // Update power if present
+        if let Some(apower) = finite(device_id, "apower", switch.apower) {
+            self.power.set(&labels, apower);
+        }
+
+        // Update voltage if present
+        if let Some(voltage) = finite(device_id, "voltage", switch.voltage) {
+            self.voltage.set(&labels, voltage);
+        }
+
+        // Update current if present
+        if let Some(current) = finite(device_id, "current", switch.current) {
+            self.current.set(&labels, current);
+        }
+
+        if let Some(pf) = finite(device_id, "pf", switch.pf) {
+            self.power_factor.set(&labels, pf);
+        }
+
+        if let Some(freq) = finite(device_id, "freq", switch.freq) {
+            self.frequency.set(&labels, freq);
+        }
+
+        // Buffered messages replayed after a reconnect would fake a counter reset,
+        // so readings older than the last `minute_ts` are skipped
+        let mut stale = false;
+        let minute_ts = switch
+            .aenergy
+            .as_ref()
+            .and_then(|aenergy| aenergy.minute_ts);
+        if let Some(minute_ts) = minute_ts {
+            if let Some(last) = self.record_minute_ts(&labels, minute_ts) {
+                if minute_ts <= last {
+                    self.energy_out_of_order.inc(&labels);
+                }
+                stale = minute_ts < last;
+            }
+        }
+
+        // Update energy total if present
+        if let Some(aenergy) = switch.aenergy.as_ref().filter(|_| !stale) {
+            let total = finite(device_id, "aenergy.total", Some(aenergy.total));
+            if let Some(total) = total {
+                self.energy_total.set(&labels, total);
+            }
+            if let (true, Some(minute_ts), Some(total)) =
+                (self.emit_computed_power, aenergy.minute_ts, total)
+            {
+                if let Some(watts) = self.record_energy_sample(&labels, minute_ts, total) {
+                    self.computed_power.set(&labels, watts);
+                }
+            }
+        }
+
+        // Update returned energy, only reported by bidirectional meters
+        let returned = switch
+            .ret_aenergy
+            .as_ref()
+            .filter(|_| !stale)
+            .map(|ret| ret.total);
+        if let Some(returned) = finite(device_id, "ret_aenergy.total", returned) {
+            self.energy_returned.set(&labels, returned);
+        }
+
+        // Flag the energy total as stalled when it stops advancing under load
+        if let Some(max_unchanged) = self.energy_stall_updates.filter(|_| !stale) {
+            if let Some(stalled) = self.record_energy_progress(&labels, switch, max_unchanged) {
+                self.energy_stalled
+                    .set(&labels, if stalled { 1.0 } else { 0.0 });
+            }
+        }
+
+        // Update switch state if present
+        if let Some(output) = switch.output {
+            self.switch_state
+                .set(&labels, if output { 1.0 } else { 0.0 });
+        }
+
+        // Partial updates without `errors` say nothing about the error state
+        let overtemp = match &switch.errors {
+            Some(errors) => Some(errors.iter().any(|error| error == "overtemp")),
+            None if full_status => Some(false),
+            None => None,
+        };
+        if let Some(overtemp) = overtemp {
+            self.overtemp.set(&labels, if overtemp { 1.0 } else { 0.0 });
+        }
+
+        // Update internal temperature if present, labeled with the switch channel; the
+        // switch labels are not needed anymore, so their strings move over
+        if let Some(temperature) = &switch.temperature {
+            let Sited {
+                site,
+                labels: DeviceLabels { device, switch },
+            } = labels;
+            let sensor_labels = Sited {
+                site,
+                labels: SensorLabels { device, id: switch },
+            };
+            self.update_temperature(device_id, &sensor_labels, temperature.tc, temperature.tf);
+        }
+    }
+
+    /// Set the temperature families enabled by `--temperature-unit`
+    fn update_temperature(
+        &self,
+        device_id: &str,
+        labels: &Sited<SensorLabels>,
+        tc: Option<f64>,
+        tf: Option<f64>,
+    ) {
+        if let Some(tc) = finite(device_id, "temperature.tC", tc) {
+            self.temperature.set(labels, tc);
+        }
+        if let Some(tf) = finite(device_id, "temperature.tF", tf) {
+            self.temperature_fahrenheit.set(labels, tf);
+        }
+    }
+
+    /// Update power meter channel (pm1:N)
+    fn update_pm1(&self, device_id: &str, channel: u16, pm1: &Pm1Data) {
+        let labels = self.sited(ChannelLabels {
+            device: device_id.to_string(),
+            channel: channel.to_string(),
+        });
+
+        if let Some(apower) = finite(device_id, "apower", pm1.apower) {
+            self.pm1_power.set(&labels, apower);
+        }
+
+        if let Some(voltage) = finite(device_id, "voltage", pm1.voltage) {
+            self.pm1_voltage.set(&labels, voltage);
+        }
+
+        if let Some(current) = finite(device_id, "current", pm1.current) {
+            self.pm1_current.set(&labels, current);
+        }
+
+        if let Some(freq) = finite(device_id, "freq", pm1.freq) {
+            self.pm1_frequency.set(&labels, freq);
+        }
+
+        let total = pm1.aenergy.as_ref().map(|aenergy| aenergy.total);
+        if let Some(total) = finite(device_id, "aenergy.total", total) {
+            self.pm1_energy_total.set(&labels, total);
+        }
+    }
+
+    /// Update three-phase energy meter (em:0), skipping phases the meter does not report
+    fn update_em(&self, device_labels: &Sited<DeviceOnlyLabels>, em: &EmData) {
+        let device_id = device_labels.device.as_str();
+        for reading in em.phases() {
+            let labels = self.sited(PhaseLabels {
+                device: device_id.to_string(),
+                phase: reading.phase.to_string(),
+            });
+
+            if let Some(power) = finite(device_id, "act_power", reading.act_power) {
+                self.em_power.set(&labels, power);
+            }
+
+            if let Some(voltage) = finite(device_id, "voltage", reading.voltage) {
+                self.em_voltage.set(&labels, voltage);
+            }
+
+            if let Some(current) = finite(device_id, "current", reading.current) {
+                self.em_current.set(&labels, current);
+            }
+        }
+
+        if let Some(total) = finite(device_id, "total_act_power", em.total_act_power) {
+            self.em_total_power.set(device_labels, total);
+        }
+    }
+
+    /// Replace the device's payload info series with one for `hash`
+    fn update_payload_info(&self, device_id: &str, hash: String) {
+        let labels = self.sited(PayloadLabels {
+            device: device_id.to_string(),
+            hash,
+        });
+
+        let previous = self
+            .payload_info_labels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(device_id.to_string(), labels.labels.clone());
+        if let Some(previous) = previous.filter(|previous| *previous != labels.labels) {
+            self.payload_info.remove(&previous);
+        }
+
+        self.payload_info.set(&labels, 1.0);
+    }
+
+    /// Log the unsupported components of a device whenever a new one shows up
+    fn log_unknown_components(&self, device_id: &str, params: &MessageParams) {
+        if params.extra.is_empty() {
+            return;
+        }
+        let mut unknown = self
+            .unknown_components
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let seen = unknown.entry(device_id.to_string()).or_default();
+        let mut added = false;
+        for key in params.extra.keys() {
+            added |= seen.insert(key.clone());
+        }
+        if added {
+            let keys: Vec<&str> = seen.iter().map(String::as_str).collect();
+            debug!(
+                "Unsupported components on {}: {}",
+                device_id,
+                keys.join(", ")
+            );
+        }
+    }
+
+    /// Publish the WiFi info series, replacing the one for a previous SSID
+    fn update_wifi_info(&self, device_id: &str, ssid: &str) {
+        let labels = self.sited(WifiInfoLabels {
+            device: device_id.to_string(),
+            ssid: ssid.to_string(),
+        });
+
+        let previous = self
+            .wifi_info_labels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(device_id.to_string(), labels.labels.clone());
+        if let Some(previous) = previous.filter(|previous| *previous != labels.labels) {
+            self.wifi_info.remove(&previous);
+        }
+
+        self.wifi_info.set(&labels, 1.0);
+    }
+
+    /// Publish the device info series, with empty labels for missing fields
+    fn update_device_info(&self, device_id: &str, device: Option<&DeviceInfoData>) {
+        let device = device.cloned().unwrap_or_default();
+        let labels = self.sited(DeviceInfoLabels {
+            device: device_id.to_string(),
+            model: device.model.unwrap_or_default(),
+            gen: device.gen.map(|gen| gen.to_string()).unwrap_or_default(),
+            fw_id: device.fw_id.unwrap_or_default(),
+        });
+
+        let previous = self
+            .device_info_labels
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(device_id.to_string(), labels.labels.clone());
+        if let Some(previous) = previous.filter(|previous| *previous != labels.labels) {
+            self.device_info.remove(&previous);
+        }
+
+        self.device_info.set(&labels, 1.0);
+    }
+
+    /// Update H&T sensor readings and battery state
+    fn update_sensors(&self, device_labels: &Sited<DeviceOnlyLabels>, params: &MessageParams) {
+        let device_id = device_labels.device.as_str();
+
+        // Update temperature from every probe (temperature:N)
+        for (id, temperature) in params.temperature.iter() {
+            let labels = self.sited(SensorLabels {
+                device: device_id.to_string(),
+                id: id.to_string(),
+            });
+            self.update_temperature(device_id, &labels, temperature.tc, temperature.tf);
+        }
+
+        // Update humidity from every probe (humidity:N)
+        for (id, humidity) in params.humidity.iter() {
+            if let Some(rh) = finite(device_id, "humidity.rh", humidity.rh) {
+                let labels = self.sited(SensorLabels {
+                    device: device_id.to_string(),
+                    id: id.to_string(),
+                });
+                self.humidity.set(&labels, rh);
+            }
+        }
+
+        // Only devices that report devicepower:0 get a presence series
+        let external = params
+            .devicepower
+            .as_ref()
+            .and_then(|devicepower| devicepower.external.as_ref());
+        if let Some(external) = external {
+            self.external_power_present
+                .set(device_labels, if external.present { 1.0 } else { 0.0 });
+        }
+
+        // Update battery from device power (devicepower:0)
+        let battery = params
+            .devicepower
+            .as_ref()
+            .and_then(|devicepower| devicepower.battery.as_ref());
+        if let Some(battery) = battery {
+            let percent = finite(device_id, "battery.percent", battery.percent);
+            let voltage = finite(device_id, "battery.V", battery.voltage);
+            if let Some(percent) = percent {
+                self.battery_percent.set(device_labels, percent);
+                if let Some(threshold) = self.battery_low_percent {
+                    self.battery_low
+                        .set(device_labels, if percent < threshold { 1.0 } else { 0.0 });
+                }
+            }
+            if let Some(voltage) = voltage {
+                self.battery_voltage.set(device_labels, voltage);
+            }
+
+            // A percent far off the discharge curve points at a miscalibrated sensor
+            if let (Some(percent), Some(voltage), Some(threshold)) =
+                (percent, voltage, self.battery_mismatch_percent)
+            {
+                let mismatch = (percent - battery_percent_from_voltage(voltage)).abs() > threshold;
+                self.battery_mismatch
+                    .set(device_labels, if mismatch { 1.0 } else { 0.0 });
+            }
+        }
+    }
+
+    /// Update script-controlled virtual components (number:N, boolean:N)
+    fn update_virtual(&self, device_id: &str, params: &MessageParams) {
+        let labels = |id: u16, name: &Option<String>| {
+            self.sited(VirtualLabels {
+                device: device_id.to_string(),
+                id: id.to_string(),
+                name: name.clone().unwrap_or_default(),
+            })
+        };
+
+        for (id, number) in params.number.iter() {
+            if let Some(value) = finite(device_id, "number.value", number.value) {
+                self.virtual_number.set(&labels(id, &number.name), value);
+            }
+        }
+
+        for (id, boolean) in params.boolean.iter() {
+            if let Some(value) = boolean.value {
+                self.virtual_boolean
+                    .set(&labels(id, &boolean.name), if value { 1.0 } else { 0.0 });
+            }
+        }
+    }
+
+    /// Update smoke, flood and motion sensors (smoke:0, flood:0, motion:0)
+    fn update_alarms(&self, device_labels: &Sited<DeviceOnlyLabels>, params: &MessageParams) {
+        let readings = [
+            (
+                &self.smoke_alarm,
+                params.smoke.as_ref().and_then(|smoke| smoke.alarm),
+            ),
+            (
+                &self.flood_alarm,
+                params.flood.as_ref().and_then(|flood| flood.alarm),
+            ),
+            (
+                &self.motion_detected,
+                params.motion.as_ref().and_then(MotionData::detected),
+            ),
+        ];
+        for (gauge, value) in readings {
+            if let Some(value) = value {
+                gauge.set(device_labels, if value { 1.0 } else { 0.0 });
+            }
+        }
+    }
+
+    /// Update dimmers and white lights (light:N)
+    fn update_dimmers(&self, device_id: &str, params: &MessageParams) {
+        for (light, data) in params.light.iter() {
+            let labels = self.sited(LightLabels {
+                device: device_id.to_string(),
+                light: light.to_string(),
+            });
+            if let Some(output) = data.output {
+                self.light_on.set(&labels, if output { 1.0 } else { 0.0 });
+            }
+            if let Some(brightness) = finite(device_id, "light.brightness", data.brightness) {
+                self.light_brightness.set(&labels, brightness);
+            }
+        }
+    }
+
+    /// Update covers in cover mode (cover:N)
+    fn update_covers(&self, device_id: &str, params: &MessageParams) {
+        for (channel, cover) in params.cover.iter() {
+            let labels = self.sited(ChannelLabels {
+                device: device_id.to_string(),
+                channel: channel.to_string(),
+            });
+            if let Some(position) = finite(device_id, "cover.current_pos", cover.current_pos) {
+                self.cover_position.set(&labels, position);
+            }
+            if let Some(apower) = finite(device_id, "cover.apower", cover.apower) {
+                self.cover_power.set(&labels, apower);
+            }
+            if let Some(state) = &cover.state {
+                let Sited { site, labels } = labels;
+                let state_labels = Sited {
+                    site,
+                    labels: CoverStateLabels {
+                        device: labels.device.clone(),
+                        channel: labels.channel.clone(),
+                        state: state.clone(),
+                    },
+                };
+                let previous = self
+                    .cover_state_labels
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(labels, state_labels.labels.clone());
+                if let Some(previous) = previous.filter(|previous| *previous != state_labels.labels)
+                {
+                    self.cover_state.remove(&previous);
+                }
+                self.cover_state.set(&state_labels, 1.0);
+            }
+        }
+    }
+
+    /// Count NotifyEvent events by name
+    fn count_events(&self, device_id: &str, events: &[EventData]) {
+        for event in events {
+            let labels = self.sited(EventLabels {
+                device: device_id.to_string(),
+                event: event.event.clone(),
+            });
+            self.events.inc(&labels);
+        }
+    }
+
+    /// Update analog inputs (input:N), applying their calibration if configured
+    fn update_inputs(&self, device_id: &str, params: &MessageParams) {
+        for (id, input) in params.input.iter() {
+            let Some(percent) = finite(device_id, "input.percent", input.percent) else {
+                continue;
+            };
+            let labels = self.sited(InputLabels {
+                device: device_id.to_string(),
+                input: id.to_string(),
+            });
+            self.input_percent.set(&labels, percent);
+            if let Some(calibration) = self.input_calibration.get(&id) {
+                self.input_value.set(&labels, calibration.apply(percent));
+            }
+        }
+    }
+
+    /// Update color lights (cct:N, rgb:N, rgbw:N)
+    fn update_lights(&self, device_id: &str, params: &MessageParams) {
+        for (channel, cct) in params.cct.iter() {
+            if let Some(ct) = finite(device_id, "cct.ct", cct.ct) {
+                let labels = self.sited(ChannelLabels {
+                    device: device_id.to_string(),
+                    channel: channel.to_string(),
+                });
+                self.light_color_temp.set(&labels, ct);
+            }
+        }
+
+        let set_intensity = |channel: u16, color: &str, value: f64| {
+            if let Some(value) = finite(device_id, "rgb", Some(value)) {
+                let labels = self.sited(LightColorLabels {
+                    device: device_id.to_string(),
+                    channel: channel.to_string(),
+                    color: color.to_string(),
+                });
+                self.light_color_intensity.set(&labels, value);
+            }
+        };
+        let set_rgb = |channel: u16, [red, green, blue]: [f64; 3]| {
+            set_intensity(channel, "red", red);
+            set_intensity(channel, "green", green);
+            set_intensity(channel, "blue", blue);
+        };
+        for (channel, rgb) in params.rgb.iter() {
+            if let Some(color) = rgb.rgb {
+                set_rgb(channel, color);
+            }
+        }
+        for (channel, rgbw) in params.rgbw.iter() {
+            if let Some(color) = rgbw.rgb {
+                set_rgb(channel, color);
+            }
+            if let Some(white) = rgbw.white {
+                set_intensity(channel, "white", white);
+            }
+        }
+    }
+
+    /// Track energy progress of a switch, returning whether the total is stalled
+    /// when the update carries an energy total
+    fn record_energy_progress(
+        &self,
+        labels: &DeviceLabels,
+        switch: &SwitchData,
+        max_unchanged: u32,
+    ) -> Option<bool> {
+        let mut energy_progress = self
+            .energy_progress
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let progress = energy_progress.entry(labels.clone()).or_default();
+
+        if let Some(apower) = switch.apower.filter(|apower| apower.is_finite()) {
+            progress.power = apower;
+        }
+        let total = switch.aenergy.as_ref()?.total;
+        if !total.is_finite() {
+            return None;
+        }
+
+        if total > progress.total {
+            progress.unchanged = 0;
+        } else if progress.power > 0.0 {
+            progress.unchanged += 1;
+        } else {
+            progress.unchanged = 0;
+        }
+        progress.total = total;
+
+        Some(progress.unchanged >= max_unchanged)
+    }
+
+    /// Remember the latest energy sample of a switch, returning the average power
+    /// in watts since the previous one; older or repeated samples are ignored
+    fn record_energy_sample(
+        &self,
+        labels: &DeviceLabels,
+        minute_ts: i64,
+        total: f64,
+    ) -> Option<f64> {
+        let mut energy_samples = self
+            .energy_samples
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let previous = energy_samples.get(labels).copied();
+        if previous.is_some_and(|(last_ts, _)| minute_ts <= last_ts) {
+            return None;
+        }
+        energy_samples.insert(labels.clone(), (minute_ts, total));
+
+        // A total going backward means a counter reset, not negative power
+        let (last_ts, last_total) = previous?;
+        (total >= last_total).then(|| (total - last_total) * 3600.0 / (minute_ts - last_ts) as f64)
+    }
+
+    /// Remember the latest `minute_ts` for a switch, returning the one seen before
+    fn record_minute_ts(&self, labels: &DeviceLabels, minute_ts: i64) -> Option<i64> {
+        let mut last_minute_ts = self
+            .last_minute_ts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match last_minute_ts.get_mut(labels) {
+            Some(last) => {
+                let previous = *last;
+                *last = previous.max(minute_ts);
+                Some(previous)
+            }
+            None => {
+                last_minute_ts.insert(labels.clone(), minute_ts);
+                None
+            }
+        }
+    }
+}
+
+/// Prune the series of devices silent for longer than `max_age` on a fixed interval, forever
+pub async fn prune_stale(metrics: Arc<ShellyMetrics>, max_age: Duration, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let pruned = metrics.prune(max_age);
+        if pruned > 0 {
+            info!("Pruned {} stale series", pruned);
+        }
+    }
+}
+
+/// Refresh the exporter's self-monitoring gauges on a fixed interval, forever
+pub async fn observe_process(
+    metrics: Arc<ShellyMetrics>,
+    exporter_metrics: Arc<ExporterMetrics>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        exporter_metrics.observe_process(&metrics);
+    }
+}
+
+/// Resident set size of this process, from `VmRSS` in /proc/self/status
+#[cfg(target_os = "linux")]
+fn resident_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_bytes() -> Option<u64> {
+    None
+}
+
+/// Register `mqtt2prom_build_info` with the crate version and the commit captured by
+/// build.rs, returning its descriptor
+pub fn register_build_info(registry: &mut Registry) -> Vec<MetricDescriptor> {
+    let options = MetricsOptions::default();
+    let mut registrar = Registrar::new(registry, &options);
+    let build_info: Tracked<BuildInfoLabels, FloatGauge> = registrar.family(
+        "mqtt2prom_build_info",
+        "Exporter version and git commit (always 1)",
+        "info",
+    );
+    let labels = Sited::unsited(BuildInfoLabels {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        commit: option_env!("MQTT2PROM_GIT_SHA")
+            .unwrap_or("unknown")
+            .to_string(),
+    });
+    build_info.set(&labels, 1.0);
+    registrar.descriptors
+}
+
+/// Render every exported metric as a tab-separated table for `--print-metrics-list`
+pub fn metrics_list() -> String {
+    let mut registry = Registry::default();
+    let build_info = register_build_info(&mut registry);
+    // Every optional family, so the list covers what any configuration can emit
+    let options = MetricsOptions {
+        emit_legacy_scaled: true,
+        max_devices: Some(usize::MAX),
+        temperature_unit: TemperatureUnit::Both,
+        ..Default::default()
+    };
+    let metrics = ShellyMetrics::with_options(&mut registry, &options);
+    let exporter_metrics = ExporterMetrics::new(&mut registry);
+
+    let mut out = String::from("# name\ttype\tunit\tlabels\thelp\n");
+    let descriptors = metrics
+        .descriptors()
+        .iter()
+        .chain(exporter_metrics.descriptors())
+        .chain(&build_info);
+    for descriptor in descriptors {
+        out.push_str(&descriptor.to_string());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_message;
+    use prometheus_client::encoding::text::encode;
+
+    #[test]
+    fn test_metrics_registration() {
+        let mut registry = Registry::default();
+        let _metrics = ShellyMetrics::new(&mut registry);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        assert!(buffer.contains("shelly_switch_power_watts"));
+        assert!(buffer.contains("shelly_switch_voltage_volts"));
+        assert!(buffer.contains("shelly_switch_current_amps"));
+        assert!(buffer.contains("shelly_switch_energy_total_wh"));
+        assert!(buffer.contains("shelly_switch_state"));
+        assert!(buffer.contains("shelly_temperature_celsius"));
+        assert!(buffer.contains("shelly_humidity_percent"));
+        assert!(buffer.contains("shelly_battery_percent"));
+        assert!(buffer.contains("shelly_battery_voltage"));
+        assert!(buffer.contains("shelly_wifi_rssi_dbm"));
+    }
+
+    #[test]
+    fn test_metrics_list() {
+        let list = metrics_list();
+
+        assert!(list.contains("shelly_switch_power_watts\tgauge\twatts\tdevice,switch\t"));
+        assert!(list.contains("shelly_temperature_celsius\tgauge\tcelsius\tdevice,id\t"));
+        // Families that only some options register are listed too
+        for name in [
+            "mqtt2prom_devices_dropped_total\tcounter",
+            "shelly_switch_voltage_volts_scaled\t",
+            "shelly_temperature_fahrenheit\t",
+        ] {
+            assert!(list.contains(name), "{}", name);
+        }
+        assert!(list.lines().all(|line| line.split('\t').count() == 5));
+    }
+
+    #[test]
+    fn test_update_individual_metrics() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        metrics.update_power("device1", "0", 125.5);
+        metrics.update_voltage("device1", "0", 122.3);
+        metrics.update_current("device1", "0", 1.025);
+        metrics.update_energy("device1", "0", 3949.949);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        assert!(buffer.contains("device1"));
+        assert!(buffer.contains("switch=\"0\""));
+    }
+
+    #[test]
+    fn test_update_from_message() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyFullStatus",
+            "params": {
+                "switch:0": {
+                    "id": 0,
+                    "output": true,
+                    "apower": 125.5,
+                    "voltage": 122.3,
+                    "current": 1.025,
+                    "aenergy": {"total": 3949.949},
+                    "temperature": {"tC": 37.9, "tF": 100.1}
+                },
+                "wifi": {"rssi": -40}
+            }
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        metrics.update_from_message(&msg, Some("mostert/shelly/plugcoffee/events/rpc"));
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        // Should use topic-derived name "plugcoffee" instead of MAC
+        assert!(buffer.contains("plugcoffee"));
+        assert!(buffer.contains("switch=\"0\""));
+    }
+
+    #[test]
+    fn test_multiple_devices() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        metrics.update_power("device1", "0", 100.0);
+        metrics.update_power("device2", "0", 200.0);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        assert!(buffer.contains("device1"));
+        assert!(buffer.contains("device2"));
+    }
+
+    /// Full status of an H&T Gen3 on battery
+    const HT_FULL_STATUS: &str = r#"{
+        "src": "shellyhtg3-3030f9e7d294",
+        "dst": "mostert/shelly/temp-main/events",
+        "method": "NotifyFullStatus",
+        "params": {
+            "temperature:0": {"id": 0, "tC": 18.0, "tF": 64.5},
+            "humidity:0": {"id": 0, "rh": 38.9},
+            "devicepower:0": {
+                "id": 0,
+                "battery": {"V": 5.41, "percent": 70},
+                "external": {"present": false}
+            },
+            "wifi": {"rssi": -54}
+        }
+    }"#;
+
+    #[test]
+    fn test_ht_sensor_message() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        let msg = parse_message(HT_FULL_STATUS).unwrap();
+        metrics.update_from_message(&msg, Some("mostert/shelly/temp-main/events/rpc"));
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        // Values are exported unscaled
+        assert!(buffer.contains("shelly_temperature_celsius{device=\"temp-main\",id=\"0\"} 18.0\n"));
+        assert!(buffer.contains("shelly_humidity_percent{device=\"temp-main\",id=\"0\"} 38.9\n"));
+        assert!(buffer.contains("shelly_battery_percent{device=\"temp-main\"} 70.0\n"));
+        assert!(buffer.contains("shelly_battery_voltage{device=\"temp-main\"} 5.41\n"));
+        assert!(buffer.contains("shelly_external_power_present{device=\"temp-main\"} 0.0\n"));
+    }
+
+    #[test]
+    fn test_energy_out_of_order() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        for minute_ts in [1763918640, 1763918700, 1763918640, 1763918700, 1763918760] {
+            let json = format!(
+                r#"{{
+                    "src": "shellyplugus-d48afc781ad8",
+                    "method": "NotifyStatus",
+                    "params": {{
+                        "switch:0": {{
+                            "id": 0,
+                            "aenergy": {{"total": 3949.949, "minute_ts": {}}}
+                        }}
+                    }}
+                }}"#,
+                minute_ts
+            );
+            let msg = parse_message(&json).unwrap();
+            metrics.update_from_message(&msg, Some("mostert/shelly/plugcoffee/events/rpc"));
+        }
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        // One backward timestamp and one repeated timestamp
+        assert!(buffer.contains(
+            "mqtt2prom_energy_out_of_order_total{device=\"plugcoffee\",switch=\"0\"} 2\n"
+        ));
+    }
+
+    #[test]
+    fn test_stale_energy_skipped() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+        let update = |total: f64, minute_ts: Option<i64>| {
+            let minute_ts =
+                minute_ts.map_or(String::new(), |ts| format!(r#", "minute_ts": {}"#, ts));
+            let json = format!(
+                r#"{{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {{"switch:0": {{"id": 0, "aenergy": {{"total": {}{}}}}}}}}}"#,
+                total, minute_ts
+            );
+            metrics.update_from_message(
+                &parse_message(&json).unwrap(),
+                Some("mostert/shelly/plugcoffee/events/rpc"),
+            );
+        };
+        let total = |registry: &Registry| {
+            let mut buffer = String::new();
+            encode(&mut buffer, registry).unwrap();
+            buffer
+                .lines()
+                .find_map(|line| {
+                    line.strip_prefix(
+                        "shelly_switch_energy_total_wh_total{device=\"plugcoffee\",switch=\"0\"} ",
+                    )
+                })
+                .map(str::to_string)
+        };
+
+        update(3950.5, Some(1763918700));
+        // A buffered reading from the minute before is skipped
+        update(3949.9, Some(1763918640));
+        assert_eq!(total(&registry).as_deref(), Some("3950.5"));
+
+        // Updates within the same minute and without a timestamp still apply
+        update(3950.7, Some(1763918700));
+        assert_eq!(total(&registry).as_deref(), Some("3950.7"));
+        update(3951.0, None);
+        assert_eq!(total(&registry).as_deref(), Some("3951.0"));
+    }
+
+    #[test]
+    fn test_computed_power() {
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            computed_power: true,
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+        let series = "shelly_switch_computed_power_watts{device=\"kettle\",switch=\"0\"}";
+
+        let update = |total: f64, minute_ts: i64| {
+            let json = format!(
+                r#"{{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {{"switch:0": {{"id": 0, "apower": 1500.0, "aenergy": {{"total": {}, "minute_ts": {}}}}}}}}}"#,
+                total, minute_ts
+            );
+            let msg = parse_message(&json).unwrap();
+            metrics.update_from_message(&msg, Some("mostert/shelly/kettle/events/rpc"));
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            buffer
+        };
+
+        // A single sample has no rate yet
+        assert!(!update(1000.0, 1763918640).contains(series));
+        // 25 Wh over one minute is 1500 W
+        assert!(update(1025.0, 1763918700).contains(&format!("{} 1500.0\n", series)));
+        // A repeated minute leaves the rate alone
+        assert!(update(1030.0, 1763918700).contains(&format!("{} 1500.0\n", series)));
+    }
+
+    #[test]
+    fn test_sharded_registries() {
+        let registries: Vec<_> = (0..4)
+            .map(|_| Arc::new(RwLock::new(Registry::default())))
+            .collect();
+        let metrics = {
+            let mut guards: Vec<_> = registries.iter().map(|r| r.write().unwrap()).collect();
+            let mut regs: Vec<&mut Registry> = guards.iter_mut().map(|g| &mut **g).collect();
+            ShellyMetrics::sharded(&mut regs, &MetricsOptions::default())
+        };
+
+        for i in 0..32 {
+            metrics.update_power(&format!("plug{}", i), "0", i as f64);
+        }
+
+        let populated = registries
+            .iter()
+            .filter(|registry| {
+                let mut buffer = String::new();
+                encode(&mut buffer, &registry.read().unwrap()).unwrap();
+                buffer.contains("shelly_switch_power_watts{")
+            })
+            .count();
+        assert!(populated > 1, "devices should spread over several shards");
+
+        let body = crate::server::encode_registries(&registries).unwrap();
+        assert_eq!(body.matches("# HELP shelly_switch_power_watts ").count(), 1);
+        for i in 0..32 {
+            assert!(body.contains(&format!("{{device=\"plug{}\",switch=\"0\"}}", i)));
+        }
+    }
+
+    #[test]
+    fn test_oversized_component_messages() {
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            max_components_per_message: Some(2),
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+
+        let json = r#"{
+            "src": "custombridge-01",
+            "method": "NotifyStatus",
+            "params": {
+                "switch:0": {"apower": 1.0},
+                "switch:1": {"apower": 2.0},
+                "number:200": {"value": 3.0},
+                "wifi": {"rssi": -60}
+            }
+        }"#;
+        let msg = parse_message(json).unwrap();
+        metrics.update_from_message(&msg, Some("mostert/shelly/bridge/events/rpc"));
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        assert!(
+            buffer.contains("mqtt2prom_oversized_component_messages_total{device=\"bridge\"} 1\n")
+        );
+        assert_eq!(buffer.matches("shelly_switch_power_watts{").count(), 2);
+        // Components past the cap are dropped, singletons are still processed
+        assert!(!buffer.contains("shelly_virtual_number{"));
+        assert!(buffer.contains("shelly_wifi_rssi_dbm{device=\"bridge\"} -60.0\n"));
+    }
+
+    #[test]
+    fn test_max_devices() {
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            max_devices: Some(2),
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+        let update = |device: &str, apower: f64| {
+            let json = format!(
+                r#"{{"src": "shellyplugus-{}", "method": "NotifyStatus", "params": {{"switch:0": {{"id": 0, "apower": {}}}}}}}"#,
+                device, apower
+            );
+            metrics.update_from_message(&parse_message(&json).unwrap(), None);
+        };
+
+        update("aaaaaaaaaaaa", 1.0);
+        update("bbbbbbbbbbbb", 2.0);
+        update("cccccccccccc", 3.0);
+        // Known devices keep updating past the cap
+        update("aaaaaaaaaaaa", 4.0);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer
+            .contains("shelly_switch_power_watts{device=\"aaaaaaaaaaaa\",switch=\"0\"} 4.0\n"));
+        assert!(buffer
+            .contains("shelly_switch_power_watts{device=\"bbbbbbbbbbbb\",switch=\"0\"} 2.0\n"));
+        assert!(!buffer.contains("cccccccccccc"));
+        assert!(buffer.contains("mqtt2prom_devices_dropped_total 1\n"));
+    }
+
+    #[test]
+    fn test_max_devices_with_last_seen_disabled() {
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            max_devices: Some(1),
+            disabled_metrics: vec!["last_seen_timestamp".to_string()],
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+        for device in ["aaaaaaaaaaaa", "bbbbbbbbbbbb"] {
+            let json = format!(
+                r#"{{"src": "shellyplugus-{}", "method": "NotifyStatus", "params": {{"switch:0": {{"id": 0, "apower": 1.0}}}}}}"#,
+                device
+            );
+            metrics.update_from_message(&parse_message(&json).unwrap(), None);
+        }
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(!buffer.contains("shelly_last_seen"));
+        assert!(buffer.contains("aaaaaaaaaaaa"));
+        assert!(!buffer.contains("bbbbbbbbbbbb"));
+        assert!(buffer.contains("mqtt2prom_devices_dropped_total 1\n"));
+    }
+
+    #[test]
+    fn test_max_channels_per_device() {
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            max_channels_per_device: Some(16),
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+
+        let switches = (0..20)
+            .map(|channel| format!("\"switch:{}\": {{\"apower\": 1.0}}", channel))
+            .collect::<Vec<_>>()
+            .join(",");
+        let json = format!(
+            r#"{{"src": "shellypro4pm-aabbcc", "method": "NotifyStatus", "params": {{{}}}}}"#,
+            switches
+        );
+        let msg = parse_message(&json).unwrap();
+        metrics.update_from_message(&msg, Some("mostert/shelly/rack/events/rpc"));
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        assert_eq!(buffer.matches("shelly_switch_power_watts{").count(), 16);
+        assert!(buffer.contains("switch=\"15\"}"));
+        assert!(!buffer.contains("switch=\"16\"}"));
+        assert!(buffer.contains("mqtt2prom_switch_channels_dropped_total{device=\"rack\"} 4\n"));
+    }
+
+    #[test]
+    fn test_prune_stale_series() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+        let ttl = Duration::from_secs(3600);
+
+        let json = r#"{
+            "src": "shellyhtg3-3030f9e7d294",
+            "method": "NotifyStatus",
+            "params": {"temperature:0": {"id": 0, "tC": 18.0, "tF": 64.5}}
+        }"#;
+        let msg = parse_message(json).unwrap();
+        metrics.update_from_message(&msg, Some("mostert/shelly/temp-main/events/rpc"));
+
+        let encoded = |registry: &Registry| {
+            let mut buffer = String::new();
+            encode(&mut buffer, registry).unwrap();
+            buffer
+        };
+        let series = "shelly_temperature_celsius{device=\"temp-main\",id=\"0\"}";
+
+        // Still within the TTL
+        assert_eq!(metrics.prune_at(Instant::now(), ttl), 0);
+        assert!(encoded(&registry).contains(series));
+
+        // Advance the clock past the TTL
+        let later = Instant::now() + ttl + Duration::from_secs(1);
+        // The temperature and last seen series
+        assert_eq!(metrics.prune_at(later, ttl), 2);
+        assert!(!encoded(&registry).contains(series));
+    }
+
+    #[test]
+    fn test_prune_keeps_series_of_reporting_devices() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+        let ttl = Duration::from_millis(200);
+        let topic = "mostert/shelly/plugcoffee/events/rpc";
+
+        let full = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyFullStatus",
+            "params": {"switch:0": {"id": 0, "output": true, "apower": 5.0}}
+        }"#;
+        metrics.update_from_message(&parse_message(full).unwrap(), Some(topic));
+        metrics.update_online("mostert/shelly/plugcoffee/online", true);
+
+        // Only power readings arrive once the TTL has passed since the full status
+        std::thread::sleep(ttl + Duration::from_millis(100));
+        let partial = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyStatus",
+            "params": {"switch:0": {"id": 0, "apower": 7.5}}
+        }"#;
+        metrics.update_from_message(&parse_message(partial).unwrap(), Some(topic));
+
+        assert_eq!(metrics.prune_at(Instant::now(), ttl), 0);
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("shelly_switch_state{device=\"plugcoffee\",switch=\"0\"} 1"));
+        assert!(buffer.contains("shelly_online{device=\"plugcoffee\"} 1"));
+
+        // Once the device goes quiet, all of its series go together
+        let later = Instant::now() + ttl + Duration::from_secs(1);
+        assert!(metrics.prune_at(later, ttl) > 0);
+        assert_eq!(metrics.series_count(), 0);
+    }
+
+    #[test]
+    fn test_prune_forgets_device_state() {
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            computed_power: true,
+            energy_stall_updates: Some(3),
+            debug_payload_label: true,
+            site_from_topic_segment: true,
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+        let ttl = Duration::from_secs(3600);
+        let topic = Some("mostert/shelly/plugcoffee/events/rpc");
+
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyFullStatus",
+            "params": {
+                "switch:0": {"id": 0, "apower": 5.0, "aenergy": {"total": 10.0, "minute_ts": 1700000000}},
+                "cover:0": {"id": 0, "state": "open"},
+                "thermostat:0": {"id": 0},
+                "wifi": {"rssi": -50, "ssid": "home"},
+                "sys": {"device": {"model": "SNPL-00116US", "gen": 2, "fw_id": "1.0"}}
+            }
+        }"#;
+        metrics.update_from_message(&parse_message(json).unwrap(), topic);
+        metrics.record_payload(&parse_message(json).unwrap(), topic, json.as_bytes());
+
+        let shard = metrics.shard("plugcoffee");
+        let sizes = || {
+            [
+                shard.devices.lock().unwrap().len(),
+                shard.wifi_info_labels.lock().unwrap().len(),
+                shard.device_info_labels.lock().unwrap().len(),
+                shard.payload_info_labels.lock().unwrap().len(),
+                shard.unknown_components.lock().unwrap().len(),
+                shard.cover_state_labels.lock().unwrap().len(),
+                shard.last_minute_ts.lock().unwrap().len(),
+                shard.energy_samples.lock().unwrap().len(),
+                shard.energy_progress.lock().unwrap().len(),
+                metrics.sites.as_ref().unwrap().read().unwrap().len(),
+            ]
+        };
+        assert_eq!(sizes(), [1; 10]);
+
+        metrics.prune_at(Instant::now() + ttl + Duration::from_secs(1), ttl);
+        assert_eq!(sizes(), [0; 10]);
+    }
+
+    #[test]
+    fn test_uptime() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyStatus",
+            "params": {"sys": {"uptime": 12345}}
+        }"#;
+        let msg = parse_message(json).unwrap();
+        metrics.update_from_message(&msg, Some("mostert/shelly/plugcoffee/events/rpc"));
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        assert!(buffer.contains("shelly_uptime_seconds{device=\"plugcoffee\"} 12345.0\n"));
+    }
+
+    #[test]
+    fn test_absent_fields_create_no_series() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        let json = r#"{
+            "src": "shellypro4pm-a8032ab12345",
+            "method": "NotifyStatus",
+            "params": {
+                "switch:0": {"id": 0, "apower": 12.5},
+                "wifi": {"sta_ip": null, "status": "disconnected", "ssid": null}
+            }
+        }"#;
+        let msg = parse_message(json).unwrap();
+        metrics.update_from_message(&msg, Some("mostert/shelly/pro4pm/events/rpc"));
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        assert!(buffer.contains("shelly_switch_power_watts{device=\"pro4pm\",switch=\"0\"} 12.5\n"));
+        let series = buffer
+            .lines()
+            .filter(|line| !line.starts_with('#') && !line.starts_with("shelly_last_seen"))
+            .count();
+        assert_eq!(
+            series, 1,
+            "only the reported power should create a series:\n{}",
+            buffer
+        );
+    }
+
+    #[test]
+    fn test_non_finite_values_skipped() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+        let topic = Some("mostert/shelly/plugcoffee/events/rpc");
+
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyStatus",
+            "params": {"switch:0": {"id": 0, "apower": 10.5, "voltage": 120.1}}
+        }"#;
+        let mut msg = parse_message(json).unwrap();
+        metrics.update_from_message(&msg, topic);
+
+        // JSON cannot carry NaN, so build the reading directly
+        let switch = SwitchData {
+            id: 0,
+            output: None,
+            apower: Some(f64::NAN),
+            voltage: Some(121.4),
+            current: Some(f64::INFINITY),
+            pf: None,
+            freq: None,
+            aenergy: None,
+            ret_aenergy: None,
+            temperature: None,
+            errors: None,
+        };
+        msg.params.switch = [(0, switch)].into_iter().collect();
+        metrics.update_from_message(&msg, topic);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        let labels = r#"{device="plugcoffee",switch="0"}"#;
+        assert!(buffer.contains(&format!("shelly_switch_power_watts{} 10.5\n", labels)));
+        assert!(buffer.contains(&format!("shelly_switch_voltage_volts{} 121.4\n", labels)));
+        assert!(!buffer.contains("shelly_switch_current_amps{"));
+        assert!(!buffer.contains("NaN"));
+    }
+
+    #[test]
+    fn test_sensor_error_creates_no_series() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        let json = r#"{
+            "src": "shellyhtg3-3030f9e7d294",
+            "method": "NotifyStatus",
+            "params": {
+                "temperature:0": {"id": 0, "tC": null, "tF": null, "errors": ["out_of_range"]},
+                "humidity:0": {"id": 0, "rh": 41.2},
+                "devicepower:0": {"id": 0, "battery": {"percent": 80}}
+            }
+        }"#;
+        let msg = parse_message(json).unwrap();
+        metrics.update_from_message(&msg, Some("mostert/shelly/temp-main/events/rpc"));
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        assert!(!buffer.contains("shelly_temperature_celsius{"));
+        assert!(!buffer.contains("shelly_battery_voltage{"));
+        assert!(buffer.contains("shelly_humidity_percent{device=\"temp-main\",id=\"0\"} 41.2\n"));
+        assert!(buffer.contains("shelly_battery_percent{device=\"temp-main\"} 80.0\n"));
+    }
+
+    #[test]
+    fn test_battery_low() {
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            battery_low_percent: Some(20.0),
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+        let series = "shelly_battery_low{device=\"temp-main\"}";
+
+        let update = |percent: f64| {
+            let json = format!(
+                r#"{{"src": "shellyhtg3-3030f9e7d294", "method": "NotifyStatus", "params": {{"devicepower:0": {{"id": 0, "battery": {{"percent": {}}}}}}}}}"#,
+                percent
+            );
+            let msg = parse_message(&json).unwrap();
+            metrics.update_from_message(&msg, Some("mostert/shelly/temp-main/events/rpc"));
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            buffer
+        };
+
+        assert!(update(21.0).contains(&format!("{} 0.0\n", series)));
+        assert!(update(20.0).contains(&format!("{} 0.0\n", series)));
+        assert!(update(19.0).contains(&format!("{} 1.0\n", series)));
+        assert!(update(35.0).contains(&format!("{} 0.0\n", series)));
+    }
+
+    #[test]
+    fn test_battery_voltage_percent_mismatch() {
+        assert_eq!(battery_percent_from_voltage(3.5), 0.0);
+        assert_eq!(battery_percent_from_voltage(5.0), 45.0);
+        assert_eq!(battery_percent_from_voltage(6.4), 100.0);
+
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            battery_mismatch_percent: Some(25.0),
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+        let series = "shelly_battery_voltage_percent_mismatch{device=\"temp-main\"}";
+
+        let update = |volts: f64, percent: f64| {
+            let json = format!(
+                r#"{{"src": "shellyhtg3-3030f9e7d294", "method": "NotifyStatus", "params": {{"devicepower:0": {{"id": 0, "battery": {{"V": {}, "percent": {}}}}}}}}}"#,
+                volts, percent
+            );
+            let msg = parse_message(&json).unwrap();
+            metrics.update_from_message(&msg, Some("mostert/shelly/temp-main/events/rpc"));
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            buffer
+        };
+
+        // A nearly empty pack reporting 90%
+        assert!(update(4.3, 90.0).contains(&format!("{} 1.0\n", series)));
+        assert!(update(5.8, 90.0).contains(&format!("{} 0.0\n", series)));
+    }
+
+    #[test]
+    fn test_last_seen_timestamp() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        let json =
+            r#"{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {}}"#;
+        let msg = parse_message(json).unwrap();
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        metrics.update_from_message_at(&msg, Some("mostert/shelly/plugcoffee/events/rpc"), now);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer
+            .contains("shelly_last_seen_timestamp_seconds{device=\"plugcoffee\"} 1700000000.0\n"));
+    }
+
+    #[test]
+    fn test_name_precedence() {
+        let json = r#"{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {"switch:0": {"apower": 7.5}}}"#;
+        let msg = parse_message(json).unwrap();
+        let topic = Some("mostert/shelly/plugcoffee/events/rpc");
+        let device = |precedence: Vec<NameSource>| {
+            let mut registry = Registry::default();
+            let options = MetricsOptions {
+                device_aliases: vec!["d48afc781ad8=espresso".parse().unwrap()],
+                name_precedence: precedence,
+                ..Default::default()
+            };
+            ShellyMetrics::with_options(&mut registry, &options).device_id(&msg, topic)
+        };
+
+        // The default puts the alias before the topic
+        assert_eq!(device(vec![]), "espresso");
+        assert_eq!(
+            device(vec![NameSource::Topic, NameSource::Alias]),
+            "plugcoffee"
+        );
+        assert_eq!(
+            device(vec![NameSource::Mac, NameSource::Topic]),
+            "d48afc781ad8"
+        );
+
+        // Without a topic the next source is used
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            name_precedence: vec![NameSource::Topic, NameSource::Alias],
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+        assert_eq!(metrics.device_id(&msg, None), "d48afc781ad8");
+    }
+
+    #[test]
+    fn test_device_alias_fallback() {
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            device_aliases: vec!["d48afc781ad8=espresso".parse().unwrap()],
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+        let device = |src: &str| {
+            let json = format!(
+                r#"{{"src": "{}", "method": "NotifyStatus", "params": {{}}}}"#,
+                src
+            );
+            metrics.device_id(&parse_message(&json).unwrap(), None)
+        };
+
+        // Topics without a name fall back to the alias, then the raw MAC
+        assert_eq!(device("shellyplugus-d48afc781ad8"), "espresso");
+        assert_eq!(device("shellyplugus-e8db84d2c1f0"), "e8db84d2c1f0");
+    }
+
+    #[test]
+    fn test_per_component_freshness() {
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            per_component_freshness: true,
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+        let topic = Some("mostert/shelly/plugcoffee/events/rpc");
+
+        let full = r#"{"src": "shellyplugus-d48afc781ad8", "method": "NotifyFullStatus", "params": {
+            "switch:0": {"id": 0, "apower": 7.5}, "wifi": {"rssi": -60}}}"#;
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        metrics.update_from_message_at(&parse_message(full).unwrap(), topic, now);
+
+        // Only wifi is reported again, so the switch data goes stale
+        let wifi = r#"{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {"wifi": {"rssi": -58}}}"#;
+        let later = now + Duration::from_secs(300);
+        metrics.update_from_message_at(&parse_message(wifi).unwrap(), topic, later);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        let series = "shelly_component_last_seen_timestamp_seconds{device=\"plugcoffee\"";
+        assert!(buffer.contains(&format!(
+            "{},component=\"switch:0\"}} 1700000000.0\n",
+            series
+        )));
+        assert!(buffer.contains(&format!("{},component=\"wifi\"}} 1700000300.0\n", series)));
+        assert_eq!(buffer.matches(series).count(), 2);
+    }
+
+    #[test]
+    fn test_energy_stalled() {
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            energy_stall_updates: Some(3),
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+        let series = "shelly_switch_energy_stalled{device=\"fridge\",switch=\"0\"}";
+
+        let update = |apower: f64, total: f64| {
+            let json = format!(
+                r#"{{"src": "shellyplugus-a", "method": "NotifyStatus", "params": {{"switch:0": {{"apower": {}, "aenergy": {{"total": {}}}}}}}}}"#,
+                apower, total
+            );
+            let msg = parse_message(&json).unwrap();
+            metrics.update_from_message(&msg, Some("mostert/shelly/fridge/events/rpc"));
+
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            buffer
+        };
+
+        assert!(update(80.0, 100.0).contains(&format!("{} 0.0\n", series)));
+        assert!(update(80.0, 100.0).contains(&format!("{} 0.0\n", series)));
+        assert!(update(80.0, 100.0).contains(&format!("{} 0.0\n", series)));
+        // Third update under load without progress
+        assert!(update(80.0, 100.0).contains(&format!("{} 1.0\n", series)));
+        // Advancing again clears the flag
+        assert!(update(80.0, 101.2).contains(&format!("{} 0.0\n", series)));
+        // An idle load is not stalled
+        for _ in 0..4 {
+            assert!(update(0.0, 101.2).contains(&format!("{} 0.0\n", series)));
+        }
+    }
+
+    #[test]
+    fn test_temperature_unit() {
+        let switch = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyStatus",
+            "params": {"switch:0": {"id": 0, "temperature": {"tC": 37.9, "tF": 100.1}}}
+        }"#;
+        let encoded = |temperature_unit| {
+            let mut registry = Registry::default();
+            let options = MetricsOptions {
+                temperature_unit,
+                ..Default::default()
+            };
+            let metrics = ShellyMetrics::with_options(&mut registry, &options);
+            metrics.update_from_message(
+                &parse_message(HT_FULL_STATUS).unwrap(),
+                Some("mostert/shelly/temp-main/events/rpc"),
+            );
+            metrics.update_from_message(
+                &parse_message(switch).unwrap(),
+                Some("mostert/shelly/plugcoffee/events/rpc"),
+            );
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            buffer
+        };
+        let celsius = [
+            "shelly_temperature_celsius{device=\"temp-main\",id=\"0\"} 18.0\n",
+            "shelly_temperature_celsius{device=\"plugcoffee\",id=\"0\"} 37.9\n",
+        ];
+        let fahrenheit = [
+            "shelly_temperature_fahrenheit{device=\"temp-main\",id=\"0\"} 64.5\n",
+            "shelly_temperature_fahrenheit{device=\"plugcoffee\",id=\"0\"} 100.1\n",
+        ];
+
+        let buffer = encoded(TemperatureUnit::Celsius);
+        assert!(celsius.iter().all(|series| buffer.contains(series)));
+        assert!(!buffer.contains("shelly_temperature_fahrenheit"));
+
+        let buffer = encoded(TemperatureUnit::Fahrenheit);
+        assert!(fahrenheit.iter().all(|series| buffer.contains(series)));
+        assert!(!buffer.contains("shelly_temperature_celsius"));
+
+        let buffer = encoded(TemperatureUnit::Both);
+        assert!(celsius.iter().all(|series| buffer.contains(series)));
+        assert!(fahrenheit.iter().all(|series| buffer.contains(series)));
+    }
+
+    #[test]
+    fn test_disabled_metrics() {
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            disabled_metrics: vec!["voltage".to_string(), "bogus".to_string()],
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyStatus",
+            "params": {"switch:0": {"id": 0, "apower": 125.5, "voltage": 120.1}}
+        }"#;
+        metrics.update_from_message(&parse_message(json).unwrap(), None);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer
+            .contains("shelly_switch_power_watts{device=\"d48afc781ad8\",switch=\"0\"} 125.5\n"));
+        assert!(!buffer.contains("shelly_switch_voltage_volts"));
+        assert!(!buffer.contains("shelly_pm1_voltage_volts"));
+        assert!(!metrics
+            .descriptors()
+            .iter()
+            .any(|descriptor| descriptor.name.contains("voltage_volts")));
+        // A disabled family never records a series, so it has nothing to prune
+        assert_eq!(metrics.shard("d48afc781ad8").voltage.value.len(), 0);
+    }
+
+    #[test]
+    fn test_disables() {
+        let name = "shelly_switch_voltage_volts";
+        for entry in ["voltage", "switch_voltage", "switch_voltage_volts", name] {
+            assert!(disables(entry, name, "volts"), "{}", entry);
+        }
+        for entry in ["age", "volts", "pm1_voltage", ""] {
+            assert!(!disables(entry, name, "volts"), "{}", entry);
+        }
+        assert!(disables("wifi_rssi", "shelly_wifi_rssi_dbm", "dbm"));
+        assert!(!disables("power", "shelly_external_power_present", "bool"));
+        // Exporter self-monitoring families cannot be disabled
+        assert!(!disables(
+            "reconnects",
+            "mqtt2prom_mqtt_reconnects",
+            "reconnects"
+        ));
+    }
+
+    #[test]
+    fn test_switch_overtemp() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+        let series = "shelly_switch_overtemp{device=\"heater\",switch=\"0\"}";
+
+        let update = |method: &str, switch: &str| {
+            let json = format!(
+                r#"{{"src": "shellyplus1pm-a", "method": "{}", "params": {{"switch:0": {}}}}}"#,
+                method, switch
+            );
+            let msg = parse_message(&json).unwrap();
+            metrics.update_from_message(&msg, Some("mostert/shelly/heater/events/rpc"));
+
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            buffer
+        };
+
+        assert!(update(
+            "NotifyStatus",
+            r#"{"output": false, "errors": ["overtemp"]}"#
+        )
+        .contains(&format!("{} 1.0\n", series)));
+        // A partial update without `errors` keeps the flag
+        assert!(update("NotifyStatus", r#"{"apower": 0.0}"#).contains(&format!("{} 1.0\n", series)));
+        // A full status without `errors` clears it
+        assert!(update("NotifyFullStatus", r#"{"output": true}"#)
+            .contains(&format!("{} 0.0\n", series)));
+        assert!(update("NotifyStatus", r#"{"errors": ["overpower"]}"#)
+            .contains(&format!("{} 0.0\n", series)));
+    }
+
+    #[test]
+    fn test_device_info() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+        let topic = Some("mostert/shelly/plugcoffee/events/rpc");
+
+        let full_status = |fw_id: &str| {
+            let json = format!(
+                r#"{{"src": "shellyplugus-d48afc781ad8", "method": "NotifyFullStatus", "params": {{"sys": {{"device": {{"model": "SNPL-00116US", "gen": 2, "fw_id": "{}"}}}}}}}}"#,
+                fw_id
+            );
+            parse_message(&json).unwrap()
+        };
+        metrics.update_from_message(&full_status("1.0.7"), topic);
+        metrics.update_from_message(&full_status("1.0.8"), topic);
+
+        // Missing firmware fields still produce a series
+        let json =
+            r#"{"src": "shellyhtg3-3030f9e7d294", "method": "NotifyFullStatus", "params": {}}"#;
+        metrics.update_from_message(
+            &parse_message(json).unwrap(),
+            Some("mostert/shelly/temp/events/rpc"),
+        );
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        assert!(buffer.contains(
+            "shelly_device_info{device=\"plugcoffee\",model=\"SNPL-00116US\",gen=\"2\",fw_id=\"1.0.8\"} 1.0\n"
+        ));
+        assert!(!buffer.contains("fw_id=\"1.0.7\""));
+        assert!(buffer
+            .contains("shelly_device_info{device=\"temp\",model=\"\",gen=\"\",fw_id=\"\"} 1.0\n"));
+    }
+
+    #[test]
+    fn test_series_count() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyStatus",
+            "params": {
+                "switch:0": {"apower": 12.5, "voltage": 120.1},
+                "switch:1": {"apower": 3.0},
+                "wifi": {"rssi": -50}
+            }
+        }"#;
+        metrics.update_from_message(
+            &parse_message(json).unwrap(),
+            Some("mostert/shelly/plug/events/rpc"),
+        );
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        let encoded_series = buffer.lines().filter(|line| !line.starts_with('#')).count();
+        // Two powers, a voltage, the rssi and the last seen timestamp
+        assert_eq!(metrics.series_count(), 5);
+        assert_eq!(metrics.series_count(), encoded_series);
+
+        let exporter_metrics = ExporterMetrics::new(&mut registry);
+        exporter_metrics.observe_process(&metrics);
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("mqtt2prom_series_total 5\n"));
+    }
+
+    #[test]
+    fn test_build_info() {
+        let mut registry = Registry::default();
+        register_build_info(&mut registry);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        let version = format!("version=\"{}\"", env!("CARGO_PKG_VERSION"));
+        let series = buffer
+            .lines()
+            .find(|line| line.starts_with("mqtt2prom_build_info{"))
+            .unwrap();
+        assert!(series.contains(&version), "{}", series);
+        assert!(series.contains("commit=\""), "{}", series);
+        assert!(series.ends_with("} 1.0"), "{}", series);
+    }
+
+    #[test]
+    fn test_site_from_topic_segment() {
+        let json = r#"{"src": "shellyplugus-c049ef8b3a44", "method": "NotifyStatus", "params": {"switch:0": {"apower": 12.5}}}"#;
+        let msg = parse_message(json).unwrap();
+
+        for (enabled, expected) in [
+            (
+                true,
+                [
+                    "shelly_switch_power_watts{site=\"site-a\",device=\"plugcoffee\",switch=\"0\"} 12.5\n",
+                    "shelly_switch_power_watts{site=\"site-b\",device=\"plugdesk\",switch=\"0\"} 12.5\n",
+                ],
+            ),
+            (
+                false,
+                [
+                    "shelly_switch_power_watts{device=\"plugcoffee\",switch=\"0\"} 12.5\n",
+                    "shelly_switch_power_watts{device=\"plugdesk\",switch=\"0\"} 12.5\n",
+                ],
+            ),
+        ] {
+            let mut registry = Registry::default();
+            let options = MetricsOptions {
+                site_from_topic_segment: enabled,
+                ..Default::default()
+            };
+            let metrics = ShellyMetrics::with_options(&mut registry, &options);
+            metrics.update_from_message(&msg, Some("site-a/shelly/plugcoffee/events/rpc"));
+            metrics.update_from_message(&msg, Some("site-b/shelly/plugdesk/events/rpc"));
+
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            for series in expected {
+                assert!(buffer.contains(series), "{}", buffer);
+            }
+        }
+    }
+
+    #[test]
+    fn test_metric_prefix() {
+        let json = r#"{"src": "shellyplugus-c049ef8b3a44", "method": "NotifyStatus", "params": {"switch:0": {"apower": 12.5}}}"#;
+        let msg = parse_message(json).unwrap();
+
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            metric_prefix: Some("home_shelly".to_string()),
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+        metrics.update_from_message(&msg, Some("mostert/shelly/plugcoffee/events/rpc"));
+        ExporterMetrics::new(&mut registry);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer
+            .contains("home_shelly_switch_power_watts{device=\"plugcoffee\",switch=\"0\"} 12.5\n"));
+        assert!(!buffer.contains("\nshelly_"));
+        // Exporter self-metrics keep their own prefix
+        assert!(buffer.contains("mqtt2prom_messages_received_total 0\n"));
+        assert!(!metrics
+            .descriptors()
+            .iter()
+            .any(|d| d.name.starts_with("shelly_")));
+
+        assert!(validate_metric_prefix("_shelly2").is_ok());
+        assert!(validate_metric_prefix("2shelly").is_err());
+        assert!(validate_metric_prefix("shelly-home").is_err());
+        assert!(validate_metric_prefix("").is_err());
+    }
+
+    #[test]
+    fn test_broker_label() {
+        let json = r#"{
+            "src": "shellyplugus-c049ef8b3a44",
+            "method": "NotifyStatus",
+            "params": {"switch:0": {"id": 0, "apower": 12.5}}
+        }"#;
+        let msg = parse_message(json).unwrap();
+        let topic = Some("mostert/shelly/plugcoffee/events/rpc");
+
+        let mut registry = Registry::default();
+        let options = MetricsOptions {
+            broker_label: Some("broker-a:1883".to_string()),
+            ..Default::default()
+        };
+        ShellyMetrics::with_options(&mut registry, &options).update_from_message(&msg, topic);
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains(
+            "shelly_switch_power_watts{broker=\"broker-a:1883\",device=\"plugcoffee\",switch=\"0\"} 12.5\n"
+        ));
+
+        let mut registry = Registry::default();
+        ShellyMetrics::new(&mut registry).update_from_message(&msg, topic);
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(!buffer.contains("broker="));
+    }
+
+    #[test]
+    fn test_multiple_humidity_sensors() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        let json = r#"{
+            "src": "shellyplusaddon-a1b2c3d4e5f6",
+            "method": "NotifyStatus",
+            "params": {
+                "humidity:100": {"id": 100, "rh": 45.1},
+                "humidity:101": {"id": 101, "rh": 62.8}
+            }
+        }"#;
+        metrics.update_from_message(
+            &parse_message(json).unwrap(),
+            Some("mostert/shelly/cellar/events/rpc"),
+        );
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        assert!(buffer.contains("shelly_humidity_percent{device=\"cellar\",id=\"100\"} 45.1\n"));
+        assert!(buffer.contains("shelly_humidity_percent{device=\"cellar\",id=\"101\"} 62.8\n"));
+    }
+
+    #[test]
+    fn test_light_colors() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        let json = r#"{
+            "src": "shellyprorgbwwpm-ac1518783c4c",
+            "method": "NotifyStatus",
+            "params": {
+                "cct:0": {"id": 0, "output": true, "brightness": 80, "ct": 4000},
+                "rgbw:1": {"id": 1, "output": true, "rgb": [255, 120, 0], "white": 40}
+            }
+        }"#;
+        metrics.update_from_message(
+            &parse_message(json).unwrap(),
+            Some("mostert/shelly/kitchen/events/rpc"),
+        );
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer
+            .contains("shelly_light_color_temp_kelvin{device=\"kitchen\",channel=\"0\"} 4000.0\n"));
+        assert!(buffer.contains(
+            "shelly_light_color_intensity{device=\"kitchen\",channel=\"1\",color=\"red\"} 255.0\n"
+        ));
+        assert!(buffer.contains(
+            "shelly_light_color_intensity{device=\"kitchen\",channel=\"1\",color=\"white\"} 40.0\n"
+        ));
+        assert_eq!(buffer.matches("shelly_light_color_intensity{").count(), 4);
+    }
+
+    #[test]
+    fn test_energy_total_counter() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+        let topic = Some("mostert/shelly/plugcoffee/events/rpc");
+
+        for total in ["3949.949", "3950.5"] {
+            let json = format!(
+                r#"{{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {{"switch:0": {{"aenergy": {{"total": {}}}}}}}}}"#,
+                total
+            );
+            metrics.update_from_message(&parse_message(&json).unwrap(), topic);
+        }
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("# TYPE shelly_switch_energy_total_wh counter\n"));
+        assert!(buffer.contains(
+            "shelly_switch_energy_total_wh_total{device=\"plugcoffee\",switch=\"0\"} 3950.5\n"
+        ));
+        assert!(metrics_list().contains("shelly_switch_energy_total_wh_total\tcounter\t"));
+    }
+
+    #[test]
+    fn test_switch_returned_energy() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        let json = r#"{
+            "src": "shellypro1pm-30c6f782d1a4",
+            "method": "NotifyStatus",
+            "params": {
+                "switch:0": {"id": 0, "aenergy": {"total": 1200.5}, "ret_aenergy": {"total": 310.25}},
+                "switch:1": {"id": 1, "aenergy": {"total": 42.0}}
+            }
+        }"#;
+        metrics.update_from_message(
+            &parse_message(json).unwrap(),
+            Some("mostert/shelly/solar/events/rpc"),
+        );
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer
+            .contains("shelly_switch_energy_returned_wh{device=\"solar\",switch=\"0\"} 310.25\n"));
+        assert_eq!(
+            buffer.matches("shelly_switch_energy_returned_wh{").count(),
+            1
+        );
+        // The consumed total is unaffected
+        assert!(buffer.contains(
+            "shelly_switch_energy_total_wh_total{device=\"solar\",switch=\"0\"} 1200.5\n"
+        ));
+        assert!(buffer
+            .contains("shelly_switch_energy_total_wh_total{device=\"solar\",switch=\"1\"} 42.0\n"));
+    }
+
+    #[test]
+    fn test_switch_power_factor_and_frequency() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        let json = r#"{
+            "src": "shellypro1pm-30c6f782d1a4",
+            "method": "NotifyStatus",
+            "params": {
+                "switch:0": {"id": 0, "apower": 230.1, "pf": 0.93, "freq": 50.02},
+                "switch:1": {"id": 1, "apower": 12.0}
+            }
+        }"#;
+        metrics.update_from_message(
+            &parse_message(json).unwrap(),
+            Some("mostert/shelly/heatpump/events/rpc"),
+        );
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(
+            buffer.contains("shelly_switch_power_factor{device=\"heatpump\",switch=\"0\"} 0.93\n")
+        );
+        assert!(
+            buffer.contains("shelly_switch_frequency_hz{device=\"heatpump\",switch=\"0\"} 50.02\n")
+        );
+        assert_eq!(buffer.matches("shelly_switch_power_factor{").count(), 1);
+        assert_eq!(buffer.matches("shelly_switch_frequency_hz{").count(), 1);
+    }
+
+    #[test]
+    fn test_alarm_sensors() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        let json = r#"{
+            "src": "shellyplus1-a8032ab12345",
+            "method": "NotifyStatus",
+            "params": {"flood:0": {"id": 0, "alarm": true}}
+        }"#;
+        metrics.update_from_message(
+            &parse_message(json).unwrap(),
+            Some("mostert/shelly/basement/events/rpc"),
+        );
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("shelly_flood_alarm{device=\"basement\"} 1.0\n"));
+        // Components that are absent get no series
+        assert!(!buffer.contains("shelly_smoke_alarm{"));
+        assert!(!buffer.contains("shelly_motion_detected{"));
+    }
+
+    #[test]
+    fn test_illuminance() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        let json = r#"{
+            "src": "shellyblumotion-a8032ab12345",
+            "method": "NotifyStatus",
+            "params": {"illuminance:0": {"id": 0, "lux": 123.4}}
+        }"#;
+        metrics.update_from_message(
+            &parse_message(json).unwrap(),
+            Some("mostert/shelly/hallway/events/rpc"),
+        );
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("shelly_illuminance_lux{device=\"hallway\"} 123.4\n"));
+    }
+
+    #[test]
+    fn test_wifi_info() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+        let update = |wifi: &str| {
+            let json = format!(
+                r#"{{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {{"wifi": {}}}}}"#,
+                wifi
+            );
+            metrics.update_from_message(
+                &parse_message(&json).unwrap(),
+                Some("mostert/shelly/plugcoffee/events/rpc"),
+            );
+        };
+
+        update(r#"{"ssid": "attic", "rssi": -61}"#);
+        update(r#"{"ssid": "garage", "rssi": -55}"#);
+        // RSSI-only updates leave the info series alone
+        update(r#"{"rssi": -58}"#);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("shelly_wifi_info{device=\"plugcoffee\",ssid=\"garage\"} 1.0\n"));
+        assert_eq!(buffer.matches("shelly_wifi_info{").count(), 1);
+        assert!(buffer.contains("shelly_wifi_rssi_dbm{device=\"plugcoffee\"} -58"));
+    }
+
+    #[test]
+    fn test_update_available() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+        let update = |device: &str, method: &str, sys: &str| {
+            let json = format!(
+                r#"{{"src": "shellyplugus-{}", "method": "{}", "params": {{"sys": {}}}}}"#,
+                device, method, sys
+            );
+            metrics.update_from_message(&parse_message(&json).unwrap(), None);
+        };
 
-        registry.register(
-            "shelly_switch_energy_total_wh",
-            "Total energy consumed in watt-hours",
-            energy_total.clone(),
+        update(
+            "d48afc781ad8",
+            "NotifyFullStatus",
+            r#"{"available_updates": {"stable": {"version": "1.4.4"}}}"#,
         );
-
-        registry.register(
-            "shelly_switch_state",
-            "Switch output state (0=off, 1=on)",
-            switch_state.clone(),
+        // A partial update without available_updates keeps the last value
+        update("d48afc781ad8", "NotifyStatus", r#"{"uptime": 300}"#);
+        update(
+            "e8db84d2c1f0",
+            "NotifyFullStatus",
+            r#"{"available_updates": {}}"#,
         );
 
-        registry.register(
-            "shelly_temperature_celsius",
-            "Device temperature in celsius",
-            temperature.clone(),
-        );
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("shelly_update_available{device=\"d48afc781ad8\"} 1.0\n"));
+        assert!(buffer.contains("shelly_update_available{device=\"e8db84d2c1f0\"} 0.0\n"));
+    }
 
-        registry.register(
-            "shelly_humidity_percent",
-            "Relative humidity percentage",
-            humidity.clone(),
-        );
+    #[test]
+    fn test_sys_memory() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
 
-        registry.register(
-            "shelly_battery_percent",
-            "Battery charge percentage",
-            battery_percent.clone(),
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyStatus",
+            "params": {"sys": {"ram_size": 260764, "ram_free": 113436, "fs_size": 458752}}
+        }"#;
+        metrics.update_from_message(
+            &parse_message(json).unwrap(),
+            Some("mostert/shelly/plugcoffee/events/rpc"),
         );
 
-        registry.register(
-            "shelly_battery_voltage",
-            "Battery voltage in volts",
-            battery_voltage.clone(),
-        );
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("shelly_ram_free_bytes{device=\"plugcoffee\"} 113436.0\n"));
+        assert!(buffer.contains("shelly_ram_size_bytes{device=\"plugcoffee\"} 260764.0\n"));
+        assert!(buffer.contains("shelly_fs_size_bytes{device=\"plugcoffee\"} 458752.0\n"));
+        // Fields the device did not report get no series
+        assert!(!buffer.contains("shelly_fs_free_bytes{"));
+    }
+
+    #[test]
+    fn test_light() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
 
-        registry.register(
-            "shelly_wifi_rssi_dbm",
-            "WiFi signal strength in dBm",
-            wifi_rssi.clone(),
+        let json = r#"{
+            "src": "shellyplusdimmer-a8032ab12345",
+            "method": "NotifyStatus",
+            "params": {"light:0": {"id":0,"output":true,"brightness":75}}
+        }"#;
+        metrics.update_from_message(
+            &parse_message(json).unwrap(),
+            Some("mostert/shelly/lounge/events/rpc"),
         );
 
-        Self {
-            power,
-            voltage,
-            current,
-            energy_total,
-            switch_state,
-            temperature,
-            humidity,
-            battery_percent,
-            battery_voltage,
-            wifi_rssi,
-        }
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("shelly_light_on{device=\"lounge\",light=\"0\"} 1.0\n"));
+        assert!(buffer
+            .contains("shelly_light_brightness_percent{device=\"lounge\",light=\"0\"} 75.0\n"));
     }
 
-    pub fn update_from_message(&self, msg: &ShellyMessage, topic: Option<&str>) {
-        // Use topic-derived device name if available, otherwise fall back to MAC
-        let device_id = topic
-            .and_then(extract_device_from_topic)
-            .unwrap_or_else(|| extract_device_id(&msg.src));
+    #[test]
+    fn test_cover() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+        let update = |cover: &str| {
+            let json = format!(
+                r#"{{"src": "shellypro2pm-ec62608a1b2c", "method": "NotifyStatus", "params": {{"cover:0": {}}}}}"#,
+                cover
+            );
+            metrics.update_from_message(
+                &parse_message(&json).unwrap(),
+                Some("mostert/shelly/blinds/events/rpc"),
+            );
+        };
 
-        if let Some(switch) = &msg.params.switch {
-            let switch_id = switch.id.to_string();
+        update(r#"{"id": 0, "state": "closing", "current_pos": 80, "apower": 95.2}"#);
+        update(r#"{"id": 0, "state": "stopped", "current_pos": 42, "apower": 0.0}"#);
 
-            let labels = DeviceLabels {
-                device: device_id.clone(),
-                switch: switch_id,
-            };
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer
+            .contains("shelly_cover_position_percent{device=\"blinds\",channel=\"0\"} 42.0\n"));
+        assert!(buffer.contains("shelly_cover_power_watts{device=\"blinds\",channel=\"0\"} 0.0\n"));
+        // A state change replaces the previous state series
+        assert!(buffer.contains(
+            "shelly_cover_state{device=\"blinds\",channel=\"0\",state=\"stopped\"} 1.0\n"
+        ));
+        assert_eq!(buffer.matches("shelly_cover_state{").count(), 1);
+    }
 
-            // Update power if present
-            if let Some(apower) = switch.apower {
-                self.power.get_or_create(&labels).set(apower as i64);
-            }
+    #[test]
+    fn test_battery_powered() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
 
-            // Update voltage if present
-            if let Some(voltage) = switch.voltage {
-                self.voltage
-                    .get_or_create(&labels)
-                    .set((voltage * 10.0) as i64);
+        let ht = r#"{
+            "src": "shellyhtg3-3030f9e7d294",
+            "method": "NotifyFullStatus",
+            "params": {
+                "temperature:0": {"id": 0, "tC": 18.0, "tF": 64.5},
+                "devicepower:0": {"id": 0, "battery": {"V": 5.2, "percent": 80}, "external": {"present": false}}
             }
+        }"#;
+        metrics.update_from_message(
+            &parse_message(ht).unwrap(),
+            Some("mostert/shelly/temp-main/events/rpc"),
+        );
+        let plug = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyFullStatus",
+            "params": {"switch:0": {"id": 0, "output": true, "apower": 7.5}}
+        }"#;
+        metrics.update_from_message(
+            &parse_message(plug).unwrap(),
+            Some("mostert/shelly/plugcoffee/events/rpc"),
+        );
+        // A partial update without devicepower leaves the gauge alone
+        let partial = r#"{
+            "src": "shellyhtg3-3030f9e7d294",
+            "method": "NotifyStatus",
+            "params": {"temperature:0": {"id": 0, "tC": 18.5, "tF": 65.3}}
+        }"#;
+        metrics.update_from_message(
+            &parse_message(partial).unwrap(),
+            Some("mostert/shelly/temp-main/events/rpc"),
+        );
 
-            // Update current if present
-            if let Some(current) = switch.current {
-                self.current
-                    .get_or_create(&labels)
-                    .set((current * 1000.0) as i64);
-            }
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("shelly_device_battery_powered{device=\"temp-main\"} 1.0\n"));
+        assert!(buffer.contains("shelly_device_battery_powered{device=\"plugcoffee\"} 0.0\n"));
+        // Switches without devicepower get no presence series
+        assert!(!buffer.contains("shelly_external_power_present{device=\"plugcoffee\"}"));
+    }
 
-            // Update energy total if present
-            if let Some(aenergy) = &switch.aenergy {
-                self.energy_total
-                    .get_or_create(&labels)
-                    .set((aenergy.total * 10.0) as i64);
-            }
+    #[test]
+    fn test_multiple_temperature_probes() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
 
-            // Update switch state if present
-            if let Some(output) = switch.output {
-                self.switch_state
-                    .get_or_create(&labels)
-                    .set(if output { 1 } else { 0 });
+        let json = r#"{
+            "src": "shellyplusaddon-a1b2c3d4e5f6",
+            "method": "NotifyStatus",
+            "params": {
+                "temperature:0": {"id": 0, "tC": 19.5, "tF": 67.1},
+                "temperature:1": {"id": 1, "tC": 4.25, "tF": 39.7}
             }
+        }"#;
+        let msg = parse_message(json).unwrap();
+        assert_eq!(msg.params.temperature.len(), 2);
+        metrics.update_from_message(&msg, Some("mostert/shelly/cellar/events/rpc"));
 
-            // Update temperature if present
-            if let Some(temp) = &switch.temperature {
-                let device_labels = DeviceOnlyLabels {
-                    device: device_id.clone(),
-                };
-                self.temperature
-                    .get_or_create(&device_labels)
-                    .set((temp.tc * 10.0) as i64);
-            }
-        }
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
 
-        // Update temperature from H&T sensor (temperature:0)
-        if let Some(temp) = &msg.params.temperature {
-            let device_labels = DeviceOnlyLabels {
-                device: device_id.clone(),
-            };
-            self.temperature
-                .get_or_create(&device_labels)
-                .set((temp.tc * 10.0) as i64);
-        }
+        assert!(buffer.contains("shelly_temperature_celsius{device=\"cellar\",id=\"0\"} 19.5\n"));
+        assert!(buffer.contains("shelly_temperature_celsius{device=\"cellar\",id=\"1\"} 4.25\n"));
+        // No probe reported temperature:2 or humidity
+        assert!(!buffer.contains("id=\"2\""));
+        assert!(!buffer.contains("shelly_humidity_percent{"));
+    }
 
-        // Update humidity from H&T sensor (humidity:0)
-        if let Some(humidity) = &msg.params.humidity {
-            let device_labels = DeviceOnlyLabels {
-                device: device_id.clone(),
-            };
-            self.humidity
-                .get_or_create(&device_labels)
-                .set((humidity.rh * 10.0) as i64);
-        }
+    #[test]
+    fn test_multi_channel_switches() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
 
-        // Update battery from device power (devicepower:0)
-        if let Some(devicepower) = &msg.params.devicepower {
-            if let Some(battery) = &devicepower.battery {
-                let device_labels = DeviceOnlyLabels {
-                    device: device_id.clone(),
-                };
-                self.battery_percent
-                    .get_or_create(&device_labels)
-                    .set(battery.percent as i64);
-                self.battery_voltage
-                    .get_or_create(&device_labels)
-                    .set((battery.voltage * 100.0) as i64);
+        let json = r#"{
+            "src": "shellypro4pm-a8032ab12345",
+            "method": "NotifyStatus",
+            "params": {
+                "switch:0": {"id": 0, "apower": 10.0},
+                "switch:1": {"id": 1, "apower": 11.0},
+                "switch:2": {"id": 2, "apower": 12.0},
+                "switch:3": {"apower": 13.0}
             }
-        }
+        }"#;
 
-        // Update WiFi RSSI if present
-        if let Some(wifi) = &msg.params.wifi {
-            let device_labels = DeviceOnlyLabels {
-                device: device_id.clone(),
-            };
-            self.wifi_rssi
-                .get_or_create(&device_labels)
-                .set(wifi.rssi as i64);
+        let msg = parse_message(json).unwrap();
+        metrics.update_from_message(&msg, Some("mostert/shelly/pro4pm/events/rpc"));
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        for channel in 0..4 {
+            assert!(buffer.contains(&format!(
+                "shelly_switch_power_watts{{device=\"pro4pm\",switch=\"{}\"}} {}.0\n",
+                channel,
+                10 + channel
+            )));
         }
+        assert_eq!(buffer.matches("shelly_switch_power_watts{").count(), 4);
     }
 
-    #[allow(dead_code)]
-    pub fn update_power(&self, device: &str, switch: &str, watts: f64) {
-        let labels = DeviceLabels {
-            device: device.to_string(),
-            switch: switch.to_string(),
-        };
-        self.power.get_or_create(&labels).set(watts as i64);
-    }
+    #[test]
+    fn test_em_single_phase() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
 
-    #[allow(dead_code)]
-    pub fn update_voltage(&self, device: &str, switch: &str, volts: f64) {
-        let labels = DeviceLabels {
-            device: device.to_string(),
-            switch: switch.to_string(),
-        };
-        self.voltage
-            .get_or_create(&labels)
-            .set((volts * 10.0) as i64);
-    }
+        let json = r#"{
+            "src": "shellypro3em-0cb815fcaff4",
+            "method": "NotifyStatus",
+            "params": {
+                "em:0": {"id": 0, "a_act_power": 199.3, "a_voltage": 231.2, "total_act_power": 199.3}
+            }
+        }"#;
 
-    #[allow(dead_code)]
-    pub fn update_current(&self, device: &str, switch: &str, amps: f64) {
-        let labels = DeviceLabels {
-            device: device.to_string(),
-            switch: switch.to_string(),
-        };
-        self.current
-            .get_or_create(&labels)
-            .set((amps * 1000.0) as i64);
-    }
+        let msg = parse_message(json).unwrap();
+        metrics.update_from_message(&msg, Some("mostert/shelly/mains/events/rpc"));
 
-    #[allow(dead_code)]
-    pub fn update_energy(&self, device: &str, switch: &str, wh: f64) {
-        let labels = DeviceLabels {
-            device: device.to_string(),
-            switch: switch.to_string(),
-        };
-        self.energy_total
-            .get_or_create(&labels)
-            .set((wh * 10.0) as i64);
-    }
-}
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::parser::parse_message;
-    use prometheus_client::encoding::text::encode;
+        assert!(buffer.contains("shelly_em_power_watts{device=\"mains\",phase=\"a\"} 199.3\n"));
+        assert!(buffer.contains("shelly_em_voltage_volts{device=\"mains\",phase=\"a\"} 231.2\n"));
+        assert!(buffer.contains("shelly_em_total_power_watts{device=\"mains\"} 199.3\n"));
+        // Absent phases must not produce zero series
+        assert!(!buffer.contains("phase=\"b\""));
+        assert!(!buffer.contains("shelly_em_current_amps{"));
+    }
 
     #[test]
-    fn test_metrics_registration() {
+    fn test_virtual_components() {
         let mut registry = Registry::default();
-        let _metrics = ShellyMetrics::new(&mut registry);
+        let metrics = ShellyMetrics::new(&mut registry);
+
+        let json = r#"{
+            "src": "shelly1pmg3-34b7da8f1234",
+            "method": "NotifyStatus",
+            "params": {
+                "number:0": {"id": 0, "value": 21.5},
+                "boolean:200": {"id": 200, "value": true, "name": "heating"}
+            }
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        metrics.update_from_message(&msg, Some("mostert/shelly/boiler/events/rpc"));
 
         let mut buffer = String::new();
         encode(&mut buffer, &registry).unwrap();
 
-        assert!(buffer.contains("shelly_switch_power_watts"));
-        assert!(buffer.contains("shelly_switch_voltage_volts"));
-        assert!(buffer.contains("shelly_switch_current_amps"));
-        assert!(buffer.contains("shelly_switch_energy_total_wh"));
-        assert!(buffer.contains("shelly_switch_state"));
-        assert!(buffer.contains("shelly_temperature_celsius"));
-        assert!(buffer.contains("shelly_humidity_percent"));
-        assert!(buffer.contains("shelly_battery_percent"));
-        assert!(buffer.contains("shelly_battery_voltage"));
-        assert!(buffer.contains("shelly_wifi_rssi_dbm"));
+        assert!(
+            buffer.contains("shelly_virtual_number{device=\"boiler\",id=\"0\",name=\"\"} 21.5\n")
+        );
+        assert!(buffer.contains(
+            "shelly_virtual_boolean{device=\"boiler\",id=\"200\",name=\"heating\"} 1.0\n"
+        ));
     }
 
     #[test]
-    fn test_update_individual_metrics() {
+    fn test_analog_input_calibration() {
         let mut registry = Registry::default();
-        let metrics = ShellyMetrics::new(&mut registry);
+        let options = MetricsOptions {
+            input_calibration: vec!["100=0.5,-10".parse().unwrap()],
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
 
-        metrics.update_power("device1", "0", 125.5);
-        metrics.update_voltage("device1", "0", 122.3);
-        metrics.update_current("device1", "0", 1.025);
-        metrics.update_energy("device1", "0", 3949.949);
+        let json = r#"{
+            "src": "shellyplus1-a8032ab12345",
+            "method": "NotifyStatus",
+            "params": {
+                "input:100": {"id": 100, "percent": 64.0},
+                "input:101": {"id": 101, "percent": 12.5},
+                "input:0": {"id": 0, "state": false}
+            }
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        metrics.update_from_message(&msg, Some("mostert/shelly/tank/events/rpc"));
 
         let mut buffer = String::new();
         encode(&mut buffer, &registry).unwrap();
 
-        assert!(buffer.contains("device1"));
-        assert!(buffer.contains("switch=\"0\""));
+        assert!(buffer.contains("shelly_input_percent{device=\"tank\",input=\"100\"} 64.0\n"));
+        assert!(buffer.contains("shelly_input_value{device=\"tank\",input=\"100\"} 22.0\n"));
+        // Uncalibrated inputs only export the raw percent, digital inputs nothing
+        assert!(buffer.contains("shelly_input_percent{device=\"tank\",input=\"101\"} 12.5\n"));
+        assert!(!buffer.contains("shelly_input_value{device=\"tank\",input=\"101\"}"));
+        assert!(!buffer.contains("input=\"0\""));
     }
 
     #[test]
-    fn test_update_from_message() {
+    fn test_pm1_message() {
         let mut registry = Registry::default();
         let metrics = ShellyMetrics::new(&mut registry);
 
         let json = r#"{
-            "src": "shellyplugus-d48afc781ad8",
-            "method": "NotifyFullStatus",
+            "src": "shellypmminig3-84fce63bf0a0",
+            "method": "NotifyStatus",
             "params": {
-                "switch:0": {
+                "pm1:0": {
                     "id": 0,
-                    "output": true,
-                    "apower": 125.5,
-                    "voltage": 122.3,
-                    "current": 1.025,
-                    "aenergy": {"total": 3949.949},
-                    "temperature": {"tC": 37.9, "tF": 100.1}
-                },
-                "wifi": {"rssi": -40}
+                    "voltage": 121.8,
+                    "current": 0.412,
+                    "apower": 48.7,
+                    "freq": 60.0,
+                    "aenergy": {"total": 1234.5}
+                }
             }
         }"#;
 
         let msg = parse_message(json).unwrap();
-        metrics.update_from_message(&msg, Some("mostert/shelly/plugcoffee/events/rpc"));
+        metrics.update_from_message(&msg, Some("mostert/shelly/pmfridge/events/rpc"));
 
         let mut buffer = String::new();
         encode(&mut buffer, &registry).unwrap();
 
-        // Should use topic-derived name "plugcoffee" instead of MAC
-        assert!(buffer.contains("plugcoffee"));
-        assert!(buffer.contains("switch=\"0\""));
+        let labels = r#"{device="pmfridge",channel="0"}"#;
+        assert!(buffer.contains(&format!("shelly_pm1_power_watts{} 48.7\n", labels)));
+        assert!(buffer.contains(&format!("shelly_pm1_energy_total_wh{} 1234.5\n", labels)));
+        // pm1 readings must not leak into the switch families
+        assert!(!buffer.contains("shelly_switch_power_watts{"));
     }
 
     #[test]
-    fn test_multiple_devices() {
+    fn test_scale_rounding() {
+        assert_eq!(scale(1.0259, CURRENT_SCALE), 1026);
+        assert_eq!(ScalingRound::Truncate.scale(1.0259, CURRENT_SCALE), 1025);
+        // Halves round away from zero
+        assert_eq!(scale(1.25, VOLTAGE_SCALE), 13);
+        assert_eq!(scale(-1.25, VOLTAGE_SCALE), -13);
+        assert_eq!(scale(0.0004, CURRENT_SCALE), 0);
+        assert_eq!(scale(0.0005, CURRENT_SCALE), 1);
+        assert_eq!(scale(3.999, BATTERY_VOLTAGE_SCALE), 400);
+        assert_eq!(ScalingRound::Round.scale(3.999, BATTERY_VOLTAGE_SCALE), 400);
+    }
+
+    #[test]
+    fn test_legacy_scaled_metrics() {
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyFullStatus",
+            "params": {
+                "switch:0": {"id": 0, "voltage": 122.3, "current": 1.025}
+            }
+        }"#;
+        let msg = parse_message(json).unwrap();
+
         let mut registry = Registry::default();
-        let metrics = ShellyMetrics::new(&mut registry);
+        let options = MetricsOptions {
+            emit_legacy_scaled: true,
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
+        metrics.update_from_message(&msg, Some("mostert/shelly/plugcoffee/events/rpc"));
 
-        metrics.update_power("device1", "0", 100.0);
-        metrics.update_power("device2", "0", 200.0);
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        let labels = r#"{device="plugcoffee",switch="0"}"#;
+        assert!(buffer.contains(&format!(
+            "shelly_switch_voltage_volts_scaled{} 1223\n",
+            labels
+        )));
+        assert!(buffer.contains(&format!("shelly_switch_voltage_volts{} 122.3\n", labels)));
+        assert!(buffer.contains(&format!(
+            "shelly_switch_current_amps_scaled{} 1025\n",
+            labels
+        )));
+        assert!(buffer.contains(&format!("shelly_switch_current_amps{} 1.025\n", labels)));
+
+        // 122.39 V scales to 1223.9
+        let boundary = parse_message(&json.replace("122.3", "122.39")).unwrap();
+        for (round, expected) in [(ScalingRound::Truncate, 1223), (ScalingRound::Round, 1224)] {
+            let mut registry = Registry::default();
+            let options = MetricsOptions {
+                emit_legacy_scaled: true,
+                scaling_round: round,
+                ..Default::default()
+            };
+            let metrics = ShellyMetrics::with_options(&mut registry, &options);
+            metrics.update_from_message(&boundary, Some("mostert/shelly/plugcoffee/events/rpc"));
+
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            assert!(buffer.contains(&format!(
+                "shelly_switch_voltage_volts_scaled{} {}\n",
+                labels, expected
+            )));
+        }
+
+        // Without the flag only the unscaled families are registered
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+        metrics.update_from_message(&msg, Some("mostert/shelly/plugcoffee/events/rpc"));
 
         let mut buffer = String::new();
         encode(&mut buffer, &registry).unwrap();
 
-        assert!(buffer.contains("device1"));
-        assert!(buffer.contains("device2"));
+        assert!(buffer.contains(&format!("shelly_switch_voltage_volts{} 122.3\n", labels)));
+        assert!(!buffer.contains("shelly_switch_voltage_volts_scaled"));
     }
 
     #[test]
-    fn test_ht_sensor_message() {
+    fn test_device_type_inference() {
         let mut registry = Registry::default();
-        let metrics = ShellyMetrics::new(&mut registry);
+        let options = MetricsOptions {
+            infer_device_type: true,
+            ..Default::default()
+        };
+        let metrics = ShellyMetrics::with_options(&mut registry, &options);
 
+        // An H&T sensor never has switches, so a stray switch:0 is not checked
         let json = r#"{
             "src": "shellyhtg3-3030f9e7d294",
-            "dst": "mostert/shelly/temp-main/events",
             "method": "NotifyFullStatus",
             "params": {
+                "switch:0": {"id": 0, "apower": 10.0},
                 "temperature:0": {"id": 0, "tC": 18.0, "tF": 64.5},
-                "humidity:0": {"id": 0, "rh": 38.9},
-                "devicepower:0": {
-                    "id": 0,
-                    "battery": {"V": 5.41, "percent": 70},
-                    "external": {"present": false}
-                },
                 "wifi": {"rssi": -54}
             }
         }"#;
-
         let msg = parse_message(json).unwrap();
         metrics.update_from_message(&msg, Some("mostert/shelly/temp-main/events/rpc"));
 
+        // A Plus Add-on reports temperature:100 and humidity:100 on a relay
+        let json = r#"{
+            "src": "shellyplus1pm-a8032ab12345",
+            "method": "NotifyStatus",
+            "params": {
+                "switch:0": {"id": 0, "apower": 30.0},
+                "temperature:100": {"id": 100, "tC": 21.5, "tF": 70.7},
+                "humidity:100": {"id": 100, "rh": 48.0}
+            }
+        }"#;
+        let msg = parse_message(json).unwrap();
+        metrics.update_from_message(&msg, Some("mostert/shelly/relay/events/rpc"));
+
+        // Unknown models fall back to checking every component
+        let json = r#"{
+            "src": "custombridge-01",
+            "method": "NotifyStatus",
+            "params": {"switch:0": {"id": 0, "apower": 20.0}}
+        }"#;
+        let msg = parse_message(json).unwrap();
+        metrics.update_from_message(&msg, Some("mostert/shelly/bridge/events/rpc"));
+
         let mut buffer = String::new();
         encode(&mut buffer, &registry).unwrap();
 
-        // Check temperature (18.0 * 10 = 180)
-        assert!(buffer.contains("temp-main"));
-        assert!(buffer.contains("shelly_temperature_celsius"));
-        // Check humidity (38.9 * 10 = 389)
-        assert!(buffer.contains("shelly_humidity_percent"));
-        // Check battery
-        assert!(buffer.contains("shelly_battery_percent"));
-        assert!(buffer.contains("shelly_battery_voltage"));
+        assert!(buffer.contains("shelly_temperature_celsius{device=\"temp-main\",id=\"0\"} 18.0\n"));
+        assert!(buffer.contains("shelly_wifi_rssi_dbm{device=\"temp-main\"} -54.0\n"));
+        assert!(!buffer.contains("shelly_switch_power_watts{device=\"temp-main\""));
+        assert!(buffer.contains("shelly_switch_power_watts{device=\"bridge\",switch=\"0\"} 20.0\n"));
+        assert!(buffer.contains("shelly_switch_power_watts{device=\"relay\",switch=\"0\"} 30.0\n"));
+        assert!(buffer.contains("shelly_temperature_celsius{device=\"relay\",id=\"100\"} 21.5\n"));
+        assert!(buffer.contains("shelly_humidity_percent{device=\"relay\",id=\"100\"} 48.0\n"));
     }
 }
@@ -1,109 +1,572 @@
 use anyhow::{Context, Result};
-use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
-use std::sync::Arc;
-use std::time::Duration;
+use rumqttc::{
+    AsyncClient, ConnectReturnCode, ConnectionError, Event, Incoming, MqttOptions, QoS, StateError,
+    Transport,
+};
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 use tracing::{debug, error, info, warn};
 
-use crate::config::Config;
-use crate::metrics::ShellyMetrics;
-use crate::parser::{parse_message, MessageMethod};
+use crate::archive::Archive;
+use crate::config::{BrokerEndpoint, Config, MqttTransport};
+use crate::metrics::{ExporterMetrics, ShellyMetrics};
+use crate::parser::{
+    extract_device_from_topic_at, parse_gen1_message, parse_message_with, parse_online_payload,
+    MessageMethod, ParserError, ShellyMessage, DEFAULT_DEVICE_NAME_TOPIC_INDEX,
+};
+use crate::state::StateStore;
+
+/// Payloads remembered per topic for duplicate detection
+const DUPLICATE_WINDOW: usize = 8;
+
+/// Remembers hashes of the last `DUPLICATE_WINDOW` payloads of each topic to spot
+/// broker redeliveries
+#[derive(Default)]
+pub struct DuplicateTracker {
+    hasher: RandomState,
+    recent: HashMap<String, VecDeque<u64>>,
+}
+
+impl DuplicateTracker {
+    /// Record a payload, returning whether it repeats a recent one on the same topic
+    pub fn is_duplicate(&mut self, topic: &str, payload: &[u8]) -> bool {
+        let hash = self.hasher.hash_one(payload);
+        let recent = self.recent.entry(topic.to_string()).or_default();
+        if recent.contains(&hash) {
+            return true;
+        }
+        if recent.len() == DUPLICATE_WINDOW {
+            recent.pop_front();
+        }
+        recent.push_back(hash);
+        false
+    }
+}
+
+/// Fires when no message has arrived for `timeout`, catching stalls the transport misses
+pub struct MessageWatchdog {
+    timeout: Option<Duration>,
+    last_message: tokio::time::Instant,
+}
+
+impl MessageWatchdog {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            last_message: tokio::time::Instant::now(),
+        }
+    }
+
+    pub fn on_message(&mut self) {
+        self.last_message = tokio::time::Instant::now();
+    }
+
+    /// Resolves once `timeout` has passed since the last message; never when disabled
+    pub async fn expired(&self) {
+        match self.timeout {
+            Some(timeout) => tokio::time::sleep_until(self.last_message + timeout).await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Leading bytes of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Upper bound on a decompressed payload, guarding against decompression bombs
+pub const MAX_DECOMPRESSED_PAYLOAD_BYTES: u64 = 1024 * 1024;
+
+/// Decompress a gzip payload, passing anything else through unchanged
+fn decompress_payload(payload: &[u8]) -> std::io::Result<Cow<'_, [u8]>> {
+    if !payload.starts_with(&GZIP_MAGIC) {
+        return Ok(Cow::Borrowed(payload));
+    }
+    let mut decompressed = Vec::new();
+    flate2::read::GzDecoder::new(payload)
+        .take(MAX_DECOMPRESSED_PAYLOAD_BYTES + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_PAYLOAD_BYTES {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "decompressed payload exceeds {} bytes",
+                MAX_DECOMPRESSED_PAYLOAD_BYTES
+            ),
+        ));
+    }
+    Ok(Cow::Owned(decompressed))
+}
+
+/// Map a QoS level from the configuration to rumqttc's
+pub fn qos_from_level(level: u8) -> Result<QoS> {
+    match level {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        _ => anyhow::bail!("Invalid MQTT QoS level {}, expected 0, 1 or 2", level),
+    }
+}
 
 pub struct MqttHandler {
     client: AsyncClient,
     metrics: Arc<ShellyMetrics>,
+    exporter_metrics: Arc<ExporterMetrics>,
+    state: Option<Arc<StateStore>>,
+    archive: Option<Archive>,
+    qos: QoS,
+    duplicates: Mutex<DuplicateTracker>,
+    case_insensitive_topics: bool,
+    accept_compressed_payloads: bool,
+    device_name_topic_index: usize,
+    process_events: bool,
+}
+
+/// Options for `broker` over the configured transport; WebSocket transports take the
+/// broker as a `ws://` or `wss://` URL
+fn broker_options(config: &Config, broker: &BrokerEndpoint) -> MqttOptions {
+    let (scheme, transport) = match config.mqtt_transport {
+        MqttTransport::Tcp => {
+            return MqttOptions::new(&config.mqtt_client_id, &broker.host, broker.port)
+        }
+        MqttTransport::Ws => ("ws", Transport::ws()),
+        MqttTransport::Wss => ("wss", Transport::wss_with_default_config()),
+    };
+    let path = config.mqtt_ws_path.trim_start_matches('/');
+    let url = format!("{}://{}/{}", scheme, broker, path);
+    let mut mqttoptions = MqttOptions::new(&config.mqtt_client_id, url, broker.port);
+    mqttoptions.set_transport(transport);
+    mqttoptions
 }
 
 impl MqttHandler {
-    pub fn new(config: &Config, metrics: Arc<ShellyMetrics>) -> Result<(Self, rumqttc::EventLoop)> {
-        let mut mqttoptions =
-            MqttOptions::new(&config.mqtt_client_id, &config.mqtt_host, config.mqtt_port);
+    pub fn new(
+        config: &Config,
+        broker: &BrokerEndpoint,
+        metrics: Arc<ShellyMetrics>,
+        exporter_metrics: Arc<ExporterMetrics>,
+    ) -> (Self, rumqttc::EventLoop) {
+        let mut mqttoptions = broker_options(config, broker);
 
-        mqttoptions.set_credentials(&config.mqtt_username, &config.mqtt_password);
-        mqttoptions.set_keep_alive(Duration::from_secs(30));
-        mqttoptions.set_clean_session(true);
+        // A lone username or password is rejected when the config is loaded
+        match (&config.mqtt_username, &config.mqtt_password) {
+            (Some(username), Some(password)) => {
+                mqttoptions.set_credentials(username, password);
+            }
+            _ => info!("No MQTT credentials configured, connecting anonymously"),
+        }
+        mqttoptions.set_keep_alive(Duration::from_secs(config.mqtt_keep_alive_seconds));
+        mqttoptions.set_clean_session(config.mqtt_clean_session);
 
         let (client, eventloop) = AsyncClient::new(mqttoptions, 10);
 
-        Ok((Self { client, metrics }, eventloop))
+        (
+            Self {
+                client,
+                metrics,
+                exporter_metrics,
+                state: None,
+                archive: None,
+                qos: qos_from_level(config.mqtt_qos)
+                    .expect("MQTT_QOS is validated when the config is loaded"),
+                duplicates: Mutex::default(),
+                case_insensitive_topics: config.case_insensitive_topics,
+                accept_compressed_payloads: config.accept_compressed_payloads,
+                device_name_topic_index: config
+                    .device_name_topic_index
+                    .unwrap_or(DEFAULT_DEVICE_NAME_TOPIC_INDEX),
+                process_events: config.process_events,
+            },
+            eventloop,
+        )
     }
 
-    pub async fn subscribe(&self, topic: &str) -> Result<()> {
-        self.client
-            .subscribe(topic, QoS::AtMostOnce)
-            .await
-            .context("Failed to subscribe to MQTT topic")?;
+    /// Record processed messages in the given state store
+    pub fn with_state(mut self, state: Option<Arc<StateStore>>) -> Self {
+        self.state = state;
+        self
+    }
 
-        info!("Subscribed to topic: {}", topic);
+    /// Append parsed messages to the given archive
+    pub fn with_archive(mut self, archive: Option<Archive>) -> Self {
+        self.archive = archive;
+        self
+    }
+
+    pub async fn subscribe(&self, topics: &[String]) -> Result<()> {
+        for topic in topics {
+            self.client
+                .subscribe(topic, self.qos)
+                .await
+                .with_context(|| format!("Failed to subscribe to MQTT topic {}", topic))?;
+
+            info!("Subscribed to topic: {}", topic);
+        }
+        Ok(())
+    }
+
+    /// Subscribe again after the event loop reconnected on its own; the clean session
+    /// dropped the previous subscriptions
+    pub fn resubscribe(&self, topics: &[String]) -> Result<()> {
+        for topic in topics {
+            self.client
+                .try_subscribe(topic, self.qos)
+                .with_context(|| format!("Failed to resubscribe to MQTT topic {}", topic))?;
+        }
+        info!("Resubscribed to {} topics", topics.len());
         Ok(())
     }
 
     pub fn handle_message(&self, topic: &str, payload: &[u8]) {
-        // Only process messages from events/rpc topic
-        if !topic.ends_with("/events/rpc") {
+        self.exporter_metrics.message_received();
+
+        let topic = if self.case_insensitive_topics {
+            Cow::Owned(topic.to_lowercase())
+        } else {
+            Cow::Borrowed(topic)
+        };
+        let topic = topic.as_ref();
+
+        // Only process Gen2+ events/rpc, Gen1 status and retained online topics
+        if !topic.ends_with("/events/rpc")
+            && !topic.ends_with("/status")
+            && !topic.ends_with("/online")
+        {
             debug!("Skipping topic: {}", topic);
+            self.exporter_metrics.message_ignored("topic_filter");
             return;
         }
 
+        // Duplicates are still applied, updates are idempotent
+        if self
+            .duplicates
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_duplicate(topic, payload)
+        {
+            debug!("Duplicate payload on {}", topic);
+            self.exporter_metrics.duplicate_message();
+        }
+
+        let payload = if self.accept_compressed_payloads {
+            match decompress_payload(payload) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("Failed to decompress payload on {}: {}", topic, e);
+                    self.exporter_metrics.message_ignored("decompress_error");
+                    return;
+                }
+            }
+        } else {
+            Cow::Borrowed(payload)
+        };
+        let payload = payload.as_ref();
+
         let payload_str = match std::str::from_utf8(payload) {
             Ok(s) => s,
             Err(e) => {
                 warn!("Invalid UTF-8 in payload: {}", e);
+                self.exporter_metrics.message_ignored("utf8_error");
                 return;
             }
         };
 
         debug!("Processing message from {}: {}", topic, payload_str);
 
-        match parse_message(payload_str) {
-            Ok(msg) => {
-                if msg.method == MessageMethod::NotifyEvent {
-                    debug!("Ignoring NotifyEvent message");
-                    return;
+        if topic.ends_with("/online") {
+            match parse_online_payload(payload_str) {
+                Ok(online) => {
+                    self.exporter_metrics.message_parsed();
+                    self.metrics.update_online(topic, online);
+                }
+                Err(e) => {
+                    warn!("Failed to parse online payload on {}: {}", topic, e);
+                    self.exporter_metrics.parse_error();
                 }
+            }
+            return;
+        }
 
+        match parse_any_generation(
+            topic,
+            payload_str,
+            self.process_events,
+            self.device_name_topic_index,
+        ) {
+            Ok(msg) => {
                 info!("Processing {:?} from device: {}", msg.method, msg.src);
+                self.exporter_metrics.message_parsed();
                 self.metrics.update_from_message(&msg, Some(topic));
+                self.metrics.record_payload(&msg, Some(topic), payload);
+                // Events are counted once, replaying them would count them again
+                if let Some(state) = &self.state {
+                    if msg.method != MessageMethod::NotifyEvent {
+                        state.record(&msg, Some(topic));
+                    }
+                }
+                if let Some(archive) = &self.archive {
+                    archive.record(&msg, Some(topic));
+                }
+            }
+            Err(ParserError::IgnoredMessage(reason)) => {
+                debug!("Ignoring {} message", reason);
+                self.exporter_metrics.message_ignored("notify_event");
             }
             Err(e) => {
                 warn!("Failed to parse message: {}", e);
+                self.exporter_metrics.parse_error();
             }
         }
     }
 }
 
-pub async fn run(config: Config, metrics: Arc<ShellyMetrics>) -> Result<()> {
+/// A connection that stays up this long resets the reconnect backoff
+const STABLE_CONNECTION: Duration = Duration::from_secs(30);
+
+/// Fraction of the reconnect delay randomly added or subtracted
+const BACKOFF_JITTER: f64 = 0.2;
+
+/// Reconnect delay before jitter: `base` doubled per failed attempt, capped at `max`
+pub fn next_backoff(attempt: u32, base: Duration, max: Duration) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(attempt)).min(max)
+}
+
+/// Scale `delay` by ±`BACKOFF_JITTER` using `unit` in [0, 1)
+fn with_jitter(delay: Duration, unit: f64) -> Duration {
+    delay.mul_f64(1.0 - BACKOFF_JITTER + 2.0 * BACKOFF_JITTER * unit)
+}
+
+/// Random number in [0, 1) from the std hasher's per-instance random keys
+fn random_unit() -> f64 {
+    let bits = RandomState::new().build_hasher().finish() >> 11;
+    bits as f64 / (1u64 << 53) as f64
+}
+
+/// Jittered delay for the current attempt, advancing the attempt counter
+fn reconnect_delay(config: &Config, attempt: &mut u32) -> Duration {
+    let delay = next_backoff(
+        *attempt,
+        Duration::from_secs(config.mqtt_reconnect_base_seconds),
+        Duration::from_secs(config.mqtt_reconnect_max_seconds),
+    );
+    *attempt = attempt.saturating_add(1);
+    with_jitter(delay, random_unit())
+}
+
+/// Whether the broker refused the connection because of the credentials
+fn is_auth_failure(error: &ConnectionError) -> bool {
+    matches!(
+        error,
+        ConnectionError::ConnectionRefused(
+            ConnectReturnCode::BadUserNamePassword | ConnectReturnCode::NotAuthorized
+        )
+    )
+}
+
+/// Recoverable errors in a row, without a message in between, before falling back to a
+/// full reconnect with a new handler and the next broker
+const MAX_RECOVERABLE_ERRORS: u32 = 3;
+
+/// Errors caused by a single bad or unexpected packet rather than a broker or network fault
+///
+/// rumqttc drops the connection on every error, these included; after them the event
+/// loop reconnects to the same broker on its next poll and the handler is kept, but the
+/// reconnect still waits out the backoff and is counted.
+fn is_recoverable(error: &ConnectionError) -> bool {
+    matches!(
+        error,
+        ConnectionError::MqttState(
+            StateError::Unsolicited(_)
+                | StateError::WrongPacket
+                | StateError::CollisionTimeout
+                | StateError::Deserialization(_)
+        )
+    )
+}
+
+/// Count an authentication refusal, failing once `max` consecutive ones are reached
+fn check_auth_failure(
+    error: &ConnectionError,
+    consecutive: &mut u32,
+    max: Option<u32>,
+    exporter_metrics: &ExporterMetrics,
+) -> Result<()> {
+    if !is_auth_failure(error) {
+        return Ok(());
+    }
+
+    exporter_metrics.auth_failure();
+    *consecutive += 1;
+    error!(
+        "MQTT broker rejected the connection ({}), check MQTT_USERNAME and MQTT_PASSWORD; the broker may not allow anonymous clients",
+        error
+    );
+
+    match max {
+        Some(max) if *consecutive >= max => {
+            anyhow::bail!(
+                "Giving up after {} MQTT authentication failures",
+                consecutive
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Resolves once `true` is sent on the shutdown channel (or its sender is dropped)
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|&stop| stop).await;
+}
+
+/// Sleep for `delay`, returning `false` if shutdown was requested meanwhile
+async fn sleep_unless_shutdown(delay: Duration, shutdown: &mut watch::Receiver<bool>) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(delay) => true,
+        _ = shutdown_requested(shutdown) => false,
+    }
+}
+
+/// Parse a Gen2+ message, falling back to the Gen1 `/status` shape with the
+/// device name from topic segment `device_name_topic_index` as `src`; the
+/// Gen2+ error is kept if both fail
+fn parse_any_generation(
+    topic: &str,
+    payload: &str,
+    process_events: bool,
+    device_name_topic_index: usize,
+) -> Result<ShellyMessage, ParserError> {
+    let error = match parse_message_with(payload, process_events) {
+        Err(error @ ParserError::JsonError(_)) => error,
+        result => return result,
+    };
+    match extract_device_from_topic_at(topic, device_name_topic_index) {
+        Some(device) => parse_gen1_message(payload, &device).map_err(|_| error),
+        None => Err(error),
+    }
+}
+
+/// Consume broker messages until `true` is sent on `shutdown`, reconnecting on failure;
+/// `connected` tracks whether the broker has acknowledged the current connection
+pub async fn run(
+    config: Config,
+    metrics: Arc<ShellyMetrics>,
+    exporter_metrics: Arc<ExporterMetrics>,
+    state: Option<Arc<StateStore>>,
+    archive: Option<Archive>,
+    connected: Arc<AtomicBool>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let brokers = config.mqtt_brokers();
+    anyhow::ensure!(!brokers.is_empty(), "No MQTT broker configured");
+    let mut attempt = 0;
+    let mut auth_failures = 0;
+    let max_auth_failures =
+        (config.mqtt_max_auth_failures > 0).then_some(config.mqtt_max_auth_failures);
+    let watchdog_timeout = (config.message_watchdog_secs > 0)
+        .then(|| Duration::from_secs(config.message_watchdog_secs));
+
+    let mut reconnecting = false;
     loop {
-        info!("Connecting to MQTT broker: {}", config.mqtt_server());
+        if *shutdown.borrow() {
+            break;
+        }
+        // Every pass after the first follows a disconnect or a failed attempt
+        if reconnecting {
+            exporter_metrics.mqtt_reconnect();
+        }
+        reconnecting = true;
+        // Rotate through the brokers, one per connection attempt
+        let broker = &brokers[attempt as usize % brokers.len()];
+        info!("Connecting to MQTT broker: {}", broker);
 
-        let (handler, mut eventloop) = match MqttHandler::new(&config, metrics.clone()) {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Failed to create MQTT handler: {}", e);
-                tokio::time::sleep(Duration::from_secs(5)).await;
-                continue;
-            }
-        };
+        let (handler, mut eventloop) =
+            MqttHandler::new(&config, broker, metrics.clone(), exporter_metrics.clone());
+        let handler = handler
+            .with_state(state.clone())
+            .with_archive(archive.clone());
 
         if let Err(e) = handler.subscribe(&config.mqtt_topic).await {
             error!("Failed to subscribe: {}", e);
-            tokio::time::sleep(Duration::from_secs(5)).await;
+            if !sleep_unless_shutdown(reconnect_delay(&config, &mut attempt), &mut shutdown).await {
+                break;
+            }
             continue;
         }
 
         info!("MQTT connection established");
 
+        let mut connected_at = None;
+        let mut watchdog = MessageWatchdog::new(watchdog_timeout);
+        let mut recoverable_errors = 0;
+
         loop {
-            match eventloop.poll().await {
+            let event = tokio::select! {
+                event = eventloop.poll() => event,
+                _ = watchdog.expired() => {
+                    warn!("No MQTT message within the watchdog timeout, forcing a reconnect");
+                    exporter_metrics.watchdog_reconnect();
+                    break;
+                }
+                _ = shutdown_requested(&mut shutdown) => {
+                    info!("Shutdown requested, leaving MQTT loop");
+                    connected.store(false, Ordering::Relaxed);
+                    return Ok(());
+                }
+            };
+            match event {
                 Ok(Event::Incoming(Incoming::Publish(p))) => {
+                    watchdog.on_message();
+                    recoverable_errors = 0;
                     handler.handle_message(&p.topic, &p.payload);
                 }
                 Ok(Event::Incoming(Incoming::ConnAck(_))) => {
                     info!("MQTT connected");
+                    // Silence only counts once the broker has accepted the connection
+                    watchdog.on_message();
+                    if connected_at.is_some() {
+                        if let Err(e) = handler.resubscribe(&config.mqtt_topic) {
+                            error!("{:#}", e);
+                            break;
+                        }
+                    }
+                    connected.store(true, Ordering::Relaxed);
+                    connected_at = Some(Instant::now());
+                    auth_failures = 0;
                 }
                 Ok(Event::Incoming(Incoming::Disconnect)) => {
                     warn!("MQTT disconnected");
                     break;
                 }
+                Err(e) if is_auth_failure(&e) => {
+                    check_auth_failure(
+                        &e,
+                        &mut auth_failures,
+                        max_auth_failures,
+                        &exporter_metrics,
+                    )?;
+                    break;
+                }
+                Err(e) if is_recoverable(&e) && recoverable_errors < MAX_RECOVERABLE_ERRORS => {
+                    connected.store(false, Ordering::Relaxed);
+                    exporter_metrics.mqtt_reconnect();
+                    // Advances `recoverable_errors`, so the delay grows with each one in a row
+                    let delay = reconnect_delay(&config, &mut recoverable_errors);
+                    warn!(
+                        "Recoverable MQTT error, reconnecting to the same broker in {:.1} seconds: {}",
+                        delay.as_secs_f64(),
+                        e
+                    );
+                    if !sleep_unless_shutdown(delay, &mut shutdown).await {
+                        info!("Shutdown requested, leaving MQTT loop");
+                        return Ok(());
+                    }
+                }
                 Err(e) => {
                     error!("MQTT error: {}", e);
                     break;
@@ -112,13 +575,265 @@ pub async fn run(config: Config, metrics: Arc<ShellyMetrics>) -> Result<()> {
             }
         }
 
-        warn!("MQTT connection lost, reconnecting in 5 seconds...");
-        tokio::time::sleep(Duration::from_secs(5)).await;
+        connected.store(false, Ordering::Relaxed);
+        if connected_at.is_some_and(|at| at.elapsed() >= STABLE_CONNECTION) {
+            attempt = 0;
+        }
+        let delay = reconnect_delay(&config, &mut attempt);
+        warn!(
+            "MQTT connection lost, reconnecting in {:.1} seconds...",
+            delay.as_secs_f64()
+        );
+        if !sleep_unless_shutdown(delay, &mut shutdown).await {
+            break;
+        }
     }
+
+    info!("Shutdown requested, leaving MQTT loop");
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::state::StoredMessage;
+    use clap::Parser;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_qos_from_level() {
+        assert_eq!(qos_from_level(0).unwrap(), QoS::AtMostOnce);
+        assert_eq!(qos_from_level(1).unwrap(), QoS::AtLeastOnce);
+        assert_eq!(qos_from_level(2).unwrap(), QoS::ExactlyOnce);
+        assert!(qos_from_level(3).is_err());
+    }
+
+    #[test]
+    fn test_recoverable_errors() {
+        use std::io;
+
+        for recoverable in [
+            ConnectionError::MqttState(StateError::Unsolicited(7)),
+            ConnectionError::MqttState(StateError::WrongPacket),
+            ConnectionError::MqttState(StateError::CollisionTimeout),
+            ConnectionError::MqttState(StateError::Deserialization(
+                rumqttc::Error::PayloadSizeLimitExceeded(1 << 20),
+            )),
+        ] {
+            assert!(is_recoverable(&recoverable), "{:?}", recoverable);
+        }
+
+        for fatal in [
+            ConnectionError::Io(io::Error::from(io::ErrorKind::ConnectionReset)),
+            ConnectionError::NetworkTimeout,
+            ConnectionError::FlushTimeout,
+            ConnectionError::RequestsDone,
+            ConnectionError::ConnectionRefused(ConnectReturnCode::ServiceUnavailable),
+            ConnectionError::MqttState(StateError::AwaitPingResp),
+            ConnectionError::MqttState(StateError::ConnectionAborted),
+            ConnectionError::MqttState(StateError::Io(io::Error::from(io::ErrorKind::BrokenPipe))),
+        ] {
+            assert!(!is_recoverable(&fatal), "{:?}", fatal);
+        }
+    }
+
+    #[test]
+    fn test_auth_failure_connack() {
+        let mut registry = Registry::default();
+        let exporter_metrics = ExporterMetrics::new(&mut registry);
+        let refused = ConnectionError::ConnectionRefused(ConnectReturnCode::BadUserNamePassword);
+        let unavailable = ConnectionError::ConnectionRefused(ConnectReturnCode::ServiceUnavailable);
+        assert!(is_auth_failure(&refused));
+        assert!(is_auth_failure(&ConnectionError::ConnectionRefused(
+            ConnectReturnCode::NotAuthorized
+        )));
+        assert!(!is_auth_failure(&unavailable));
+
+        let mut consecutive = 0;
+        assert!(
+            check_auth_failure(&unavailable, &mut consecutive, Some(2), &exporter_metrics).is_ok()
+        );
+        assert!(check_auth_failure(&refused, &mut consecutive, Some(2), &exporter_metrics).is_ok());
+        assert!(
+            check_auth_failure(&refused, &mut consecutive, Some(2), &exporter_metrics).is_err()
+        );
+        assert_eq!(consecutive, 2);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("mqtt2prom_auth_failures_total 2\n"));
+    }
+
+    #[tokio::test]
+    async fn test_run_returns_on_shutdown() {
+        // Nothing listens on port 1, so the loop keeps failing and backing off
+        let config = test_config(&["--mqtt-port", "1"]);
+        let mut registry = Registry::default();
+        let metrics = Arc::new(ShellyMetrics::new(&mut registry));
+        let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+        let (tx, rx) = watch::channel(false);
+
+        let connected = Arc::new(AtomicBool::new(false));
+        let task = tokio::spawn(run(
+            config,
+            metrics,
+            exporter_metrics,
+            None,
+            None,
+            connected.clone(),
+            rx,
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!connected.load(Ordering::Relaxed));
+        tx.send(true).unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(2), task)
+            .await
+            .expect("run did not stop after shutdown");
+        assert!(result.unwrap().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnects_counted() {
+        // Nothing listens on port 1, so every attempt fails and the loop reconnects
+        let config = test_config(&["--mqtt-port", "1", "--mqtt-reconnect-base-seconds", "1"]);
+        let mut registry = Registry::default();
+        let metrics = Arc::new(ShellyMetrics::new(&mut registry));
+        let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+        let (tx, rx) = watch::channel(false);
+        let reconnects = |registry: &Registry| {
+            let mut buffer = String::new();
+            encode(&mut buffer, registry).unwrap();
+            buffer
+                .lines()
+                .find_map(|line| line.strip_prefix("mqtt2prom_mqtt_reconnects_total "))
+                .map(str::to_string)
+        };
+
+        let task = tokio::spawn(run(
+            config,
+            metrics,
+            exporter_metrics,
+            None,
+            None,
+            Arc::new(AtomicBool::new(false)),
+            rx,
+        ));
+        // Stop during the backoff after the second reconnect
+        while reconnects(&registry).as_deref() != Some("2") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tx.send(true).unwrap();
+        task.await.unwrap().unwrap();
+
+        assert_eq!(reconnects(&registry).as_deref(), Some("2"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_message_watchdog() {
+        let timeout = Duration::from_secs(60);
+        let mut watchdog = MessageWatchdog::new(Some(timeout));
+
+        // A message half way through pushes the deadline out
+        tokio::time::advance(Duration::from_secs(30)).await;
+        watchdog.on_message();
+        let early = tokio::time::timeout(Duration::from_secs(59), watchdog.expired()).await;
+        assert!(early.is_err());
+
+        // Silence past the timeout forces a reconnect
+        let fired = tokio::time::timeout(Duration::from_secs(2), watchdog.expired()).await;
+        assert!(fired.is_ok());
+
+        let disabled = MessageWatchdog::new(None);
+        let never = tokio::time::timeout(Duration::from_secs(86400), disabled.expired()).await;
+        assert!(never.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run_watchdog_forces_reconnect() {
+        // A broker that acknowledges the connection late, then never sends a message
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            // CONNECT, whose remaining length fits in one byte
+            let mut header = [0u8; 2];
+            socket.read_exact(&mut header).await.unwrap();
+            let mut connect = vec![0u8; header[1] as usize];
+            socket.read_exact(&mut connect).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            // CONNACK, session not present, accepted
+            socket.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+            let mut buf = [0u8; 1024];
+            while socket.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+        });
+
+        let config = test_config(&[
+            "--mqtt-port",
+            &port,
+            "--message-watchdog-secs",
+            "60",
+            "--mqtt-keep-alive-seconds",
+            "300",
+        ]);
+        let mut registry = Registry::default();
+        let metrics = Arc::new(ShellyMetrics::new(&mut registry));
+        let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+        let (tx, rx) = watch::channel(false);
+        let watchdog_reconnects = |registry: &Registry| {
+            let mut buffer = String::new();
+            encode(&mut buffer, registry).unwrap();
+            buffer
+                .lines()
+                .find_map(|line| line.strip_prefix("mqtt2prom_watchdog_reconnects_total "))
+                .map(str::to_string)
+        };
+
+        let start = tokio::time::Instant::now();
+        let task = tokio::spawn(run(
+            config,
+            metrics,
+            exporter_metrics,
+            None,
+            None,
+            Arc::new(AtomicBool::new(false)),
+            rx,
+        ));
+        while watchdog_reconnects(&registry).as_deref() != Some("1") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // The timeout runs from the CONNACK, not from when the client was created
+        assert!(start.elapsed() >= Duration::from_secs(63));
+        tx.send(true).unwrap();
+        task.await.unwrap().unwrap();
+
+        assert_eq!(watchdog_reconnects(&registry).as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn test_next_backoff_doubles_up_to_max() {
+        let base = Duration::from_secs(1);
+        let max = Duration::from_secs(60);
+        let delays: Vec<u64> = (0..8)
+            .map(|attempt| next_backoff(attempt, base, max).as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(next_backoff(u32::MAX, base, max), max);
+    }
+
+    #[test]
+    fn test_backoff_jitter_within_twenty_percent() {
+        let delay = Duration::from_secs(10);
+        assert_eq!(with_jitter(delay, 0.0), Duration::from_secs(8));
+        assert_eq!(with_jitter(delay, 0.5), delay);
+        for _ in 0..100 {
+            let jittered = with_jitter(delay, random_unit());
+            assert!(jittered >= Duration::from_secs(8) && jittered < Duration::from_secs(12));
+        }
+    }
 
     #[test]
     fn test_topic_filtering() {
@@ -126,4 +841,401 @@ mod tests {
         assert!(!"mostert/shelly/online".ends_with("/events/rpc"));
         assert!(!"other/topic".ends_with("/events/rpc"));
     }
+
+    fn test_broker() -> BrokerEndpoint {
+        BrokerEndpoint {
+            host: "localhost".to_string(),
+            port: 1883,
+        }
+    }
+
+    #[test]
+    fn test_websocket_options() {
+        let config = test_config(&["--mqtt-transport", "ws", "--mqtt-ws-path", "/ws"]);
+        let broker = BrokerEndpoint {
+            host: "broker.lan".to_string(),
+            port: 8083,
+        };
+
+        let options = broker_options(&config, &broker);
+        assert_eq!(
+            options.broker_address(),
+            ("ws://broker.lan:8083/ws".to_string(), 8083)
+        );
+        assert!(matches!(options.transport(), Transport::Ws));
+        // Building the client does not connect, so no broker is needed
+        let mut registry = Registry::default();
+        let metrics = Arc::new(ShellyMetrics::new(&mut registry));
+        let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+        MqttHandler::new(&config, &broker, metrics, exporter_metrics);
+
+        let options = broker_options(&test_config(&[]), &broker);
+        assert_eq!(options.broker_address(), ("broker.lan".to_string(), 8083));
+    }
+
+    fn test_config(extra_args: &[&str]) -> Config {
+        let args = [
+            "mqtt2prom",
+            "--mqtt-host",
+            "localhost",
+            "--mqtt-username",
+            "user",
+            "--mqtt-password",
+            "pass",
+        ];
+        Config::try_parse_from(args.iter().chain(extra_args)).unwrap()
+    }
+
+    #[test]
+    fn test_handle_message_uses_topic_device_name() {
+        let mut registry = Registry::default();
+        let metrics = Arc::new(ShellyMetrics::new(&mut registry));
+        let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+        let (handler, _eventloop) =
+            MqttHandler::new(&test_config(&[]), &test_broker(), metrics, exporter_metrics);
+
+        handler.handle_message(
+            "mostert/shelly/plugcoffee/events/rpc",
+            br#"{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {"switch:0": {"apower": 7.5}}}"#,
+        );
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(
+            buffer.contains("shelly_switch_power_watts{device=\"plugcoffee\",switch=\"0\"} 7.5\n")
+        );
+        assert!(!buffer.contains("d48afc781ad8"));
+    }
+
+    #[tokio::test]
+    async fn test_handle_message_archives_parsed_messages() {
+        let path =
+            std::env::temp_dir().join(format!("mqtt2prom-archive-test-{}", std::process::id()));
+        let mut registry = Registry::default();
+        let metrics = Arc::new(ShellyMetrics::new(&mut registry));
+        let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+        let (handler, _eventloop) =
+            MqttHandler::new(&test_config(&[]), &test_broker(), metrics, exporter_metrics);
+        let handler = handler.with_archive(Some(Archive::open(&path).unwrap()));
+
+        handler.handle_message(
+            "mostert/shelly/plugcoffee/events/rpc",
+            br#"{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {"switch:0": {"apower": 7.5}}}"#,
+        );
+        // Not JSON, so nothing is archived
+        handler.handle_message("mostert/shelly/plugcoffee/events/rpc", b"garbage");
+
+        let mut written = String::new();
+        for _ in 0..100 {
+            written = std::fs::read_to_string(&path).unwrap();
+            if !written.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<_> = written.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let archived: StoredMessage = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(
+            archived.topic.as_deref(),
+            Some("mostert/shelly/plugcoffee/events/rpc")
+        );
+        assert_eq!(archived.message.src, "shellyplugus-d48afc781ad8");
+        assert_eq!(
+            archived.message.params.switch.get(0).unwrap().apower,
+            Some(7.5)
+        );
+    }
+
+    #[test]
+    fn test_device_name_topic_index() {
+        let config = test_config(&["--device-name-topic-index", "4"]);
+        let mut registry = Registry::default();
+        let metrics = Arc::new(ShellyMetrics::with_options(
+            &mut registry,
+            &config.metrics_options(),
+        ));
+        let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+        let (handler, _eventloop) =
+            MqttHandler::new(&config, &test_broker(), metrics, exporter_metrics);
+
+        handler.handle_message(
+            "home/floor2/room/shelly/lamp/events/rpc",
+            br#"{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {"switch:0": {"apower": 7.5}}}"#,
+        );
+        handler.handle_message(
+            "home/floor2/room/shelly/garage/status",
+            br#"{"relays": [{"ison": true}]}"#,
+        );
+        // Too short for the configured index, so the MAC is used
+        handler.handle_message(
+            "shelly/events/rpc",
+            br#"{"src": "shellyplugus-aabbccddeeff", "method": "NotifyStatus", "params": {"switch:0": {"apower": 1.0}}}"#,
+        );
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("shelly_switch_power_watts{device=\"lamp\",switch=\"0\"} 7.5\n"));
+        assert!(buffer.contains("shelly_switch_state{device=\"garage\",switch=\"0\"} 1.0\n"));
+        assert!(buffer
+            .contains("shelly_switch_power_watts{device=\"aabbccddeeff\",switch=\"0\"} 1.0\n"));
+    }
+
+    #[test]
+    fn test_handle_gen1_status() {
+        let mut registry = Registry::default();
+        let metrics = Arc::new(ShellyMetrics::new(&mut registry));
+        let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+        let (handler, _eventloop) =
+            MqttHandler::new(&test_config(&[]), &test_broker(), metrics, exporter_metrics);
+
+        handler.handle_message(
+            "mostert/shelly/garage/status",
+            br#"{"relays": [{"ison": true}], "meters": [{"power": 12.3, "is_valid": true, "total": 456}], "tmp": {"tC": 21.5, "is_valid": true}}"#,
+        );
+        handler.handle_message("mostert/shelly/garage/status", br#"{"online": true}"#);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("shelly_switch_power_watts{device=\"garage\",switch=\"0\"} 12.3\n"));
+        assert!(buffer.contains("shelly_switch_state{device=\"garage\",switch=\"0\"} 1.0\n"));
+        assert!(buffer.contains("shelly_temperature_celsius{device=\"garage\",id=\"0\"} 21.5\n"));
+        assert!(buffer.contains("mqtt2prom_parse_errors_total 1\n"));
+    }
+
+    #[test]
+    fn test_debug_payload_label() {
+        let mut registry = Registry::default();
+        let options = crate::metrics::MetricsOptions {
+            debug_payload_label: true,
+            ..Default::default()
+        };
+        let metrics = Arc::new(ShellyMetrics::with_options(&mut registry, &options));
+        let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+        let (handler, _eventloop) =
+            MqttHandler::new(&test_config(&[]), &test_broker(), metrics, exporter_metrics);
+
+        let topic = "mostert/shelly/plugcoffee/events/rpc";
+        let payload = br#"{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {"switch:0": {"apower": 7.5}}}"#;
+        handler.handle_message(topic, payload);
+        handler.handle_message(topic, payload);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains(
+            "mqtt2prom_last_payload_info{device=\"plugcoffee\",hash=\"5eb45bf0beaeec1c\"} 1.0\n"
+        ));
+
+        // A new payload replaces the series rather than adding one
+        handler.handle_message(
+            topic,
+            br#"{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {"switch:0": {"apower": 8.0}}}"#,
+        );
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert_eq!(buffer.matches("mqtt2prom_last_payload_info{").count(), 1);
+        assert!(!buffer.contains("5eb45bf0beaeec1c"));
+    }
+
+    #[test]
+    fn test_handle_online_topic() {
+        let mut registry = Registry::default();
+        let metrics = Arc::new(ShellyMetrics::new(&mut registry));
+        let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+        let (handler, _eventloop) =
+            MqttHandler::new(&test_config(&[]), &test_broker(), metrics, exporter_metrics);
+
+        handler.handle_message("mostert/shelly/plugcoffee/online", b"true");
+        handler.handle_message("mostert/shelly/garage/online", b"\"false\"");
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("shelly_online{device=\"plugcoffee\"} 1.0\n"));
+        assert!(buffer.contains("shelly_online{device=\"garage\"} 0.0\n"));
+
+        // The last will flips the retained state back to offline
+        handler.handle_message("mostert/shelly/plugcoffee/online", b"false");
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("shelly_online{device=\"plugcoffee\"} 0.0\n"));
+    }
+
+    #[test]
+    fn test_process_events() {
+        let payload = br#"{"src": "shellyplusi4-c4d8d5570b14", "method": "NotifyEvent", "params": {"ts": 1700000000.12, "events": [
+            {"component": "input:0", "id": 0, "event": "single_push"},
+            {"component": "input:1", "id": 1, "event": "single_push"},
+            {"component": "switch:0", "id": 0, "event": "overtemp"}
+        ]}}"#;
+        let topic = "mostert/shelly/hallway/events/rpc";
+
+        for (args, counted) in [(&[][..], false), (&["--process-events"][..], true)] {
+            let mut registry = Registry::default();
+            let metrics = Arc::new(ShellyMetrics::new(&mut registry));
+            let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+            let (handler, _eventloop) = MqttHandler::new(
+                &test_config(args),
+                &test_broker(),
+                metrics,
+                exporter_metrics,
+            );
+
+            handler.handle_message(topic, payload);
+
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            let push = "shelly_events_total{device=\"hallway\",event=\"single_push\"} 2\n";
+            let overtemp = "shelly_events_total{device=\"hallway\",event=\"overtemp\"} 1\n";
+            assert_eq!(buffer.contains(push), counted, "{:?}", args);
+            assert_eq!(buffer.contains(overtemp), counted, "{:?}", args);
+            assert_eq!(
+                buffer.contains("mqtt2prom_messages_ignored_total{reason=\"notify_event\"} 1\n"),
+                !counted
+            );
+        }
+    }
+
+    #[test]
+    fn test_case_insensitive_topics() {
+        let payload = br#"{"src": "shellyplugus-a", "method": "NotifyStatus", "params": {"switch:0": {"apower": 5.0}}}"#;
+        let topic = "Mostert/Shelly/PlugCoffee/Events/RPC";
+
+        for (args, processed) in [(&[][..], false), (&["--case-insensitive-topics"][..], true)] {
+            let mut registry = Registry::default();
+            let metrics = Arc::new(ShellyMetrics::new(&mut registry));
+            let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+            let (handler, _eventloop) = MqttHandler::new(
+                &test_config(args),
+                &test_broker(),
+                metrics,
+                exporter_metrics,
+            );
+
+            handler.handle_message(topic, payload);
+
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            assert_eq!(
+                buffer.contains(
+                    "shelly_switch_power_watts{device=\"plugcoffee\",switch=\"0\"} 5.0\n"
+                ),
+                processed
+            );
+        }
+    }
+
+    #[test]
+    fn test_compressed_payloads() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let gzip = |data: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let payload = gzip(
+            br#"{"src": "shellyplugus-a", "method": "NotifyStatus", "params": {"switch:0": {"apower": 5.0}}}"#,
+        );
+        let bomb = gzip(&vec![b' '; MAX_DECOMPRESSED_PAYLOAD_BYTES as usize + 1]);
+        let topic = "mostert/shelly/plugcoffee/events/rpc";
+
+        for (args, processed) in [
+            (&[][..], false),
+            (&["--accept-compressed-payloads"][..], true),
+        ] {
+            let mut registry = Registry::default();
+            let metrics = Arc::new(ShellyMetrics::new(&mut registry));
+            let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+            let (handler, _eventloop) = MqttHandler::new(
+                &test_config(args),
+                &test_broker(),
+                metrics,
+                exporter_metrics,
+            );
+
+            handler.handle_message(topic, &payload);
+            handler.handle_message(topic, &bomb);
+
+            let mut buffer = String::new();
+            encode(&mut buffer, &registry).unwrap();
+            assert_eq!(
+                buffer.contains(
+                    "shelly_switch_power_watts{device=\"plugcoffee\",switch=\"0\"} 5.0\n"
+                ),
+                processed
+            );
+            assert_eq!(
+                buffer
+                    .contains("mqtt2prom_messages_ignored_total{reason=\"decompress_error\"} 1\n"),
+                processed
+            );
+        }
+    }
+
+    #[test]
+    fn test_duplicate_messages() {
+        let config = test_config(&[]);
+        let mut registry = Registry::default();
+        let metrics = Arc::new(ShellyMetrics::new(&mut registry));
+        let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+        let (handler, _eventloop) =
+            MqttHandler::new(&config, &test_broker(), metrics, exporter_metrics);
+
+        let payload = br#"{"src": "shellyplugus-a", "method": "NotifyStatus", "params": {"switch:0": {"apower": 5.0}}}"#;
+        handler.handle_message("mostert/shelly/plugcoffee/events/rpc", payload);
+        handler.handle_message("mostert/shelly/plugcoffee/events/rpc", payload);
+        handler.handle_message("mostert/shelly/plugcoffee/events/rpc", payload);
+        // The same payload on another topic is not a redelivery
+        handler.handle_message("mostert/shelly/plugdesk/events/rpc", payload);
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("mqtt2prom_duplicate_messages_total 2\n"));
+        assert!(buffer.contains("mqtt2prom_messages_parsed_total 4\n"));
+        assert!(
+            buffer.contains("shelly_switch_power_watts{device=\"plugcoffee\",switch=\"0\"} 5.0\n")
+        );
+    }
+
+    #[test]
+    fn test_duplicate_window() {
+        let mut tracker = DuplicateTracker::default();
+        assert!(!tracker.is_duplicate("t", b"0"));
+        for i in 1..=DUPLICATE_WINDOW {
+            assert!(!tracker.is_duplicate("t", i.to_string().as_bytes()));
+        }
+        // "0" has left the window
+        assert!(!tracker.is_duplicate("t", b"0"));
+        assert!(tracker.is_duplicate("t", DUPLICATE_WINDOW.to_string().as_bytes()));
+    }
+
+    #[test]
+    fn test_message_counters() {
+        let config = test_config(&[]);
+        let mut registry = Registry::default();
+        let metrics = Arc::new(ShellyMetrics::new(&mut registry));
+        let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+        let (handler, _eventloop) =
+            MqttHandler::new(&config, &test_broker(), metrics, exporter_metrics);
+
+        let topic = "mostert/shelly/plugcoffee/events/rpc";
+        handler.handle_message(topic, &[0xff, 0xfe]);
+        handler.handle_message(
+            topic,
+            br#"{"src": "shellyplugus-a", "method": "NotifyStatus", "params": {}}"#,
+        );
+        handler.handle_message(topic, b"not json");
+        handler.handle_message("mostert/shelly/plugcoffee/command", b"status_update");
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("mqtt2prom_messages_received_total 4\n"));
+        assert!(buffer.contains("mqtt2prom_messages_parsed_total 1\n"));
+        assert!(buffer.contains("mqtt2prom_parse_errors_total 1\n"));
+        assert!(buffer.contains("mqtt2prom_messages_ignored_total{reason=\"utf8_error\"} 1\n"));
+        assert!(buffer.contains("mqtt2prom_messages_ignored_total{reason=\"topic_filter\"} 1\n"));
+    }
 }
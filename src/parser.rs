@@ -1,4 +1,8 @@
-use serde::{Deserialize, Serialize};
+use serde::de::{IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::marker::PhantomData;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -10,7 +14,6 @@ pub enum ParserError {
     IgnoredMessage(String),
 
     #[error("Missing required field: {0}")]
-    #[allow(dead_code)]
     MissingField(String),
 }
 
@@ -32,20 +35,298 @@ pub struct ShellyMessage {
     pub params: MessageParams,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct MessageParams {
-    #[serde(rename = "switch:0", skip_serializing_if = "Option::is_none")]
-    pub switch: Option<SwitchData>,
-    #[serde(rename = "temperature:0", skip_serializing_if = "Option::is_none")]
-    pub temperature: Option<TemperatureSensorData>,
-    #[serde(rename = "humidity:0", skip_serializing_if = "Option::is_none")]
-    pub humidity: Option<HumiditySensorData>,
+    #[serde(flatten, skip_serializing_if = "Components::is_empty")]
+    pub switch: Components<SwitchData>,
+    #[serde(flatten, skip_serializing_if = "Components::is_empty")]
+    pub temperature: Components<TemperatureSensorData>,
     #[serde(rename = "devicepower:0", skip_serializing_if = "Option::is_none")]
     pub devicepower: Option<DevicePowerData>,
+    #[serde(rename = "em:0", skip_serializing_if = "Option::is_none")]
+    pub em: Option<EmData>,
+    #[serde(rename = "smoke:0", skip_serializing_if = "Option::is_none")]
+    pub smoke: Option<AlarmData>,
+    #[serde(rename = "flood:0", skip_serializing_if = "Option::is_none")]
+    pub flood: Option<AlarmData>,
+    #[serde(rename = "motion:0", skip_serializing_if = "Option::is_none")]
+    pub motion: Option<MotionData>,
+    #[serde(rename = "illuminance:0", skip_serializing_if = "Option::is_none")]
+    pub illuminance: Option<IlluminanceData>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub wifi: Option<WifiData>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sys: Option<SysData>,
+    #[serde(flatten, skip_serializing_if = "Components::is_empty")]
+    pub pm1: Components<Pm1Data>,
+    #[serde(flatten, skip_serializing_if = "Components::is_empty")]
+    pub humidity: Components<HumiditySensorData>,
+    #[serde(flatten, skip_serializing_if = "Components::is_empty")]
+    pub number: Components<VirtualNumberData>,
+    #[serde(flatten, skip_serializing_if = "Components::is_empty")]
+    pub boolean: Components<VirtualBooleanData>,
+    #[serde(flatten, skip_serializing_if = "Components::is_empty")]
+    pub input: Components<InputData>,
+    #[serde(flatten, skip_serializing_if = "Components::is_empty")]
+    pub cct: Components<CctData>,
+    #[serde(flatten, skip_serializing_if = "Components::is_empty")]
+    pub rgb: Components<RgbData>,
+    #[serde(flatten, skip_serializing_if = "Components::is_empty")]
+    pub rgbw: Components<RgbwData>,
+    #[serde(flatten, skip_serializing_if = "Components::is_empty")]
+    pub cover: Components<CoverData>,
+    #[serde(flatten, skip_serializing_if = "Components::is_empty")]
+    pub light: Components<LightData>,
+    /// Only present in NotifyEvent messages
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<EventData>,
+    /// Components the exporter does not model yet, such as `cover:0`
+    #[serde(
+        flatten,
+        deserialize_with = "deserialize_unknown",
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+impl MessageParams {
+    /// Number of `<prefix>:<N>` components, the ones that can amplify cardinality
+    pub fn component_count(&self) -> usize {
+        self.switch.len()
+            + self.pm1.len()
+            + self.temperature.len()
+            + self.humidity.len()
+            + self.number.len()
+            + self.boolean.len()
+            + self.input.len()
+            + self.cct.len()
+            + self.rgb.len()
+            + self.rgbw.len()
+            + self.cover.len()
+            + self.light.len()
+    }
+
+    /// Keys of every component present, such as `switch:0` or `wifi`
+    pub fn component_keys(&self) -> Vec<String> {
+        let singletons = [
+            ("devicepower:0", self.devicepower.is_some()),
+            ("em:0", self.em.is_some()),
+            ("smoke:0", self.smoke.is_some()),
+            ("flood:0", self.flood.is_some()),
+            ("motion:0", self.motion.is_some()),
+            ("illuminance:0", self.illuminance.is_some()),
+            ("wifi", self.wifi.is_some()),
+            ("sys", self.sys.is_some()),
+        ];
+        self.switch
+            .keys()
+            .chain(self.pm1.keys())
+            .chain(self.temperature.keys())
+            .chain(self.humidity.keys())
+            .chain(self.number.keys())
+            .chain(self.boolean.keys())
+            .chain(self.input.keys())
+            .chain(self.cct.keys())
+            .chain(self.rgb.keys())
+            .chain(self.rgbw.keys())
+            .chain(self.cover.keys())
+            .chain(self.light.keys())
+            .chain(
+                singletons
+                    .into_iter()
+                    .filter(|(_, present)| *present)
+                    .map(|(key, _)| key.to_string()),
+            )
+            .collect()
+    }
+
+    /// Keep only the first `max` `<prefix>:<N>` components, in field order
+    pub fn truncate_components(&mut self, max: usize) {
+        let mut remaining = max;
+        self.switch.truncate(remaining);
+        remaining -= self.switch.len();
+        self.pm1.truncate(remaining);
+        remaining -= self.pm1.len();
+        self.temperature.truncate(remaining);
+        remaining -= self.temperature.len();
+        self.humidity.truncate(remaining);
+        remaining -= self.humidity.len();
+        self.number.truncate(remaining);
+        remaining -= self.number.len();
+        self.boolean.truncate(remaining);
+        remaining -= self.boolean.len();
+        self.input.truncate(remaining);
+        remaining -= self.input.len();
+        self.cct.truncate(remaining);
+        remaining -= self.cct.len();
+        self.rgb.truncate(remaining);
+        remaining -= self.rgb.len();
+        self.rgbw.truncate(remaining);
+        remaining -= self.rgbw.len();
+        self.cover.truncate(remaining);
+        remaining -= self.cover.len();
+        self.light.truncate(remaining);
+    }
+}
+
+/// A component type that appears in params as `<prefix>:<N>`
+pub trait Component {
+    const PREFIX: &'static str;
+}
+
+/// All `<prefix>:<N>` entries of one component type, keyed by channel number
+#[derive(Debug, Clone)]
+pub struct Components<T>(BTreeMap<u16, T>);
+
+impl<T> Components<T> {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Keep only the `len` lowest-numbered channels
+    pub fn truncate(&mut self, len: usize) {
+        if let Some(&channel) = self.0.keys().nth(len) {
+            self.0.split_off(&channel);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, channel: u16) -> Option<&T> {
+        self.0.get(&channel)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &T)> {
+        self.0
+            .iter()
+            .map(|(channel, component)| (*channel, component))
+    }
+}
+
+impl<T: Component> Components<T> {
+    /// The `<prefix>:<N>` keys of the components present
+    pub fn keys(&self) -> impl Iterator<Item = String> + '_ {
+        self.0
+            .keys()
+            .map(|channel| format!("{}:{}", T::PREFIX, channel))
+    }
+}
+
+impl<T> FromIterator<(u16, T)> for Components<T> {
+    fn from_iter<I: IntoIterator<Item = (u16, T)>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl<T> Default for Components<T> {
+    fn default() -> Self {
+        Self(BTreeMap::new())
+    }
+}
+
+/// Parse the channel number out of a `<prefix>:<N>` key
+fn component_channel(key: &str, prefix: &str) -> Option<u16> {
+    key.strip_prefix(prefix)?.strip_prefix(':')?.parse().ok()
+}
+
+/// Params keys that are not components
+const NON_COMPONENT_KEYS: &[&str] = &["ts"];
+
+/// Prefixes of the `<prefix>:<N>` components in `MessageParams`
+const KNOWN_PREFIXES: &[&str] = &[
+    SwitchData::PREFIX,
+    TemperatureSensorData::PREFIX,
+    HumiditySensorData::PREFIX,
+    Pm1Data::PREFIX,
+    VirtualNumberData::PREFIX,
+    VirtualBooleanData::PREFIX,
+    InputData::PREFIX,
+    CctData::PREFIX,
+    RgbData::PREFIX,
+    RgbwData::PREFIX,
+    CoverData::PREFIX,
+    LightData::PREFIX,
+];
+
+/// Whether a leftover params key is handled elsewhere
+fn is_known_key(key: &str) -> bool {
+    NON_COMPONENT_KEYS.contains(&key)
+        || KNOWN_PREFIXES
+            .iter()
+            .any(|prefix| component_channel(key, prefix).is_some())
+}
+
+/// Collect the leftover params keys that no component type claims
+fn deserialize_unknown<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, serde_json::Value>, D::Error> {
+    struct UnknownVisitor;
+
+    impl<'de> Visitor<'de> for UnknownVisitor {
+        type Value = HashMap<String, serde_json::Value>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a map of components")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut extra = HashMap::new();
+            while let Some(key) = map.next_key::<String>()? {
+                if is_known_key(&key) {
+                    map.next_value::<IgnoredAny>()?;
+                } else {
+                    extra.insert(key, map.next_value()?);
+                }
+            }
+            Ok(extra)
+        }
+    }
+
+    deserializer.deserialize_map(UnknownVisitor)
+}
+
+impl<'de, T: Component + Deserialize<'de>> Deserialize<'de> for Components<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ComponentsVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Component + Deserialize<'de>> Visitor<'de> for ComponentsVisitor<T> {
+            type Value = Components<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a map of {}:N components", T::PREFIX)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut components = BTreeMap::new();
+                while let Some(key) = map.next_key::<String>()? {
+                    match component_channel(&key, T::PREFIX) {
+                        Some(channel) => {
+                            components.insert(channel, map.next_value()?);
+                        }
+                        None => {
+                            map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                }
+                Ok(Components(components))
+            }
+        }
+
+        deserializer.deserialize_map(ComponentsVisitor(PhantomData))
+    }
+}
+
+impl<T: Component + Serialize> Serialize for Components<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(
+            self.0
+                .iter()
+                .map(|(channel, component)| (format!("{}:{}", T::PREFIX, channel), component)),
+        )
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -61,10 +342,26 @@ pub struct SwitchData {
     pub voltage: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub current: Option<f64>,
+    /// Power factor, reported by PM switches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pf: Option<f64>,
+    /// Line frequency in hertz, reported by PM switches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub freq: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub aenergy: Option<EnergyData>,
+    /// Energy returned to the grid, reported by bidirectional meters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ret_aenergy: Option<EnergyData>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<TemperatureData>,
+    /// Active error conditions such as `overtemp` or `overpower`, omitted when there are none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<String>>,
+}
+
+impl Component for SwitchData {
+    const PREFIX: &'static str = "switch";
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -78,40 +375,91 @@ pub struct EnergyData {
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TemperatureData {
-    #[serde(rename = "tC")]
-    pub tc: f64,
-    #[serde(rename = "tF")]
-    pub tf: f64,
+    #[serde(rename = "tC", skip_serializing_if = "Option::is_none")]
+    pub tc: Option<f64>,
+    #[serde(rename = "tF", skip_serializing_if = "Option::is_none")]
+    pub tf: Option<f64>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct WifiData {
-    pub rssi: i32,
+    /// Absent when the device is not connected over WiFi
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rssi: Option<i32>,
+    /// Network the device is attached to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssid: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SysData {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub uptime: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<DeviceInfoData>,
+    /// Pending firmware updates keyed by channel (`stable`, `beta`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_updates: Option<BTreeMap<String, serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ram_free: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ram_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fs_free: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fs_size: Option<u64>,
+}
+
+impl SysData {
+    /// Whether any update channel is offered, None when not reported
+    pub fn update_available(&self) -> Option<bool> {
+        self.available_updates
+            .as_ref()
+            .map(|updates| !updates.is_empty())
+    }
 }
 
-/// Temperature sensor data from H&T devices (temperature:0)
+/// Device identity as reported in `sys.device`
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DeviceInfoData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gen: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fw_id: Option<String>,
+}
+
+/// Temperature sensor data from H&T and multi-probe devices (temperature:N)
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TemperatureSensorData {
     #[serde(default)]
     pub id: u8,
-    #[serde(rename = "tC")]
-    pub tc: f64,
-    #[serde(rename = "tF")]
-    pub tf: f64,
+    /// Null when the sensor reports an error
+    #[serde(rename = "tC", skip_serializing_if = "Option::is_none")]
+    pub tc: Option<f64>,
+    #[serde(rename = "tF", skip_serializing_if = "Option::is_none")]
+    pub tf: Option<f64>,
 }
 
-/// Humidity sensor data from H&T devices (humidity:0)
+impl Component for TemperatureSensorData {
+    const PREFIX: &'static str = "temperature";
+}
+
+/// Humidity sensor data from H&T and multi-probe devices (humidity:N)
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct HumiditySensorData {
     #[serde(default)]
     pub id: u8,
-    pub rh: f64,
+    /// Null when the sensor reports an error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rh: Option<f64>,
+}
+
+impl Component for HumiditySensorData {
+    const PREFIX: &'static str = "humidity";
 }
 
 /// Device power/battery data (devicepower:0)
@@ -127,9 +475,10 @@ pub struct DevicePowerData {
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BatteryData {
-    #[serde(rename = "V")]
-    pub voltage: f64,
-    pub percent: f64,
+    #[serde(rename = "V", skip_serializing_if = "Option::is_none")]
+    pub voltage: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<f64>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -137,18 +486,393 @@ pub struct ExternalPowerData {
     pub present: bool,
 }
 
-/// Parse a Shelly MQTT message from JSON
+/// Smoke or flood sensor state (smoke:0, flood:0)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AlarmData {
+    #[serde(default)]
+    pub id: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alarm: Option<bool>,
+}
+
+/// Motion sensor state (motion:0)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MotionData {
+    #[serde(default)]
+    pub id: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub motion: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vibration: Option<bool>,
+}
+
+/// Light sensor reading (illuminance:0)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IlluminanceData {
+    #[serde(default)]
+    pub id: u8,
+    /// Null when the sensor reports an error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lux: Option<f64>,
+}
+
+impl MotionData {
+    /// Motion or vibration, None when the sensor reported neither
+    pub fn detected(&self) -> Option<bool> {
+        match (self.motion, self.vibration) {
+            (None, None) => None,
+            (motion, vibration) => Some(motion.unwrap_or(false) || vibration.unwrap_or(false)),
+        }
+    }
+}
+
+/// Power meter data from PM Mini / PM1 devices (pm1:N)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Pm1Data {
+    #[serde(default)]
+    pub id: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub voltage: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub apower: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub freq: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aenergy: Option<EnergyData>,
+}
+
+impl Component for Pm1Data {
+    const PREFIX: &'static str = "pm1";
+}
+
+/// Three-phase energy meter data from Pro 3EM devices (em:0)
+///
+/// Single-phase clamp meters only report some phases, so every field is optional.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EmData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub a_act_power: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub a_voltage: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub a_current: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub b_act_power: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub b_voltage: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub b_current: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub c_act_power: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub c_voltage: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub c_current: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_act_power: Option<f64>,
+}
+
+/// Readings of a single phase of an energy meter
+pub struct PhaseReading {
+    pub phase: &'static str,
+    pub act_power: Option<f64>,
+    pub voltage: Option<f64>,
+    pub current: Option<f64>,
+}
+
+impl EmData {
+    /// Per-phase readings in a/b/c order
+    pub fn phases(&self) -> [PhaseReading; 3] {
+        [
+            PhaseReading {
+                phase: "a",
+                act_power: self.a_act_power,
+                voltage: self.a_voltage,
+                current: self.a_current,
+            },
+            PhaseReading {
+                phase: "b",
+                act_power: self.b_act_power,
+                voltage: self.b_voltage,
+                current: self.b_current,
+            },
+            PhaseReading {
+                phase: "c",
+                act_power: self.c_act_power,
+                voltage: self.c_voltage,
+                current: self.c_current,
+            },
+        ]
+    }
+}
+
+/// Script-controlled virtual number component (number:N)
+///
+/// `text:N` and `enum:N` virtual components are not parsed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VirtualNumberData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl Component for VirtualNumberData {
+    const PREFIX: &'static str = "number";
+}
+
+/// Script-controlled virtual boolean component (boolean:N)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VirtualBooleanData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl Component for VirtualBooleanData {
+    const PREFIX: &'static str = "boolean";
+}
+
+/// Tunable white light (cct:N)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CctData {
+    /// Color temperature in kelvin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ct: Option<f64>,
+}
+
+impl Component for CctData {
+    const PREFIX: &'static str = "cct";
+}
+
+/// Color light (rgb:N), channel intensities 0-255
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RgbData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rgb: Option<[f64; 3]>,
+}
+
+impl Component for RgbData {
+    const PREFIX: &'static str = "rgb";
+}
+
+/// Color light with a separate white channel (rgbw:N), intensities 0-255
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RgbwData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rgb: Option<[f64; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub white: Option<f64>,
+}
+
+impl Component for RgbwData {
+    const PREFIX: &'static str = "rgbw";
+}
+
+/// Cover/roller in cover mode (cover:N)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CoverData {
+    /// Position from 0 (closed) to 100 (open), null until calibrated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_pos: Option<f64>,
+    /// `open`, `closed`, `opening`, `closing`, `stopped` or `calibrating`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub apower: Option<f64>,
+}
+
+impl Component for CoverData {
+    const PREFIX: &'static str = "cover";
+}
+
+/// Dimmer or white light (light:N)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LightData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<bool>,
+    /// Brightness from 0 to 100
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<f64>,
+}
+
+impl Component for LightData {
+    const PREFIX: &'static str = "light";
+}
+
+/// One entry of a NotifyEvent `events` array, e.g. a button push
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EventData {
+    /// Emitting component such as `input:0`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub component: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u16>,
+    /// Event name such as `single_push` or `overtemp`
+    pub event: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ts: Option<f64>,
+}
+
+/// Input component (input:N); analog inputs report `percent`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct InputData {
+    /// Absent for digital inputs, null when the reading is invalid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<f64>,
+}
+
+impl Component for InputData {
+    const PREFIX: &'static str = "input";
+}
+
+/// Parse a Shelly MQTT message from JSON, ignoring NotifyEvent messages
+#[allow(dead_code)]
 pub fn parse_message(json: &str) -> Result<ShellyMessage, ParserError> {
+    parse_message_with(json, false)
+}
+
+/// Parse a Shelly MQTT message, keeping NotifyEvent messages when `process_events` is set
+pub fn parse_message_with(json: &str, process_events: bool) -> Result<ShellyMessage, ParserError> {
     let msg: ShellyMessage = serde_json::from_str(json)?;
+    accept_message(msg, process_events)
+}
 
-    // Ignore NotifyEvent messages as per spec
-    if msg.method == MessageMethod::NotifyEvent {
+/// Filter an already deserialized message the way `parse_message_with` does
+pub fn accept_message(
+    msg: ShellyMessage,
+    process_events: bool,
+) -> Result<ShellyMessage, ParserError> {
+    // Ignore NotifyEvent messages as per spec unless events are counted
+    if msg.method == MessageMethod::NotifyEvent && !process_events {
         return Err(ParserError::IgnoredMessage("NotifyEvent".to_string()));
     }
 
     Ok(msg)
 }
 
+/// Gen1 `/status` payload, a flat document without `src`/`method`/`params`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Gen1Status {
+    #[serde(default)]
+    pub relays: Vec<Gen1Relay>,
+    #[serde(default)]
+    pub meters: Vec<Gen1Meter>,
+    pub tmp: Option<Gen1Temperature>,
+    pub wifi_sta: Option<WifiData>,
+    pub uptime: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Gen1Relay {
+    pub ison: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Gen1Meter {
+    pub power: Option<f64>,
+    /// Energy in watt-minutes
+    pub total: Option<f64>,
+    #[serde(default = "default_true")]
+    pub is_valid: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Gen1Temperature {
+    #[serde(rename = "tC")]
+    pub tc: Option<f64>,
+    #[serde(rename = "tF")]
+    pub tf: Option<f64>,
+    #[serde(default = "default_true")]
+    pub is_valid: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Gen1Status {
+    /// Map onto the Gen2 shape so the same metric families are updated;
+    /// relay and meter `N` become `switch:N`
+    pub fn into_message(self, src: &str) -> ShellyMessage {
+        let channels = self.relays.len().max(self.meters.len());
+        let switch = (0..channels)
+            .map(|channel| {
+                let relay = self.relays.get(channel);
+                let meter = self.meters.get(channel).filter(|meter| meter.is_valid);
+                let data = SwitchData {
+                    id: channel as u8,
+                    output: relay.and_then(|relay| relay.ison),
+                    apower: meter.and_then(|meter| meter.power),
+                    voltage: None,
+                    current: None,
+                    pf: None,
+                    freq: None,
+                    aenergy: meter.and_then(|meter| meter.total).map(|total| EnergyData {
+                        total: total / 60.0,
+                        by_minute: None,
+                        minute_ts: None,
+                    }),
+                    ret_aenergy: None,
+                    temperature: None,
+                    errors: None,
+                };
+                (channel as u16, data)
+            })
+            .collect();
+
+        let temperature = self
+            .tmp
+            .filter(|tmp| tmp.is_valid)
+            .map(|tmp| {
+                let data = TemperatureSensorData {
+                    id: 0,
+                    tc: tmp.tc,
+                    tf: tmp.tf,
+                };
+                (0, data)
+            })
+            .into_iter()
+            .collect();
+
+        ShellyMessage {
+            src: src.to_string(),
+            dst: None,
+            method: MessageMethod::NotifyStatus,
+            params: MessageParams {
+                switch,
+                temperature,
+                wifi: self.wifi_sta,
+                sys: self.uptime.map(|uptime| SysData {
+                    uptime: Some(uptime),
+                    device: None,
+                    available_updates: None,
+                    ram_free: None,
+                    ram_size: None,
+                    fs_free: None,
+                    fs_size: None,
+                }),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Parse a Gen1 `/status` payload into a message from `src`
+pub fn parse_gen1_message(json: &str, src: &str) -> Result<ShellyMessage, ParserError> {
+    let status: Gen1Status = serde_json::from_str(json)?;
+
+    // Every field is optional, so require one the exporter can use
+    if status.relays.is_empty() && status.meters.is_empty() && status.tmp.is_none() {
+        return Err(ParserError::MissingField("meters".to_string()));
+    }
+
+    Ok(status.into_message(src))
+}
+
 /// Extract device ID from source field
 /// Example: "shellyplugus-d48afc781ad8" -> "d48afc781ad8"
 pub fn extract_device_id(src: &str) -> String {
@@ -159,15 +883,95 @@ pub fn extract_device_id(src: &str) -> String {
     }
 }
 
+/// Device family inferred from the model prefix of the `src` field; sensor
+/// components are checked for every type, since add-ons and Pro models report them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    /// Plugs and relays reporting `switch:N`, never meters
+    Switch,
+    /// Battery sensors reporting `temperature:N`, `humidity:N`, `devicepower:N`,
+    /// never switches or meters
+    Sensor,
+    /// Meters reporting `pm1:N` or `em:N`, and the contactor `switch:N` of Pro models
+    PowerMeter,
+    /// Model not recognized, every component is checked
+    Unknown,
+}
+
+impl DeviceType {
+    pub fn has_switches(self) -> bool {
+        !matches!(self, DeviceType::Sensor)
+    }
+
+    pub fn has_power_meters(self) -> bool {
+        matches!(self, DeviceType::PowerMeter | DeviceType::Unknown)
+    }
+}
+
+const SENSOR_MODELS: &[&str] = &["shellyht", "shellyplusht"];
+const POWER_METER_MODELS: &[&str] = &[
+    "shellypmmini",
+    "shellypluspmmini",
+    "shellypro3em",
+    "shellyproem",
+    "shellyemg3",
+];
+const SWITCH_MODELS: &[&str] = &[
+    "shellyplug",
+    "shellyplus1",
+    "shellyplus2",
+    "shellypro",
+    "shelly1",
+    "shellymini1",
+];
+
+/// Infer the device type from the source field
+/// Example: "shellyhtg3-3030f9e7d294" -> DeviceType::Sensor
+pub fn device_type_from_src(src: &str) -> DeviceType {
+    let model = src
+        .rsplit_once('-')
+        .map_or(src, |(model, _)| model)
+        .to_ascii_lowercase();
+    let matches_any = |prefixes: &[&str]| prefixes.iter().any(|p| model.starts_with(p));
+
+    if matches_any(SENSOR_MODELS) {
+        DeviceType::Sensor
+    } else if matches_any(POWER_METER_MODELS) {
+        DeviceType::PowerMeter
+    } else if matches_any(SWITCH_MODELS) {
+        DeviceType::Switch
+    } else {
+        DeviceType::Unknown
+    }
+}
+
+/// Position of the device name in the default `<prefix>/shelly/<device-name>/...` layout
+pub const DEFAULT_DEVICE_NAME_TOPIC_INDEX: usize = 2;
+
 /// Extract device name from MQTT topic path
 /// Example: "mostert/shelly/plugcoffee/events/rpc" -> Some("plugcoffee")
+#[allow(dead_code)]
 pub fn extract_device_from_topic(topic: &str) -> Option<String> {
-    let parts: Vec<&str> = topic.split('/').collect();
-    // Expected format: <prefix>/shelly/<device-name>/events/rpc
-    if parts.len() >= 3 {
-        Some(parts[2].to_string())
-    } else {
-        None
+    extract_device_from_topic_at(topic, DEFAULT_DEVICE_NAME_TOPIC_INDEX)
+}
+
+/// Extract the device name from the topic segment at `index`, counted from 0 at the start
+/// so `/events/rpc`, `/status` and `/online` topics of a device agree
+pub fn extract_device_from_topic_at(topic: &str, index: usize) -> Option<String> {
+    topic
+        .split('/')
+        .nth(index)
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_string)
+}
+
+/// Parse the retained `<topic>/online` payload: `true`/`false`, bare or quoted
+pub fn parse_online_payload(payload: &str) -> Result<bool, ParserError> {
+    match serde_json::from_str::<serde_json::Value>(payload.trim())? {
+        serde_json::Value::Bool(online) => Ok(online),
+        serde_json::Value::String(online) if online == "true" => Ok(true),
+        serde_json::Value::String(online) if online == "false" => Ok(false),
+        _ => Err(ParserError::MissingField("online".to_string())),
     }
 }
 
@@ -213,7 +1017,7 @@ mod tests {
         assert_eq!(msg.src, "shellyplugus-d48afc781ad8");
         assert_eq!(msg.method, MessageMethod::NotifyFullStatus);
 
-        let switch = msg.params.switch.as_ref().unwrap();
+        let switch = msg.params.switch.get(0).unwrap();
         assert_eq!(switch.id, 0);
         assert_eq!(switch.output, Some(false));
         assert_eq!(switch.apower, Some(125.5));
@@ -222,11 +1026,11 @@ mod tests {
         assert_eq!(switch.aenergy.as_ref().unwrap().total, 3949.949);
 
         let temp = switch.temperature.as_ref().unwrap();
-        assert_eq!(temp.tc, 37.9);
-        assert_eq!(temp.tf, 100.1);
+        assert_eq!(temp.tc, Some(37.9));
+        assert_eq!(temp.tf, Some(100.1));
 
         let wifi = msg.params.wifi.as_ref().unwrap();
-        assert_eq!(wifi.rssi, -40);
+        assert_eq!(wifi.rssi, Some(-40));
     }
 
     #[test]
@@ -249,12 +1053,221 @@ mod tests {
         let msg = parse_message(json).unwrap();
 
         assert_eq!(msg.method, MessageMethod::NotifyStatus);
-        let switch = msg.params.switch.as_ref().unwrap();
+        let switch = msg.params.switch.get(0).unwrap();
         let aenergy = switch.aenergy.as_ref().unwrap();
         assert_eq!(aenergy.total, 3949.949);
         assert_eq!(aenergy.by_minute, Some(vec![0.0, 0.0, 0.0]));
     }
 
+    #[test]
+    fn test_parse_switch_power_quality() {
+        let json = r#"{
+            "src": "shellypro1pm-30c6f782d1a4",
+            "method": "NotifyStatus",
+            "params": {"switch:0": {"id": 0, "apower": 230.1, "pf": 0.93, "freq": 50.02}}
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        let switch = msg.params.switch.get(0).unwrap();
+        assert_eq!(switch.pf, Some(0.93));
+        assert_eq!(switch.freq, Some(50.02));
+
+        // Plugs without metering quality leave both unset
+        let json = r#"{"src": "shellyplugus-a", "method": "NotifyStatus", "params": {"switch:0": {"id": 0}}}"#;
+        let msg = parse_message(json).unwrap();
+        assert_eq!(msg.params.switch.get(0).unwrap().pf, None);
+        assert_eq!(msg.params.switch.get(0).unwrap().freq, None);
+    }
+
+    #[test]
+    fn test_parse_switch_errors() {
+        let json = r#"{
+            "src": "shellyplus1pm-a8032ab12345",
+            "method": "NotifyStatus",
+            "params": {"switch:0": {"id": 0, "output": false, "errors": ["overtemp"]}}
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        let switch = msg.params.switch.get(0).unwrap();
+        assert_eq!(
+            switch.errors.as_deref(),
+            Some(&["overtemp".to_string()][..])
+        );
+
+        let json = r#"{"src": "shellyplugus-a", "method": "NotifyStatus", "params": {"switch:0": {"id": 0}}}"#;
+        let msg = parse_message(json).unwrap();
+        assert_eq!(msg.params.switch.get(0).unwrap().errors, None);
+    }
+
+    #[test]
+    fn test_parse_alarm_sensors() {
+        let json = r#"{
+            "src": "shellyplus1-a8032ab12345",
+            "method": "NotifyStatus",
+            "params": {
+                "flood:0": {"id": 0, "alarm": true},
+                "motion:0": {"id": 0, "vibration": false}
+            }
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        assert_eq!(msg.params.flood.unwrap().alarm, Some(true));
+        assert_eq!(msg.params.motion.unwrap().detected(), Some(false));
+        assert!(msg.params.smoke.is_none());
+        assert!(msg.params.extra.is_empty());
+    }
+
+    #[test]
+    fn test_parse_illuminance() {
+        let json = r#"{
+            "src": "shellyblumotion-a8032ab12345",
+            "method": "NotifyStatus",
+            "params": {"illuminance:0": {"id": 0, "lux": 123.4}}
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        assert_eq!(msg.params.illuminance.unwrap().lux, Some(123.4));
+        assert!(msg.params.extra.is_empty());
+    }
+
+    #[test]
+    fn test_parse_wifi_ssid() {
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyStatus",
+            "params": {"wifi": {"sta_ip": "10.0.0.12", "status": "got ip", "ssid": "attic", "rssi": -61}}
+        }"#;
+
+        let wifi = parse_message(json).unwrap().params.wifi.unwrap();
+        assert_eq!(wifi.ssid.as_deref(), Some("attic"));
+        assert_eq!(wifi.rssi, Some(-61));
+    }
+
+    #[test]
+    fn test_parse_available_updates() {
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyFullStatus",
+            "params": {"sys": {"uptime": 120, "available_updates": {"stable": {"version": "1.4.4"}}}}
+        }"#;
+
+        let sys = parse_message(json).unwrap().params.sys.unwrap();
+        assert_eq!(
+            sys.available_updates.as_ref().unwrap()["stable"]["version"],
+            "1.4.4"
+        );
+        assert_eq!(sys.update_available(), Some(true));
+
+        let json = r#"{"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {"sys": {"uptime": 180}}}"#;
+        let sys = parse_message(json).unwrap().params.sys.unwrap();
+        assert_eq!(sys.update_available(), None);
+    }
+
+    #[test]
+    fn test_parse_sys_memory() {
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyFullStatus",
+            "params": {"sys": {"ram_size": 260764, "ram_free": 113436, "fs_size": 458752, "fs_free": 135168}}
+        }"#;
+
+        let sys = parse_message(json).unwrap().params.sys.unwrap();
+        assert_eq!(sys.ram_size, Some(260764));
+        assert_eq!(sys.ram_free, Some(113436));
+        assert_eq!(sys.fs_size, Some(458752));
+        assert_eq!(sys.fs_free, Some(135168));
+    }
+
+    #[test]
+    fn test_parse_cover() {
+        let json = r#"{
+            "src": "shellypro2pm-ec62608a1b2c",
+            "method": "NotifyStatus",
+            "params": {"cover:0": {"id": 0, "state": "stopped", "current_pos": 42, "apower": 0.0}}
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        let cover = msg.params.cover.get(0).unwrap();
+        assert_eq!(cover.current_pos, Some(42.0));
+        assert_eq!(cover.state.as_deref(), Some("stopped"));
+        assert_eq!(cover.apower, Some(0.0));
+        assert!(msg.params.extra.is_empty());
+    }
+
+    #[test]
+    fn test_parse_light() {
+        let json = r#"{
+            "src": "shellyplusdimmer-a8032ab12345",
+            "method": "NotifyStatus",
+            "params": {"light:0": {"id":0,"output":true,"brightness":75}}
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        let light = msg.params.light.get(0).unwrap();
+        assert_eq!(light.output, Some(true));
+        assert_eq!(light.brightness, Some(75.0));
+        assert!(msg.params.extra.is_empty());
+    }
+
+    #[test]
+    fn test_parse_unknown_components() {
+        let json = r#"{
+            "src": "shellyplus2pm-a8032ab12345",
+            "method": "NotifyStatus",
+            "params": {
+                "ts": 1763918640.12,
+                "switch:0": {"id": 0, "apower": 12.5},
+                "thermostat:0": {"id": 0, "enable": true, "target_C": 21.5},
+                "wifi": {"rssi": -60}
+            }
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        assert_eq!(msg.params.extra["thermostat:0"]["target_C"], 21.5);
+        assert_eq!(msg.params.extra.len(), 1);
+        assert_eq!(msg.params.switch.get(0).unwrap().apower, Some(12.5));
+        assert!(msg.params.wifi.is_some());
+    }
+
+    #[test]
+    fn test_parse_returned_energy() {
+        let json = r#"{
+            "src": "shellypro1pm-30c6f782d1a4",
+            "method": "NotifyStatus",
+            "params": {
+                "switch:0": {
+                    "id": 0,
+                    "aenergy": {"total": 1200.5, "minute_ts": 1763918640},
+                    "ret_aenergy": {"total": 310.25, "minute_ts": 1763918640}
+                }
+            }
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        let switch = msg.params.switch.get(0).unwrap();
+        assert_eq!(switch.aenergy.as_ref().unwrap().total, 1200.5);
+        assert_eq!(switch.ret_aenergy.as_ref().unwrap().total, 310.25);
+    }
+
+    #[test]
+    fn test_parse_light_colors() {
+        let json = r#"{
+            "src": "shellyprorgbwwpm-ac1518783c4c",
+            "method": "NotifyStatus",
+            "params": {
+                "cct:0": {"id": 0, "output": true, "brightness": 80, "ct": 4000},
+                "rgbw:1": {"id": 1, "output": true, "rgb": [255, 120, 0], "white": 40}
+            }
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        assert_eq!(msg.params.cct.get(0).unwrap().ct, Some(4000.0));
+        let rgbw = msg.params.rgbw.get(1).unwrap();
+        assert_eq!(rgbw.rgb, Some([255.0, 120.0, 0.0]));
+        assert_eq!(rgbw.white, Some(40.0));
+        assert!(msg.params.rgb.is_empty());
+    }
+
     #[test]
     fn test_parse_notify_event_ignored() {
         let json = r#"{
@@ -273,6 +1286,38 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_parse_notify_event_processed() {
+        let json = r#"{
+            "src": "shellyplusi4-c4d8d5570b14",
+            "method": "NotifyEvent",
+            "params": {
+                "ts": 1700000000.12,
+                "events": [
+                    {"component": "input:0", "id": 0, "event": "single_push", "ts": 1700000000.12},
+                    {"component": "switch:0", "id": 0, "event": "overtemp"}
+                ]
+            }
+        }"#;
+
+        let msg = parse_message_with(json, true).unwrap();
+        assert_eq!(msg.method, MessageMethod::NotifyEvent);
+        assert_eq!(msg.params.events.len(), 2);
+        assert_eq!(msg.params.events[0].event, "single_push");
+        assert_eq!(msg.params.events[0].component.as_deref(), Some("input:0"));
+        assert_eq!(msg.params.events[1].event, "overtemp");
+    }
+
+    #[test]
+    fn test_parse_online_payload() {
+        assert!(parse_online_payload("true").unwrap());
+        assert!(!parse_online_payload("false\n").unwrap());
+        assert!(parse_online_payload("\"true\"").unwrap());
+        assert!(!parse_online_payload("\"false\"").unwrap());
+        assert!(parse_online_payload("1").is_err());
+        assert!(parse_online_payload("yes").is_err());
+    }
+
     #[test]
     fn test_extract_device_id() {
         assert_eq!(
@@ -286,6 +1331,38 @@ mod tests {
         assert_eq!(extract_device_id("nodash"), "nodash");
     }
 
+    #[test]
+    fn test_device_type_from_src() {
+        assert_eq!(
+            device_type_from_src("shellyplugus-d48afc781ad8"),
+            DeviceType::Switch
+        );
+        assert_eq!(
+            device_type_from_src("shellypro4pm-a8032ab12345"),
+            DeviceType::Switch
+        );
+        assert_eq!(
+            device_type_from_src("shellyhtg3-3030f9e7d294"),
+            DeviceType::Sensor
+        );
+        assert_eq!(
+            device_type_from_src("shellyplusht-c4d8d5571234"),
+            DeviceType::Sensor
+        );
+        assert_eq!(
+            device_type_from_src("shellypmminig3-84fce63bf0a0"),
+            DeviceType::PowerMeter
+        );
+        assert_eq!(device_type_from_src("mybridge-1"), DeviceType::Unknown);
+        assert_eq!(device_type_from_src("nodash"), DeviceType::Unknown);
+
+        assert!(DeviceType::Switch.has_switches());
+        assert!(!DeviceType::Switch.has_power_meters());
+        assert!(DeviceType::PowerMeter.has_switches());
+        assert!(!DeviceType::Sensor.has_switches());
+        assert!(DeviceType::Unknown.has_power_meters());
+    }
+
     #[test]
     fn test_should_process() {
         assert!(should_process(&MessageMethod::NotifyFullStatus));
@@ -321,4 +1398,201 @@ mod tests {
             Some("device".to_string())
         );
     }
+
+    #[test]
+    fn test_extract_device_from_topic_at() {
+        let deep = "home/floor2/room/shelly/lamp/events/rpc";
+        assert_eq!(
+            extract_device_from_topic_at(deep, 4),
+            Some("lamp".to_string())
+        );
+        assert_eq!(
+            extract_device_from_topic_at("home/floor2/room/shelly/lamp/online", 4),
+            Some("lamp".to_string())
+        );
+        assert_eq!(
+            extract_device_from_topic_at("shelly/kitchen/events/rpc", 1),
+            Some("kitchen".to_string())
+        );
+
+        // Missing or empty segments leave the caller to fall back to the MAC
+        assert_eq!(extract_device_from_topic_at("shelly/events", 4), None);
+        assert_eq!(extract_device_from_topic_at("home//shelly", 1), None);
+    }
+
+    #[test]
+    fn test_parse_pm1_components() {
+        let json = r#"{
+            "src": "shellypmminig3-84fce63bf0a0",
+            "method": "NotifyStatus",
+            "params": {
+                "pm1:0": {
+                    "id": 0,
+                    "voltage": 121.8,
+                    "current": 0.412,
+                    "apower": 48.7,
+                    "freq": 60.0,
+                    "aenergy": {"total": 1234.5}
+                },
+                "pm1:1": {"id": 1, "apower": 3.2}
+            }
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        assert_eq!(msg.params.pm1.len(), 2);
+
+        let pm1 = msg.params.pm1.get(0).unwrap();
+        assert_eq!(pm1.voltage, Some(121.8));
+        assert_eq!(pm1.current, Some(0.412));
+        assert_eq!(pm1.apower, Some(48.7));
+        assert_eq!(pm1.freq, Some(60.0));
+        assert_eq!(pm1.aenergy.as_ref().unwrap().total, 1234.5);
+
+        assert_eq!(msg.params.pm1.get(1).unwrap().apower, Some(3.2));
+        assert!(msg.params.switch.is_empty());
+    }
+
+    #[test]
+    fn test_parse_sys_device() {
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyFullStatus",
+            "params": {
+                "sys": {
+                    "mac": "D48AFC781AD8",
+                    "uptime": 100,
+                    "device": {
+                        "mac": "D48AFC781AD8",
+                        "model": "SNPL-00116US",
+                        "gen": 2,
+                        "fw_id": "20231107-164738/1.0.8-g4d8ac1d"
+                    }
+                }
+            }
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        let device = msg.params.sys.as_ref().unwrap().device.as_ref().unwrap();
+        assert_eq!(device.model.as_deref(), Some("SNPL-00116US"));
+        assert_eq!(device.gen, Some(2));
+        assert_eq!(
+            device.fw_id.as_deref(),
+            Some("20231107-164738/1.0.8-g4d8ac1d")
+        );
+    }
+
+    #[test]
+    fn test_parse_pro3em_status() {
+        let json = r#"{
+            "src": "shellypro3em-0cb815fcaff4",
+            "dst": "shellypro3em-0cb815fcaff4/events",
+            "method": "NotifyStatus",
+            "params": {
+                "ts": 1732000000.52,
+                "em:0": {
+                    "id": 0,
+                    "a_current": 1.034,
+                    "a_voltage": 231.2,
+                    "a_act_power": 199.3,
+                    "a_aprt_power": 238.9,
+                    "a_pf": 0.83,
+                    "a_freq": 50.0,
+                    "b_current": 0.418,
+                    "b_voltage": 232.8,
+                    "b_act_power": 61.7,
+                    "b_aprt_power": 97.4,
+                    "b_pf": 0.63,
+                    "b_freq": 50.0,
+                    "c_current": 2.561,
+                    "c_voltage": 230.5,
+                    "c_act_power": 571.0,
+                    "c_aprt_power": 590.3,
+                    "c_pf": 0.97,
+                    "c_freq": 50.0,
+                    "n_current": null,
+                    "total_current": 4.013,
+                    "total_act_power": 832.0,
+                    "total_aprt_power": 926.6
+                }
+            }
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        let em = msg.params.em.as_ref().unwrap();
+        assert_eq!(em.total_act_power, Some(832.0));
+
+        let [a, b, c] = em.phases();
+        assert_eq!((a.phase, a.act_power), ("a", Some(199.3)));
+        assert_eq!(b.voltage, Some(232.8));
+        assert_eq!(c.current, Some(2.561));
+        assert_eq!(device_type_from_src(&msg.src), DeviceType::PowerMeter);
+    }
+
+    #[test]
+    fn test_parse_virtual_components() {
+        let json = r#"{
+            "src": "shelly1pmg3-34b7da8f1234",
+            "method": "NotifyStatus",
+            "params": {
+                "number:0": {"id": 0, "value": 21.5},
+                "boolean:200": {"id": 200, "value": true, "name": "heating"},
+                "text:200": {"id": 200, "value": "hello"},
+                "enum:201": {"id": 201, "value": "auto"}
+            }
+        }"#;
+
+        let msg = parse_message(json).unwrap();
+        assert_eq!(msg.params.number.len(), 1);
+        assert_eq!(msg.params.number.get(0).unwrap().value, Some(21.5));
+
+        let boolean = msg.params.boolean.get(200).unwrap();
+        assert_eq!(boolean.value, Some(true));
+        assert_eq!(boolean.name.as_deref(), Some("heating"));
+    }
+
+    #[test]
+    fn test_parse_gen1_status() {
+        let json = r#"{
+            "wifi_sta": {"connected": true, "ssid": "home", "ip": "192.168.1.40", "rssi": -61},
+            "relays": [{"ison": true, "has_timer": false, "overpower": false}],
+            "meters": [{"power": 12.3, "overpower": 0.0, "is_valid": true, "timestamp": 1700000000, "counters": [12.1, 12.4, 12.2], "total": 456}],
+            "tmp": {"tC": 21.5, "tF": 70.7, "is_valid": true},
+            "uptime": 86400
+        }"#;
+
+        let msg = parse_gen1_message(json, "garage").unwrap();
+        assert_eq!(msg.src, "garage");
+        assert_eq!(msg.method, MessageMethod::NotifyStatus);
+
+        let switch = msg.params.switch.get(0).unwrap();
+        assert_eq!(switch.output, Some(true));
+        assert_eq!(switch.apower, Some(12.3));
+        // Gen1 counts watt-minutes
+        assert_eq!(switch.aenergy.as_ref().unwrap().total, 7.6);
+        assert_eq!(msg.params.temperature.get(0).unwrap().tc, Some(21.5));
+        assert_eq!(msg.params.wifi.unwrap().rssi, Some(-61));
+        assert_eq!(msg.params.sys.unwrap().uptime, Some(86400));
+    }
+
+    #[test]
+    fn test_parse_gen1_rejects_unrelated_json() {
+        assert!(parse_gen1_message(r#"{"online": true}"#, "garage").is_err());
+        assert!(parse_gen1_message(r#"[1, 2]"#, "garage").is_err());
+
+        // An invalid meter keeps the relay state but drops the readings
+        let json = r#"{"relays": [{"ison": false}], "meters": [{"power": 0, "is_valid": false, "total": 10}]}"#;
+        let msg = parse_gen1_message(json, "garage").unwrap();
+        let switch = msg.params.switch.get(0).unwrap();
+        assert_eq!(switch.output, Some(false));
+        assert!(switch.apower.is_none() && switch.aenergy.is_none());
+    }
+
+    #[test]
+    fn test_components_round_trip() {
+        let json = r#"{"src": "a-b", "method": "NotifyStatus", "params": {"pm1:2": {"id": 2, "apower": 1.5}}}"#;
+        let msg = parse_message(json).unwrap();
+
+        let serialized = serde_json::to_value(&msg).unwrap();
+        assert_eq!(serialized["params"]["pm1:2"]["apower"], 1.5);
+    }
 }
@@ -0,0 +1,181 @@
+use anyhow::{Context, Result};
+use prometheus_client::registry::Registry;
+use reqwest::header;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::metrics::ExporterMetrics;
+use crate::server::encode_registries;
+
+/// Grouping key path under the Pushgateway URL
+const PUSH_PATH: &str = "/metrics/job/mqtt2prom";
+
+/// The Pushgateway parses pushed bodies as the classic text format, whatever the content type
+const PUSH_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Rewrite an OpenMetrics exposition as text format 0.0.4: counter families take the
+/// `_total` name of their samples, so the gateway keeps them typed, and the `# UNIT`
+/// and `# EOF` lines the older format lacks are dropped
+fn to_text_format(exposition: &str) -> String {
+    let counters: HashSet<&str> = exposition
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE "))
+        .filter_map(|rest| rest.strip_suffix(" counter"))
+        .collect();
+
+    let mut out = String::with_capacity(exposition.len());
+    for line in exposition.lines() {
+        if line == "# EOF" || line.starts_with("# UNIT ") {
+            continue;
+        }
+        let descriptor = ["# HELP ", "# TYPE "]
+            .into_iter()
+            .find_map(|prefix| Some((prefix, line.strip_prefix(prefix)?)));
+        match descriptor {
+            Some((prefix, rest)) => {
+                let (name, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+                let suffix = if counters.contains(name) {
+                    "_total"
+                } else {
+                    ""
+                };
+                out.push_str(&format!("{}{}{} {}", prefix, name, suffix, tail));
+            }
+            None => out.push_str(line),
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Pushes the exposition to a Pushgateway, for exporters that cannot be scraped
+pub struct Pusher {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Pusher {
+    pub fn new(base_url: &str, timeout: Duration) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .context("Failed to build push HTTP client")?;
+        Ok(Self {
+            client,
+            url: format!("{}{}", base_url.trim_end_matches('/'), PUSH_PATH),
+        })
+    }
+
+    /// POST the current metrics, replacing the previous push of the same names
+    pub async fn push(&self, registries: &[Arc<RwLock<Registry>>]) -> Result<()> {
+        let body = to_text_format(&encode_registries(registries)?);
+        self.client
+            .post(&self.url)
+            .header(header::CONTENT_TYPE, PUSH_CONTENT_TYPE)
+            .body(body)
+            .send()
+            .await
+            .with_context(|| format!("Failed to push metrics to {}", self.url))?
+            .error_for_status()
+            .with_context(|| format!("Pushgateway {} rejected the metrics", self.url))?;
+        Ok(())
+    }
+}
+
+/// Push on a fixed interval, forever; failures are logged and counted
+pub async fn run_pusher(
+    pusher: Pusher,
+    registries: Vec<Arc<RwLock<Registry>>>,
+    exporter_metrics: Arc<ExporterMetrics>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = pusher.push(&registries).await {
+            warn!("{:#}", e);
+            exporter_metrics.push_failure();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::ShellyMetrics;
+    use crate::parser::parse_message;
+    use prometheus_client::encoding::text::encode;
+    use wiremock::matchers::{header as header_eq, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_push_sends_metrics() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/metrics/job/mqtt2prom"))
+            .and(header_eq("content-type", PUSH_CONTENT_TYPE))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let registry = Arc::new(RwLock::new(Registry::default()));
+        let metrics = ShellyMetrics::new(&mut registry.write().unwrap());
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyStatus",
+            "params": {"switch:0": {"id": 0, "apower": 125.5}}
+        }"#;
+        metrics.update_from_message(
+            &parse_message(json).unwrap(),
+            Some("mostert/shelly/plugcoffee/events/rpc"),
+        );
+
+        let pusher = Pusher::new(&format!("{}/", server.uri()), Duration::from_secs(5)).unwrap();
+        pusher.push(&[registry]).await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let body = String::from_utf8(requests[0].body.clone()).unwrap();
+        assert!(
+            body.contains("shelly_switch_power_watts{device=\"plugcoffee\",switch=\"0\"} 125.5\n")
+        );
+        // Counters keep their type under the `_total` name the gateway parses
+        assert!(body.contains("# TYPE mqtt2prom_energy_out_of_order_total counter\n"));
+        assert!(!body.contains("# TYPE mqtt2prom_energy_out_of_order counter\n"));
+        assert!(!body.contains("# UNIT "));
+        assert!(!body.contains("# EOF"));
+    }
+
+    #[tokio::test]
+    async fn test_push_failures_counted() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&server)
+            .await;
+
+        let mut registry = Registry::default();
+        let exporter_metrics = Arc::new(ExporterMetrics::new(&mut registry));
+        let pusher = Pusher::new(&server.uri(), Duration::from_secs(5)).unwrap();
+        let registries = vec![Arc::new(RwLock::new(Registry::default()))];
+
+        // The first tick fires immediately
+        let task = tokio::spawn(run_pusher(
+            pusher,
+            registries,
+            exporter_metrics,
+            Duration::from_secs(3600),
+        ));
+        while server.received_requests().await.unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        task.abort();
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("mqtt2prom_push_failures_total 1\n"));
+    }
+}
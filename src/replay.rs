@@ -0,0 +1,48 @@
+use anyhow::{Context, Result};
+use prometheus_client::encoding::text::encode;
+use prometheus_client::registry::Registry;
+use std::path::Path;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+use crate::metrics::{MetricsOptions, ShellyMetrics};
+use crate::parser::{accept_message, parse_message_with};
+use crate::state::StoredMessage;
+
+/// Apply each newline-delimited JSON message in `path` to fresh metrics and return
+/// the exposition; lines are bare device messages or `--archive-path` records, which
+/// keep their topic and receive time; blank lines are skipped and lines that fail to
+/// parse are logged
+pub fn replay(path: &Path, options: &MetricsOptions, process_events: bool) -> Result<String> {
+    let input = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read replay file {}", path.display()))?;
+
+    let mut registry = Registry::default();
+    let metrics = ShellyMetrics::with_options(&mut registry, options);
+
+    let mut applied = 0;
+    for (index, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parsed = match serde_json::from_str::<StoredMessage>(line) {
+            Ok(record) => accept_message(record.message, process_events)
+                .map(|msg| (msg, record.topic, record.received_at)),
+            Err(_) => parse_message_with(line, process_events).map(|msg| (msg, None, None)),
+        };
+        match parsed {
+            Ok((msg, topic, received_at)) => {
+                let received_at = received_at.unwrap_or_else(SystemTime::now);
+                metrics.update_from_message_at(&msg, topic.as_deref(), received_at);
+                applied += 1;
+            }
+            Err(e) => warn!("Skipping line {} of {}: {}", index + 1, path.display(), e),
+        }
+    }
+    info!("Replayed {} messages from {}", applied, path.display());
+
+    let mut buffer = String::new();
+    encode(&mut buffer, &registry)?;
+    Ok(buffer)
+}
@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+use crate::metrics::ShellyMetrics;
+use crate::parser::ShellyMessage;
+
+/// A processed message together with the topic it arrived on
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StoredMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// When the message was received; missing in state files from older releases
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<SystemTime>,
+    pub message: ShellyMessage,
+}
+
+/// Latest message of each method per device, persisted so metrics have
+/// last-known values immediately after a restart
+///
+/// Restored values are replayed through the normal update path; they carry
+/// whatever the device last reported and are replaced as soon as it reports again.
+pub struct StateStore {
+    path: PathBuf,
+    messages: Mutex<Vec<StoredMessage>>,
+}
+
+impl StateStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            messages: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Remember a message, replacing the previous one of the same method from the same device
+    pub fn record(&self, message: &ShellyMessage, topic: Option<&str>) {
+        let mut messages = self.messages.lock().unwrap_or_else(PoisonError::into_inner);
+        messages.retain(|stored| {
+            stored.message.src != message.src || stored.message.method != message.method
+        });
+        messages.push(StoredMessage {
+            topic: topic.map(str::to_string),
+            received_at: Some(SystemTime::now()),
+            message: message.clone(),
+        });
+    }
+
+    /// Write the state file atomically via a temporary file and rename
+    pub fn save(&self) -> Result<()> {
+        let json = {
+            let messages = self.messages.lock().unwrap_or_else(PoisonError::into_inner);
+            serde_json::to_string(&*messages)?
+        };
+
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, json)
+            .with_context(|| format!("Failed to write state file {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to replace state file {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /// Load the state file, if present, and replay it into the metrics
+    ///
+    /// Messages keep their original receive time, so last-seen shows restored
+    /// values as stale, and they do not make `/readyz` ready.
+    pub fn restore(&self, metrics: &ShellyMetrics) -> Result<usize> {
+        let stored = match load(&self.path)? {
+            Some(stored) => stored,
+            None => return Ok(0),
+        };
+
+        // Older files carry no receive times; the last save is the latest they can be
+        let saved_at = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        for entry in &stored {
+            metrics.update_from_message_at(
+                &entry.message,
+                entry.topic.as_deref(),
+                entry.received_at.unwrap_or(saved_at),
+            );
+        }
+
+        let count = stored.len();
+        *self.messages.lock().unwrap_or_else(PoisonError::into_inner) = stored;
+        Ok(count)
+    }
+}
+
+fn load(path: &Path) -> Result<Option<Vec<StoredMessage>>> {
+    let json = match std::fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read state file {}", path.display()))
+        }
+    };
+
+    let stored = serde_json::from_str(&json)
+        .with_context(|| format!("Failed to parse state file {}", path.display()))?;
+    Ok(Some(stored))
+}
+
+/// Save the state on a fixed interval, forever
+pub async fn run_saver(store: std::sync::Arc<StateStore>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        match store.save() {
+            Ok(()) => info!("Saved device state to {}", store.path.display()),
+            Err(e) => warn!("Failed to save device state: {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_message;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+
+    #[test]
+    fn test_save_and_restore() {
+        let path =
+            std::env::temp_dir().join(format!("mqtt2prom-state-test-{}.json", std::process::id()));
+
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyFullStatus",
+            "params": {"switch:0": {"id": 0, "output": true, "apower": 125.5}}
+        }"#;
+        let store = StateStore::new(&path);
+        store.record(
+            &parse_message(json).unwrap(),
+            Some("mostert/shelly/plugcoffee/events/rpc"),
+        );
+        store.save().unwrap();
+
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+        let restored = StateStore::new(&path).restore(&metrics).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        assert_eq!(restored, 1);
+        assert!(buffer
+            .contains("shelly_switch_power_watts{device=\"plugcoffee\",switch=\"0\"} 125.5\n"));
+    }
+
+    #[test]
+    fn test_restore_keeps_receive_time() {
+        let path = std::env::temp_dir().join(format!(
+            "mqtt2prom-state-time-test-{}.json",
+            std::process::id()
+        ));
+        let json = r#"[{
+            "topic": "mostert/shelly/plugcoffee/events/rpc",
+            "received_at": {"secs_since_epoch": 1700000000, "nanos_since_epoch": 0},
+            "message": {"src": "shellyplugus-d48afc781ad8", "method": "NotifyStatus", "params": {"switch:0": {"id": 0, "apower": 125.5}}}
+        }]"#;
+        std::fs::write(&path, json).unwrap();
+
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+        StateStore::new(&path).restore(&metrics).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer
+            .contains("shelly_last_seen_timestamp_seconds{device=\"plugcoffee\"} 1700000000.0\n"));
+        // Only live messages make /readyz ready
+        assert!(!metrics
+            .data_received()
+            .load(std::sync::atomic::Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_record_keeps_latest_per_method() {
+        let store = StateStore::new("unused.json");
+        for apower in [1.0, 2.0] {
+            let json = format!(
+                r#"{{"src": "shellyplugus-a", "method": "NotifyStatus", "params": {{"switch:0": {{"id": 0, "apower": {}}}}}}}"#,
+                apower
+            );
+            store.record(&parse_message(&json).unwrap(), None);
+        }
+
+        let messages = store.messages.lock().unwrap();
+        assert_eq!(messages.len(), 1);
+        let switch = messages[0].message.params.switch.get(0).unwrap();
+        assert_eq!(switch.apower, Some(2.0));
+    }
+
+    #[test]
+    fn test_restore_missing_file() {
+        let mut registry = Registry::default();
+        let metrics = ShellyMetrics::new(&mut registry);
+        let store = StateStore::new("/nonexistent/mqtt2prom-state.json");
+        assert_eq!(store.restore(&metrics).unwrap(), 0);
+    }
+}
@@ -0,0 +1,86 @@
+use anyhow::{Context, Result};
+use prometheus_client::registry::Registry;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::server::encode_registries;
+
+/// File name inside the textfile directory; node_exporter only reads `*.prom`
+const TEXTFILE_NAME: &str = "mqtt2prom.prom";
+
+/// Writes the exposition for the node_exporter textfile collector
+pub struct TextfileWriter {
+    path: PathBuf,
+}
+
+impl TextfileWriter {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            path: dir.join(TEXTFILE_NAME),
+        }
+    }
+
+    /// Write to a temporary file and rename it, so the collector never reads a partial file
+    pub fn write(&self, registries: &[Arc<RwLock<Registry>>]) -> Result<()> {
+        let exposition = encode_registries(registries)?;
+        let tmp_path = self.path.with_extension("prom.tmp");
+        std::fs::write(&tmp_path, exposition)
+            .with_context(|| format!("Failed to write textfile {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to replace textfile {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Write the textfile on a fixed interval, forever
+pub async fn run_writer(
+    writer: TextfileWriter,
+    registries: Vec<Arc<RwLock<Registry>>>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = writer.write(&registries) {
+            warn!("Failed to write metrics textfile: {:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::ShellyMetrics;
+    use crate::parser::parse_message;
+
+    #[test]
+    fn test_write_textfile() {
+        let dir =
+            std::env::temp_dir().join(format!("mqtt2prom-textfile-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let registry = Arc::new(RwLock::new(Registry::default()));
+        let metrics = ShellyMetrics::new(&mut registry.write().unwrap());
+        let json = r#"{
+            "src": "shellyplugus-d48afc781ad8",
+            "method": "NotifyStatus",
+            "params": {"switch:0": {"id": 0, "apower": 125.5}}
+        }"#;
+        metrics.update_from_message(
+            &parse_message(json).unwrap(),
+            Some("mostert/shelly/plugcoffee/events/rpc"),
+        );
+
+        TextfileWriter::new(&dir).write(&[registry]).unwrap();
+        let written = std::fs::read_to_string(dir.join("mqtt2prom.prom")).unwrap();
+        let leftover = dir.join("mqtt2prom.prom.tmp").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(written.contains("# TYPE shelly_switch_power_watts gauge\n"));
+        assert!(written
+            .contains("shelly_switch_power_watts{device=\"plugcoffee\",switch=\"0\"} 125.5\n"));
+        assert!(!leftover);
+    }
+}
@@ -0,0 +1,28 @@
+use std::process::Command;
+
+#[test]
+fn test_replay_fixture() {
+    let output = Command::new(env!("CARGO_BIN_EXE_mqtt2prom"))
+        .args(["--replay", "tests/fixtures/replay.ndjson"])
+        .env_clear()
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // Later messages win, blank and malformed lines are skipped
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("shelly_switch_power_watts{device=\"d48afc781ad8\",switch=\"0\"} 98.25\n")
+    );
+    assert!(stdout.contains("shelly_switch_state{device=\"d48afc781ad8\",switch=\"0\"} 1.0\n"));
+    assert!(stdout.contains("shelly_temperature_celsius{device=\"3030f9e7d294\",id=\"0\"} 18.0\n"));
+    // Archive records are named from their topic and keep their receive time
+    assert!(stdout.contains("shelly_switch_power_watts{device=\"plugdesk\",switch=\"0\"} 12.5\n"));
+    assert!(
+        stdout.contains("shelly_last_seen_timestamp_seconds{device=\"plugdesk\"} 1700000000.0\n")
+    );
+    assert!(stdout.ends_with("# EOF\n"));
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Skipping line 3"));
+}